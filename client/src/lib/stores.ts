@@ -14,6 +14,7 @@ export const selectedRoom = writable<string | null>(null);
 export const usersStore = writable<string[]>([]);
 export const tabHidden = writable(false);
 export const timedIn = writable(false);
+export const onBreak = writable(false);
 // let sound = new Audio("/notification.mp3");
 let sound: HTMLAudioElement | null = null;
 
@@ -34,6 +35,10 @@ type Message = {
   room: string;
   content: string;
   timestamp: number;
+  // Set by the server from the user's do-not-disturb and room settings
+  notify?: boolean;
+  // The sender is someone the user has blocked
+  flagged?: boolean;
 };
 type Added = {
   room: string;
@@ -41,6 +46,14 @@ type Added = {
   added: string;
   timestamp: number;
 }
+type ProfileSummary = {
+  user: string;
+  display_name?: string;
+  status?: { text?: string; emoji?: string; expires_at?: string };
+  avatar?: string;
+  clocked_in?: boolean;
+  on_break?: boolean;
+}
 type TimeInOut = {
   note?: string;
 }
@@ -69,14 +82,6 @@ export const connect = (url: URL) => {
   ws.addEventListener("message", async (message: any) => {
     let msg = await message.data.text();
     console.log("Received:", msg);
-    if (get(tabHidden)) {
-      // play sound
-      console.log("Playing sound")
-      if (!sound) {
-        sound = new Audio("/notification.mp3");
-      }
-      sound.play();
-    }
     const data: Payload = JSON.parse(msg);
     handlePayload(data);
   });
@@ -89,9 +94,19 @@ function handlePayload(payload: Payload) {
   switch (payload.action) {
     case "Message":
       const room = payload.data?.room;
+      if (payload.data?.flagged) break;
       messageStore.update((state) => {
         state[room] = state[room] || [];
         state[room].push(payload.data);
+        if (!payload.data?.notify) return state;
+        if (get(tabHidden)) {
+          // play sound
+          console.log("Playing sound")
+          if (!sound) {
+            sound = new Audio("/notification.mp3");
+          }
+          sound.play();
+        }
         if (get(selectedRoom) != room && payload.data?.timestamp > Date.now() - 1000 * 60 * 1 ){
           toast.success(`${payload.data?.sender} sent a message in ${room}`);
         }
@@ -122,11 +137,17 @@ function handlePayload(payload: Payload) {
       });
     case "List":
       if (!payload.data) return;
-      usersStore.set(payload.data);
+      usersStore.set(payload.data.map((u: ProfileSummary) => u.user));
       break;
     case "TimedIn":
       timedIn.set(payload.data || false);
       break;
+    case "OnBreak":
+      onBreak.set(payload.data || false);
+      break;
+    case "Error":
+      toast.error(payload.data);
+      break;
     default:
       break;
   }
@@ -3,4 +3,36 @@ drop table messages;
 drop table chatrooms;
 drop table users;
 drop table time_entries;
-drop table timesheets;
\ No newline at end of file
+drop index timesheets_user_id;
+drop table timesheets;
+drop table signup_codes;
+drop table api_tokens;
+drop table oidc_identities;
+drop table user_profiles;
+drop table dnd_windows;
+drop table room_notifications;
+drop table keyword_alerts;
+drop table user_blocks;
+drop table user_managers;
+drop table timesheet_approvals;
+drop table time_corrections;
+drop trigger time_entry_audit_no_update;
+drop trigger time_entry_audit_no_delete;
+drop table time_entry_audit;
+drop table time_breaks;
+drop table projects;
+drop table leave_requests;
+drop table leave_balances;
+drop table leave_types;
+drop table holidays;
+drop table shifts;
+drop table calendar_feeds;
+drop trigger kiosk_punches_no_update;
+drop trigger kiosk_punches_no_delete;
+drop table kiosk_punches;
+drop table kiosk_pins;
+drop table kiosks;
+drop trigger pay_period_log_no_update;
+drop trigger pay_period_log_no_delete;
+drop table pay_period_log;
+drop table closed_periods;
@@ -1,13 +1,110 @@
 
 CREATE TABLE IF NOT EXISTS users (
   user_id TEXT PRIMARY KEY,
-  password VARCHAR(255) NOT NULL
+  password VARCHAR(255) NOT NULL,
+  active BOOLEAN NOT NULL DEFAULT 1, -- 0 once the account has been deactivated
+  is_admin BOOLEAN NOT NULL DEFAULT 0,
+  email TEXT,
+  service_account BOOLEAN NOT NULL DEFAULT 0, -- bots that only act through API tokens
+  department TEXT -- picks the time rounding rule of [time.rounding.departments]
 );
 
-REPLACE INTO users (user_id, password) VALUES ('admin', '______________');
+-- Can only log in while ADMIN_PASSWORD is set, to make the first admins on a fresh install.
+-- Only the password gets locked again on every start, the rest of the account stays as it was.
+INSERT INTO users (user_id, password, is_admin) VALUES ('admin', '______________', 1)
+  ON CONFLICT (user_id) DO UPDATE SET password = excluded.password, is_admin = 1;
+-- Stand-in author for messages of deleted or anonymized accounts
+INSERT OR IGNORE INTO users (user_id, password, active) VALUES ('deleted', '______________', 0);
+-- Actor of automated changes, like clocking out forgotten punches
+INSERT OR IGNORE INTO users (user_id, password, active) VALUES ('system', '______________', 0);
+
+CREATE TABLE IF NOT EXISTS user_profiles (
+  user_id TEXT PRIMARY KEY,
+  display_name TEXT,
+  title TEXT,
+  status_text TEXT,
+  status_emoji TEXT,
+  status_expires_at DATETIME, -- NULL if the status doesn't expire
+  timezone TEXT, -- IANA name, e.g. 'Europe/Berlin'
+  avatar BLOB,
+  avatar_type TEXT, -- content type of the avatar
+  updated_at DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS dnd_windows (
+  user_id TEXT NOT NULL,
+  days TEXT NOT NULL DEFAULT '[]', -- JSON array of weekdays the window starts on, empty for every day
+  start_time TEXT NOT NULL, -- local time in the user's profile timezone
+  end_time TEXT NOT NULL, -- before start_time if the window runs past midnight
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS room_notifications (
+  user_id TEXT NOT NULL,
+  chatroom_id TEXT NOT NULL,
+  level TEXT NOT NULL, -- 'all', 'mentions' or 'mute'
+  PRIMARY KEY (user_id, chatroom_id),
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS keyword_alerts (
+  user_id TEXT NOT NULL,
+  keyword TEXT NOT NULL,
+  PRIMARY KEY (user_id, keyword),
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS user_blocks (
+  blocker_id TEXT NOT NULL,
+  blocked_id TEXT NOT NULL,
+  created_at DATETIME NOT NULL,
+  PRIMARY KEY (blocker_id, blocked_id),
+  FOREIGN KEY (blocker_id) REFERENCES users(user_id),
+  FOREIGN KEY (blocked_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS signup_codes (
+  code TEXT PRIMARY KEY,
+  created_by TEXT NOT NULL,
+  created_at DATETIME NOT NULL,
+  max_uses INTEGER, -- NULL for unlimited uses
+  uses INTEGER NOT NULL DEFAULT 0,
+  email TEXT, -- only this email can use the code
+  username_pattern TEXT, -- glob the new user_id has to match
+  rooms TEXT NOT NULL DEFAULT '[]', -- JSON array of rooms joined on signup
+  expires_at DATETIME,
+  FOREIGN KEY (created_by) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS api_tokens (
+  token_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE, -- hex sha256 of the token
+  scopes TEXT NOT NULL, -- JSON array, e.g. '["chat:write","time:read"]'
+  created_by TEXT NOT NULL,
+  created_at DATETIME NOT NULL,
+  expires_at DATETIME,
+  last_used_at DATETIME,
+  revoked_at DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users(user_id),
+  FOREIGN KEY (created_by) REFERENCES users(user_id)
+);
+
+-- Single sign-on identities and the account each of them logs in to
+CREATE TABLE IF NOT EXISTS oidc_identities (
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL, -- `sub` claim, which the provider never reassigns
+  user_id TEXT NOT NULL UNIQUE,
+  linked_at DATETIME NOT NULL,
+  PRIMARY KEY (issuer, subject),
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
 
 CREATE TABLE IF NOT EXISTS chatrooms (
-  chatroom_id TEXT PRIMARY KEY
+  chatroom_id TEXT PRIMARY KEY,
+  direct BOOLEAN NOT NULL DEFAULT 0 -- direct messages between two people, nobody else can join
 );
 
 CREATE TABLE IF NOT EXISTS chatroom_users (
@@ -30,12 +127,15 @@ CREATE TABLE IF NOT EXISTS messages (
 
 CREATE TABLE IF NOT EXISTS timesheets (
   timesheet_id INTEGER PRIMARY KEY AUTOINCREMENT,
-  user_id TEXT NOT NULL,
+  user_id TEXT NOT NULL UNIQUE,
   clocked_in BOOLEAN NOT NULL,
   current_id INTEGER, -- NULL if clocked out, otherwise the time_entry_id
   FOREIGN KEY (user_id) REFERENCES users(user_id)
 );
 
+-- Tables from before user_id was unique get it through this index
+CREATE UNIQUE INDEX IF NOT EXISTS timesheets_user_id ON timesheets (user_id);
+
 CREATE TABLE IF NOT EXISTS time_entries (
   time_entry_id INTEGER PRIMARY KEY AUTOINCREMENT,
   timesheet_id INTEGER NOT NULL,
@@ -43,5 +143,234 @@ CREATE TABLE IF NOT EXISTS time_entries (
   end_time DATETIME,
   start_note TEXT,
   end_note TEXT,
+  reminded_at DATETIME, -- when the user got told they might have forgotten to clock out
+  flagged BOOLEAN NOT NULL DEFAULT 0, -- closed automatically, needs a manager's review
+  project_id INTEGER,
+  task TEXT,
   FOREIGN KEY (timesheet_id) REFERENCES timesheets(timesheet_id)
-);
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS projects (
+  project_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  client TEXT,
+  cost_center TEXT,
+  active BOOLEAN NOT NULL DEFAULT 1,
+  created_at DATETIME NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS time_breaks (
+  break_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  time_entry_id INTEGER NOT NULL,
+  start_time DATETIME NOT NULL,
+  end_time DATETIME, -- NULL while still on the break
+  paid BOOLEAN NOT NULL DEFAULT 0,
+  FOREIGN KEY (time_entry_id) REFERENCES time_entries(time_entry_id)
+);
+
+CREATE TABLE IF NOT EXISTS user_managers (
+  user_id TEXT NOT NULL,
+  manager_id TEXT NOT NULL,
+  PRIMARY KEY (user_id, manager_id),
+  FOREIGN KEY (user_id) REFERENCES users(user_id),
+  FOREIGN KEY (manager_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS timesheet_approvals (
+  user_id TEXT NOT NULL,
+  period_start DATE NOT NULL,
+  period_end DATE NOT NULL,
+  status TEXT NOT NULL, -- submitted, approved or rejected
+  submitted_at DATETIME NOT NULL,
+  comment TEXT,
+  reviewed_by TEXT,
+  reviewed_at DATETIME,
+  review_comment TEXT,
+  PRIMARY KEY (user_id, period_start),
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS time_corrections (
+  correction_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL,
+  kind TEXT NOT NULL, -- add, edit or delete
+  time_entry_id INTEGER, -- NULL when adding an entry
+  start_time DATETIME, -- new values, NULL when deleting
+  end_time DATETIME,
+  reason TEXT NOT NULL,
+  status TEXT NOT NULL, -- submitted, approved or rejected
+  requested_at DATETIME NOT NULL,
+  reviewed_by TEXT,
+  reviewed_at DATETIME,
+  review_comment TEXT,
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+-- Every change to a time entry, with its values before and after. Rows outlive the entries
+-- and users they're about, and can never be changed.
+CREATE TABLE IF NOT EXISTS time_entry_audit (
+  audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  time_entry_id INTEGER NOT NULL,
+  user_id TEXT NOT NULL,
+  action TEXT NOT NULL, -- clock_in, clock_out, add, edit or delete
+  changed_by TEXT NOT NULL,
+  changed_at DATETIME NOT NULL,
+  correction_id INTEGER,
+  old_start_time DATETIME,
+  old_start_note TEXT,
+  old_end_time DATETIME,
+  old_end_note TEXT,
+  new_start_time DATETIME,
+  new_start_note TEXT,
+  new_end_time DATETIME,
+  new_end_note TEXT
+);
+
+CREATE TRIGGER IF NOT EXISTS time_entry_audit_no_update BEFORE UPDATE ON time_entry_audit
+BEGIN
+  SELECT RAISE(ABORT, 'time_entry_audit is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS time_entry_audit_no_delete BEFORE DELETE ON time_entry_audit
+BEGIN
+  SELECT RAISE(ABORT, 'time_entry_audit is append-only');
+END;
+
+CREATE TABLE IF NOT EXISTS leave_types (
+  leave_type TEXT PRIMARY KEY, -- e.g. vacation
+  name TEXT NOT NULL,
+  paid BOOLEAN NOT NULL DEFAULT 1,
+  accrual_hours REAL, -- hours earned every pay period, NULL if there's no balance to keep
+  max_balance REAL
+);
+
+CREATE TABLE IF NOT EXISTS leave_balances (
+  user_id TEXT NOT NULL,
+  leave_type TEXT NOT NULL,
+  hours REAL NOT NULL,
+  accrued_through DATE NOT NULL, -- last day of the last pay period accrued
+  PRIMARY KEY (user_id, leave_type),
+  FOREIGN KEY (user_id) REFERENCES users(user_id),
+  FOREIGN KEY (leave_type) REFERENCES leave_types(leave_type)
+);
+
+CREATE TABLE IF NOT EXISTS leave_requests (
+  request_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL,
+  leave_type TEXT NOT NULL,
+  start_date DATE NOT NULL,
+  end_date DATE NOT NULL,
+  hours_per_day REAL NOT NULL,
+  hours REAL NOT NULL, -- hours_per_day for every work day in the range that isn't a holiday
+  reason TEXT,
+  status TEXT NOT NULL, -- submitted, approved, rejected or cancelled
+  requested_at DATETIME NOT NULL,
+  reviewed_by TEXT,
+  reviewed_at DATETIME,
+  review_comment TEXT,
+  cancelled_by TEXT,
+  cancelled_at DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users(user_id),
+  FOREIGN KEY (leave_type) REFERENCES leave_types(leave_type)
+);
+
+-- Company holidays, paid to everyone
+CREATE TABLE IF NOT EXISTS holidays (
+  date DATE PRIMARY KEY,
+  name TEXT NOT NULL,
+  hours REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS shifts (
+  shift_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id TEXT NOT NULL,
+  start_time DATETIME NOT NULL,
+  end_time DATETIME NOT NULL,
+  role TEXT,
+  note TEXT,
+  created_by TEXT NOT NULL,
+  published_at DATETIME, -- NULL while it's a draft
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+-- Secret calendar URLs, one per user
+CREATE TABLE IF NOT EXISTS calendar_feeds (
+  user_id TEXT PRIMARY KEY,
+  token_hash TEXT NOT NULL UNIQUE, -- hex sha256 of the secret in the URL
+  created_at DATETIME NOT NULL,
+  last_used_at DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+-- Shared terminals people clock in on with a PIN instead of logging in
+CREATE TABLE IF NOT EXISTS kiosks (
+  kiosk_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE, -- hex sha256 of the token
+  created_by TEXT NOT NULL,
+  created_at DATETIME NOT NULL,
+  last_used_at DATETIME,
+  revoked_at DATETIME,
+  FOREIGN KEY (created_by) REFERENCES users(user_id)
+);
+
+-- Kiosk PINs, hashed apart from passwords
+CREATE TABLE IF NOT EXISTS kiosk_pins (
+  user_id TEXT PRIMARY KEY,
+  pin_hash TEXT NOT NULL, -- bcrypt
+  updated_at DATETIME NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+
+-- Every punch attempted on a kiosk, including refused ones. Append-only.
+CREATE TABLE IF NOT EXISTS kiosk_punches (
+  punch_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  kiosk_id INTEGER NOT NULL,
+  user_id TEXT NOT NULL, -- as typed, might not be a user
+  action TEXT NOT NULL, -- TimeIn, TimeOut, BreakStart, BreakEnd or Switch
+  outcome TEXT NOT NULL, -- punched, wrong_pin, locked_out or refused
+  detail TEXT,
+  attempted_at DATETIME NOT NULL,
+  FOREIGN KEY (kiosk_id) REFERENCES kiosks(kiosk_id)
+);
+
+CREATE TRIGGER IF NOT EXISTS kiosk_punches_no_update BEFORE UPDATE ON kiosk_punches
+BEGIN
+  SELECT RAISE(ABORT, 'kiosk_punches is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS kiosk_punches_no_delete BEFORE DELETE ON kiosk_punches
+BEGIN
+  SELECT RAISE(ABORT, 'kiosk_punches is append-only');
+END;
+
+-- Pay periods closed after payroll, locking everyone's time in them
+CREATE TABLE IF NOT EXISTS closed_periods (
+  period_start DATE PRIMARY KEY,
+  period_end DATE NOT NULL,
+  closed_by TEXT NOT NULL,
+  closed_at DATETIME NOT NULL,
+  FOREIGN KEY (closed_by) REFERENCES users(user_id)
+);
+
+-- Every time a pay period was closed or reopened. Append-only.
+CREATE TABLE IF NOT EXISTS pay_period_log (
+  log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  period_start DATE NOT NULL,
+  period_end DATE NOT NULL,
+  action TEXT NOT NULL, -- close or reopen
+  changed_by TEXT NOT NULL,
+  changed_at DATETIME NOT NULL,
+  reason TEXT,
+  FOREIGN KEY (changed_by) REFERENCES users(user_id)
+);
+
+CREATE TRIGGER IF NOT EXISTS pay_period_log_no_update BEFORE UPDATE ON pay_period_log
+BEGIN
+  SELECT RAISE(ABORT, 'pay_period_log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS pay_period_log_no_delete BEFORE DELETE ON pay_period_log
+BEGIN
+  SELECT RAISE(ABORT, 'pay_period_log is append-only');
+END;
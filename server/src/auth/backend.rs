@@ -0,0 +1,62 @@
+use serde::Deserialize;
+
+use crate::{
+    auth::ldap::{local_hash, LdapBackend, LdapConfig},
+    SqliteDB,
+};
+
+pub type BackendError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Checks a user's credentials when they log in
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// Whether `password` is the right password for `name`
+    async fn verify(&self, name: &str, password: &str, db: &SqliteDB)
+        -> Result<bool, BackendError>;
+
+    /// Whether accounts live outside the server, so they get created on their first
+    /// successful login instead of through registration
+    fn provisions_users(&self) -> bool {
+        false
+    }
+}
+
+/// Which [`AuthBackend`] to use, from the `backend` key of the `auth` config
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Password,
+    Ldap,
+}
+
+impl BackendKind {
+    pub fn build(&self, ldap: Option<&LdapConfig>) -> Box<dyn AuthBackend> {
+        match self {
+            BackendKind::Password => Box::new(PasswordBackend),
+            BackendKind::Ldap => Box::new(LdapBackend::new(
+                ldap.expect("The ldap backend needs an [auth.ldap] config")
+                    .clone(),
+            )),
+        }
+    }
+}
+
+/// Bcrypt hashes stored in the `users` table
+pub struct PasswordBackend;
+
+#[async_trait]
+impl AuthBackend for PasswordBackend {
+    async fn verify(
+        &self,
+        name: &str,
+        password: &str,
+        db: &SqliteDB,
+    ) -> Result<bool, BackendError> {
+        let n = name.to_owned();
+        let Some(db_pass) = db.run(move |d| local_hash(d, &n)).await? else {
+            return Ok(false);
+        };
+        Ok(bcrypt::verify(password, &db_pass).unwrap_or(false))
+    }
+}
@@ -0,0 +1,234 @@
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::{http::Status, serde::json::Json};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::Admin,
+    types::{ChatRoomID, UserID},
+    SqliteDB,
+};
+
+const CODE_LENGTH: usize = 16;
+
+/// A registration code handed out by an admin
+#[derive(Serialize)]
+pub struct SignupCode {
+    code: String,
+    created_by: UserID,
+    created_at: DateTime<Utc>,
+    /// `None` for codes that can be used any number of times
+    max_uses: Option<u32>,
+    uses: u32,
+    email: Option<String>,
+    username_pattern: Option<String>,
+    rooms: Vec<ChatRoomID>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+fn code_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<SignupCode> {
+    let rooms: String = row.get(7)?;
+    Ok(SignupCode {
+        code: row.get(0)?,
+        created_by: row.get(1)?,
+        created_at: row.get(2)?,
+        max_uses: row.get(3)?,
+        uses: row.get(4)?,
+        email: row.get(5)?,
+        username_pattern: row.get(6)?,
+        rooms: serde_json::from_str::<Vec<String>>(&rooms)
+            .unwrap_or_default()
+            .into_iter()
+            .map(ChatRoomID)
+            .collect(),
+        expires_at: row.get(8)?,
+    })
+}
+
+const SELECT_CODES: &str = "SELECT code, created_by, created_at, max_uses, uses, email, \
+    username_pattern, rooms, expires_at FROM signup_codes";
+
+fn single_use() -> Option<u32> {
+    Some(1)
+}
+
+#[derive(Deserialize)]
+pub struct NewSignupCode {
+    /// Defaults to a single use, `null` allows unlimited uses
+    #[serde(default = "single_use")]
+    max_uses: Option<u32>,
+    email: Option<String>,
+    /// Glob the new user's name has to match, e.g. `ops-*`
+    username_pattern: Option<String>,
+    /// Rooms the new user joins on signup
+    #[serde(default)]
+    rooms: Vec<ChatRoomID>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[post("/codes", data = "<new>")]
+pub async fn create_code(
+    new: Json<NewSignupCode>,
+    admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<SignupCode>, Status> {
+    let NewSignupCode {
+        max_uses,
+        email,
+        username_pattern,
+        rooms,
+        expires_at,
+    } = new.into_inner();
+    let code = SignupCode {
+        code: rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(CODE_LENGTH)
+            .map(char::from)
+            .collect(),
+        created_by: admin.0.name,
+        created_at: Utc::now(),
+        max_uses,
+        uses: 0,
+        email,
+        username_pattern,
+        rooms,
+        expires_at,
+    };
+    let room_names: Vec<&str> = code.rooms.iter().map(|r| r.0.as_str()).collect();
+    let rooms = serde_json::to_string(&room_names).map_err(|_| Status::InternalServerError)?;
+    let params = (
+        code.code.clone(),
+        code.created_by.0.clone(),
+        code.created_at,
+        code.max_uses,
+        code.email.clone(),
+        code.username_pattern.clone(),
+        rooms,
+        code.expires_at,
+    );
+    db.run(move |d| {
+        d.execute(
+            "INSERT INTO signup_codes (code, created_by, created_at, max_uses, email, \
+            username_pattern, rooms, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params,
+        )
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to create signup code: {e}");
+        Status::InternalServerError
+    })?;
+    Ok(Json(code))
+}
+
+#[get("/codes")]
+pub async fn list_codes(_admin: Admin, db: SqliteDB) -> Result<Json<Vec<SignupCode>>, Status> {
+    db.run(|d| {
+        d.prepare(&format!("{SELECT_CODES} ORDER BY created_at"))?
+            .query_map([], code_from_row)?
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list signup codes: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[delete("/codes/<code>")]
+pub async fn delete_code(code: String, _admin: Admin, db: SqliteDB) -> Status {
+    match db
+        .run(move |d| d.execute("DELETE FROM signup_codes WHERE code = ?", params![code]))
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::Ok,
+        Err(e) => {
+            log::error!("Failed to delete signup code: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+pub enum RedeemError {
+    InvalidCode,
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for RedeemError {
+    fn from(e: rusqlite::Error) -> Self {
+        RedeemError::Db(e)
+    }
+}
+
+/// Uses up one use of `code` for `name`, returning the rooms the new user should join.
+/// Should run in the same transaction that creates the user.
+pub fn redeem_code(
+    d: &rusqlite::Connection,
+    code: &str,
+    name: &str,
+    email: Option<&str>,
+) -> Result<Vec<ChatRoomID>, RedeemError> {
+    let Some(signup) = d
+        .query_row(
+            &format!("{SELECT_CODES} WHERE code = ?"),
+            params![code],
+            code_from_row,
+        )
+        .optional()?
+    else {
+        return Err(RedeemError::InvalidCode);
+    };
+    let used_up = signup.max_uses.is_some_and(|max| signup.uses >= max);
+    let expired = signup.expires_at.is_some_and(|exp| exp < Utc::now());
+    let email_matches = match (&signup.email, email) {
+        (None, _) => true,
+        (Some(expected), Some(email)) => expected.eq_ignore_ascii_case(email),
+        (Some(_), None) => false,
+    };
+    let name_matches = signup
+        .username_pattern
+        .as_deref()
+        .is_none_or(|pattern| glob_match(pattern, name));
+    if used_up || expired || !email_matches || !name_matches {
+        return Err(RedeemError::InvalidCode);
+    }
+    d.execute(
+        "UPDATE signup_codes SET uses = uses + 1 WHERE code = ?",
+        params![code],
+    )?;
+    Ok(signup.rooms)
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters
+/// and `?` matches a single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    t = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,245 @@
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::{serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Jwt;
+
+/// How long session tokens stay valid, in seconds
+const TOKEN_LIFETIME: u64 = 3600;
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::HS512
+}
+
+/// One entry of the `auth.keys` array of `Rocket.toml`
+#[derive(Deserialize, Clone)]
+pub struct KeyConfig {
+    /// Written to the `kid` header of every token signed with this key
+    pub kid: String,
+    /// `HS512`, `RS256` or `EdDSA`
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// Environment variable holding the secret of an `HS512` key
+    pub secret_env: Option<String>,
+    /// PEM file with the private key of an `RS256` or `EdDSA` key. Keys without one are only
+    /// used to check tokens signed before a rotation.
+    pub private_key: Option<PathBuf>,
+    /// PEM file with the public key of an `RS256` or `EdDSA` key
+    pub public_key: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct KeyError(String);
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl KeyError {
+    fn new(kid: &str, msg: impl fmt::Display) -> Self {
+        KeyError(format!("Key {kid:?}: {msg}"))
+    }
+}
+
+struct SigningKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+/// A public key other services can verify our tokens with
+#[derive(Serialize, Clone)]
+pub struct PublicKey {
+    kid: String,
+    alg: Algorithm,
+    /// PEM encoded
+    key: String,
+}
+
+/// Every key tokens are signed or verified with, loaded once at startup
+pub struct Keys {
+    signing: SigningKey,
+    /// Keyed by `kid`, with `None` for tokens from before keys had ids
+    verifying: HashMap<Option<String>, (Algorithm, DecodingKey)>,
+    public: Vec<PublicKey>,
+}
+
+impl Keys {
+    /// Loads the configured keys, signing with `signing_key` (or the first key).
+    /// `legacy_secret` is the old `JWT_SECRET`, which keeps verifying tokens without a `kid`
+    /// and signs new ones when no keys are configured.
+    pub fn load(
+        configs: &[KeyConfig],
+        signing_key: Option<&str>,
+        legacy_secret: Option<String>,
+    ) -> Result<Keys, KeyError> {
+        let mut signing = None;
+        let mut verifying = HashMap::new();
+        let mut public = Vec::new();
+        let signing_kid = signing_key.or(configs.first().map(|c| c.kid.as_str()));
+        for config in configs {
+            let (encoding, decoding) = load_key(config)?;
+            if let Some(pem) = decoding.1 {
+                public.push(PublicKey {
+                    kid: config.kid.clone(),
+                    alg: config.algorithm,
+                    key: pem,
+                });
+            }
+            if verifying
+                .insert(Some(config.kid.clone()), (config.algorithm, decoding.0))
+                .is_some()
+            {
+                return Err(KeyError::new(&config.kid, "Duplicate kid"));
+            }
+            if signing_kid == Some(config.kid.as_str()) {
+                let Some(key) = encoding else {
+                    return Err(KeyError::new(&config.kid, "Signing key has no private key"));
+                };
+                signing = Some(SigningKey {
+                    kid: Some(config.kid.clone()),
+                    algorithm: config.algorithm,
+                    key,
+                });
+            }
+        }
+        if let Some(secret) = legacy_secret {
+            verifying.insert(
+                None,
+                (
+                    Algorithm::HS512,
+                    DecodingKey::from_secret(secret.as_bytes()),
+                ),
+            );
+            if configs.is_empty() {
+                signing = Some(SigningKey {
+                    kid: None,
+                    algorithm: Algorithm::HS512,
+                    key: EncodingKey::from_secret(secret.as_bytes()),
+                });
+            }
+        }
+        let Some(signing) = signing else {
+            return Err(KeyError(match signing_kid {
+                Some(kid) => format!("Signing key {kid:?} is not configured"),
+                None => "No signing key, set JWT_SECRET or configure auth.keys".into(),
+            }));
+        };
+        let keys = Keys {
+            signing,
+            verifying,
+            public,
+        };
+        // Catch mismatched key pairs now instead of on the first login
+        let name = "key-check";
+        let token = keys
+            .try_encode(name)
+            .map_err(|e| KeyError(format!("Failed to sign with the signing key: {e}")))?;
+        if keys.decode(&token).is_none_or(|t| t.name.0 != name) {
+            return Err(KeyError("Signing key doesn't match its public key".into()));
+        }
+        Ok(keys)
+    }
+
+    fn try_encode(&self, name: &str) -> jsonwebtoken::errors::Result<String> {
+        let mut header = Header::new(self.signing.algorithm);
+        header.kid = self.signing.kid.clone();
+        jsonwebtoken::encode(
+            &header,
+            &Jwt {
+                name: name.to_string().into(),
+                exp: jsonwebtoken::get_current_timestamp() + TOKEN_LIFETIME,
+                scopes: None,
+            },
+            &self.signing.key,
+        )
+    }
+
+    /// Issues a session token for `name` with the current signing key
+    pub fn encode(&self, name: &str) -> String {
+        self.try_encode(name)
+            .expect("Signing key was checked at startup")
+    }
+
+    /// Checks a session token against the key named by its `kid`
+    pub fn decode(&self, token: &str) -> Option<Jwt> {
+        let token = token.trim();
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let (algorithm, key) = self.verifying.get(&header.kid)?;
+        let validation = Validation::new(*algorithm);
+        jsonwebtoken::decode::<Jwt>(token, key, &validation)
+            .inspect_err(|e| log::warn!("Rejected token: {e}"))
+            .ok()
+            .map(|t| t.claims)
+    }
+}
+
+type LoadedKey = (Option<EncodingKey>, (DecodingKey, Option<String>));
+
+/// Reads a key's material, returning the encoding key if it can sign, and the decoding key
+/// along with its public PEM if it has one
+fn load_key(config: &KeyConfig) -> Result<LoadedKey, KeyError> {
+    let kid = &config.kid;
+    let read = |path: &PathBuf| {
+        std::fs::read(path).map_err(|e| KeyError::new(kid, format!("{}: {e}", path.display())))
+    };
+    match config.algorithm {
+        Algorithm::HS512 => {
+            let Some(var) = &config.secret_env else {
+                return Err(KeyError::new(kid, "HS512 keys need a secret_env"));
+            };
+            let secret =
+                std::env::var(var).map_err(|_| KeyError::new(kid, format!("{var} is not set")))?;
+            Ok((
+                Some(EncodingKey::from_secret(secret.as_bytes())),
+                (DecodingKey::from_secret(secret.as_bytes()), None),
+            ))
+        }
+        Algorithm::RS256 | Algorithm::EdDSA => {
+            let rsa = config.algorithm == Algorithm::RS256;
+            let Some(public_key) = &config.public_key else {
+                return Err(KeyError::new(kid, "Missing public_key"));
+            };
+            let public_pem = read(public_key)?;
+            let decoding = if rsa {
+                DecodingKey::from_rsa_pem(&public_pem)
+            } else {
+                DecodingKey::from_ed_pem(&public_pem)
+            }
+            .map_err(|e| KeyError::new(kid, e))?;
+            let encoding = match &config.private_key {
+                Some(path) => {
+                    let pem = read(path)?;
+                    Some(
+                        if rsa {
+                            EncodingKey::from_rsa_pem(&pem)
+                        } else {
+                            EncodingKey::from_ed_pem(&pem)
+                        }
+                        .map_err(|e| KeyError::new(kid, e))?,
+                    )
+                }
+                None => None,
+            };
+            let public_pem = String::from_utf8(public_pem)
+                .map_err(|_| KeyError::new(kid, "Public key is not a PEM file"))?;
+            Ok((encoding, (decoding, Some(public_pem))))
+        }
+        other => Err(KeyError::new(
+            kid,
+            format!("Unsupported algorithm {other:?}"),
+        )),
+    }
+}
+
+/// Public keys for verifying session tokens, matched to tokens by `kid`
+#[get("/keys")]
+pub fn public_keys(keys: &State<Keys>) -> Json<Vec<PublicKey>> {
+    Json(keys.public.clone())
+}
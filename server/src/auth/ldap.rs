@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use ldap3::{dn_escape, LdapConnAsync, LdapConnSettings};
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+
+use crate::{
+    auth::backend::{AuthBackend, BackendError},
+    SqliteDB,
+};
+
+/// LDAP result code for a wrong DN or password
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// The `auth.ldap` table of `Rocket.toml`
+#[derive(Deserialize, Clone)]
+pub struct LdapConfig {
+    /// e.g. `ldap://localhost:3893`
+    pub url: String,
+    /// DN to bind as, with `{username}` replaced by the escaped login name,
+    /// e.g. `cn={username},ou=people,dc=example,dc=com`
+    pub bind_dn: String,
+    #[serde(default)]
+    pub starttls: bool,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+impl LdapConfig {
+    /// The DN `name` binds as
+    pub fn bind_dn_for(&self, name: &str) -> String {
+        self.bind_dn.replace("{username}", &dn_escape(name))
+    }
+}
+
+/// The bcrypt hash of `name` if they're an active user with a password of their own, like
+/// the seeded admin, rather than one from the directory
+pub(crate) fn local_hash(d: &rusqlite::Connection, name: &str) -> rusqlite::Result<Option<String>> {
+    Ok(d.query_row(
+        "SELECT password FROM users WHERE user_id = ? AND active = 1",
+        params![name],
+        |r| r.get::<_, String>(0),
+    )
+    .optional()?
+    .filter(|hash| hash.parse::<bcrypt::HashParts>().is_ok()))
+}
+
+/// Logs users in by binding to a directory server as them
+pub struct LdapBackend {
+    config: LdapConfig,
+}
+
+impl LdapBackend {
+    pub fn new(config: LdapConfig) -> Self {
+        LdapBackend { config }
+    }
+
+    /// Tries a simple bind as `name`, returning whether the directory accepted the password
+    pub async fn bind(&self, name: &str, password: &str) -> Result<bool, ldap3::LdapError> {
+        // An empty password is an unauthenticated bind, which servers happily accept
+        if name.is_empty() || password.is_empty() {
+            return Ok(false);
+        }
+        let settings = LdapConnSettings::new()
+            .set_starttls(self.config.starttls)
+            .set_conn_timeout(Duration::from_secs(self.config.timeout_secs));
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        let dn = self.config.bind_dn_for(name);
+        let result = ldap.simple_bind(&dn, password).await?;
+        let _ = ldap.unbind().await;
+        match result.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            _ => Err(result.success().unwrap_err()),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+    async fn verify(
+        &self,
+        name: &str,
+        password: &str,
+        db: &SqliteDB,
+    ) -> Result<bool, BackendError> {
+        let n = name.to_owned();
+        if let Some(hash) = db.run(move |d| local_hash(d, &n)).await? {
+            return Ok(bcrypt::verify(password, &hash).unwrap_or(false));
+        }
+        Ok(self.bind(name, password).await?)
+    }
+
+    fn provisions_users(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,408 @@
+pub mod backend;
+pub mod invites;
+pub mod keys;
+pub mod ldap;
+pub mod oidc;
+pub mod tokens;
+
+use crate::{
+    types::{User, UserDB, UserStatus},
+    SqliteDB, UserID,
+};
+use backend::{AuthBackend, BackendKind};
+use invites::{redeem_code, RedeemError};
+use keys::{KeyConfig, Keys};
+use ldap::LdapConfig;
+use oidc::OidcConfig;
+use rocket::State;
+use rocket::{
+    form::Form,
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use rusqlite::{params, OptionalExtension};
+use tokens::{resolve_token, Scope, TOKEN_PREFIX};
+
+pub(crate) const HASH_COST: u32 = 12;
+
+#[derive(FromForm)]
+pub struct Credentials<'a> {
+    name: &'a str,
+    password: &'a str,
+}
+
+#[derive(FromForm)]
+pub struct Registration<'a> {
+    name: &'a str,
+    password: &'a str,
+    /// Signup code from an admin, required unless registration is open
+    code: Option<&'a str>,
+    email: Option<&'a str>,
+}
+
+/// The `auth` table of `Rocket.toml`
+#[derive(serde::Deserialize, Default)]
+pub struct AuthConfig {
+    /// Lets anyone create an account without a signup code
+    #[serde(default)]
+    pub open_registration: bool,
+    /// Where passwords get checked, `password` or `ldap`
+    #[serde(default)]
+    pub backend: BackendKind,
+    pub ldap: Option<LdapConfig>,
+    /// Enables single sign-on through `/auth/oidc/login`
+    pub oidc: Option<OidcConfig>,
+    /// Keys session tokens are signed and verified with, `JWT_SECRET` is used if empty
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+    /// `kid` of the key new tokens get signed with, defaults to the first key
+    pub signing_key: Option<String>,
+}
+
+impl AuthConfig {
+    pub fn backend(&self) -> Box<dyn AuthBackend> {
+        self.backend.build(self.ldap.as_ref())
+    }
+
+    pub fn keys(&self) -> Result<Keys, keys::KeyError> {
+        Keys::load(
+            &self.keys,
+            self.signing_key.as_deref(),
+            std::env::var("JWT_SECRET").ok(),
+        )
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct Jwt {
+    pub name: UserID,
+    pub exp: u64,
+    /// Only set when authenticated with an API token, session JWTs can do anything
+    #[serde(skip)]
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl Jwt {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().is_none_or(|s| s.contains(&scope))
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), Status> {
+        if self.allows(scope) {
+            Ok(())
+        } else {
+            Err(Status::Forbidden)
+        }
+    }
+}
+
+/// Resolves either a session JWT or an API token to the user it belongs to
+pub async fn authenticate(token: &str, keys: &Keys, db: &SqliteDB) -> Result<Jwt, &'static str> {
+    let token = token.trim();
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    if token.starts_with(TOKEN_PREFIX) {
+        return resolve_token(db, token).await.ok_or("Invalid API Token");
+    }
+    let Some(token) = keys.decode(token) else {
+        return Err("Invalid Token");
+    };
+    if token.exp < jsonwebtoken::get_current_timestamp() {
+        return Err("Token Expired");
+    }
+    if !is_active(db, &token.name).await {
+        return Err("Account deactivated");
+    }
+    Ok(token)
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Jwt {
+    type Error = &'static str;
+    async fn from_request(r: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(token) = r.headers().get_one("authorization") else {
+            return Outcome::Error((Status::Unauthorized, "No Authorization Header"));
+        };
+        let Some(db) = SqliteDB::get_one(r.rocket()).await else {
+            return Outcome::Error((Status::InternalServerError, "Database unavailable"));
+        };
+        let Some(keys) = r.rocket().state::<Keys>() else {
+            return Outcome::Error((Status::InternalServerError, "Keys unavailable"));
+        };
+        match authenticate(token, keys, &db).await {
+            Ok(token) => Outcome::Success(token),
+            Err(e) => Outcome::Error((Status::Unauthorized, e)),
+        }
+    }
+}
+
+/// A logged in user with the `is_admin` flag set. API tokens never count as admins.
+pub struct Admin(pub Jwt);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = &'static str;
+    async fn from_request(r: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = rocket::outcome::try_outcome!(r.guard::<Jwt>().await);
+        if token.scopes.is_some() {
+            return Outcome::Error((Status::Forbidden, "Admin only"));
+        }
+        let Some(db) = SqliteDB::get_one(r.rocket()).await else {
+            return Outcome::Error((Status::InternalServerError, "Database unavailable"));
+        };
+        if !is_admin(&db, &token.name).await {
+            return Outcome::Error((Status::Forbidden, "Admin only"));
+        }
+        Outcome::Success(Admin(token))
+    }
+}
+
+/// Whether `id` exists and wasn't deactivated
+pub(crate) fn account_active(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<bool> {
+    Ok(d.query_row(
+        "SELECT active FROM users WHERE user_id = ?",
+        params![id.0],
+        |row| row.get(0),
+    )
+    .optional()?
+    .unwrap_or(false))
+}
+
+pub async fn is_active(db: &SqliteDB, id: &UserID) -> bool {
+    let id = id.clone();
+    db.run(move |d| account_active(d, &id))
+        .await
+        .unwrap_or(false)
+}
+
+pub async fn is_admin(db: &SqliteDB, id: &UserID) -> bool {
+    let id = id.clone();
+    db.run(move |d| {
+        d.query_row(
+            "SELECT is_admin FROM users WHERE user_id = ? AND active = 1",
+            params![id.0],
+            |row| row.get(0),
+        )
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Lets the admin account the migrations seed log in with the `ADMIN_PASSWORD` environment
+/// variable. The migrations lock it again on every start, so it can't log in once that's unset.
+pub fn unlock_seeded_admin(d: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let Some(password) = std::env::var("ADMIN_PASSWORD")
+        .ok()
+        .filter(|p| !p.is_empty())
+    else {
+        return Ok(());
+    };
+    let hash = bcrypt::hash(password, HASH_COST).expect("Failed to hash ADMIN_PASSWORD");
+    d.execute(
+        "UPDATE users SET password = ? WHERE user_id = 'admin'",
+        params![hash],
+    )?;
+    log::warn!("The admin account can log in with ADMIN_PASSWORD");
+    Ok(())
+}
+
+/// Fails unless `user` is `id` themself or an admin
+pub async fn require_self_or_admin(user: &Jwt, id: &UserID, db: &SqliteDB) -> Result<(), Status> {
+    if user.name != *id && !is_admin(db, &user.name).await {
+        return Err(Status::Forbidden);
+    }
+    Ok(())
+}
+
+/// Password of accounts whose real password lives with an external backend. Not a valid
+/// bcrypt hash, so it never matches a local login.
+pub(crate) const EXTERNAL_PASSWORD: &str = "!";
+
+/// Creates the account `id` for someone an external backend vouched for if it's missing,
+/// returning whether it was created
+fn create_external_user(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<bool> {
+    let created = d.execute(
+        "INSERT OR IGNORE INTO users (user_id, password) VALUES (?, ?)",
+        params![id.0, EXTERNAL_PASSWORD],
+    )? == 1;
+    if created {
+        d.execute(
+            "INSERT INTO timesheets (user_id, clocked_in, current_id) VALUES (?, 0, NULL)",
+            params![id.0],
+        )?;
+    }
+    Ok(created)
+}
+
+/// Whether `id` may get a session token. Service accounts only ever act through API tokens.
+fn may_log_in(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT active AND NOT service_account FROM users WHERE user_id = ?",
+        params![id.0],
+        |r| r.get(0),
+    )
+}
+
+/// Adds an account made by [`create_external_user`] to the users in memory
+async fn add_external_user(user_db: &UserDB, id: UserID) {
+    log::info!("Provisioned new user: {id}");
+    user_db
+        .add_user(User {
+            name: id,
+            status: UserStatus::Inactive,
+            watching_board: false,
+            password: EXTERNAL_PASSWORD.into(),
+        })
+        .await;
+}
+
+/// Creates the account of someone the auth backend vouched for the first time they log in,
+/// returning whether they're allowed to log in
+async fn provision_user(db: &SqliteDB, user_db: &UserDB, name: &str) -> bool {
+    let id = UserID(name.into());
+    let uid = id.clone();
+    let res = db
+        .run(move |d| {
+            let tx = d.transaction()?;
+            let created = create_external_user(&tx, &uid)?;
+            let allowed = may_log_in(&tx, &uid)?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>((created, allowed))
+        })
+        .await;
+    match res {
+        Ok((created, allowed)) => {
+            if created {
+                add_external_user(user_db, id).await;
+            }
+            allowed
+        }
+        Err(e) => {
+            log::error!("Failed to provision {id}: {e}");
+            false
+        }
+    }
+}
+
+#[post("/login", data = "<login>")]
+pub async fn login_user(
+    login: Form<Credentials<'_>>,
+    backend: &State<Box<dyn AuthBackend>>,
+    keys: &State<Keys>,
+    user_db: &State<UserDB>,
+    db: SqliteDB,
+) -> Option<String> {
+    let Credentials { name, password } = login.into_inner();
+    match backend.verify(name, password, &db).await {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => {
+            log::error!("Failed to check credentials for {name}: {e}");
+            return None;
+        }
+    }
+    if backend.provisions_users() && !provision_user(&db, user_db, name).await {
+        return None;
+    }
+    Some(keys.encode(name))
+}
+
+#[get("/checkuser/<name>")]
+pub async fn check_user(name: String, db: &State<UserDB>) -> &'static str {
+    if db.read().await.contains_key(&UserID(name)) {
+        "found"
+    } else {
+        "not found"
+    }
+}
+
+#[post("/createuser", data = "<form>")]
+pub async fn create_user(
+    form: Form<Registration<'_>>,
+    config: &State<AuthConfig>,
+    backend: &State<Box<dyn AuthBackend>>,
+    keys: &State<Keys>,
+    user_db: &State<UserDB>,
+    db: SqliteDB,
+) -> (Status, Option<String>) {
+    let Registration {
+        name,
+        password,
+        code,
+        email,
+    } = form.into_inner();
+    // Accounts from an external backend get created when they first log in
+    if backend.provisions_users() || (code.is_none() && !config.open_registration) {
+        return (Status::Forbidden, None);
+    }
+    let mut user_db = user_db.write().await;
+    let id = UserID(name.into());
+
+    let uid = id.clone();
+    let res = db
+        .run(move |d| {
+            d.query_row(
+                "SELECT user_id FROM users WHERE user_id=?",
+                params![uid.0],
+                |_| Ok(()),
+            )
+        })
+        .await;
+    let Err(rusqlite::Error::QueryReturnedNoRows) = res else {
+        log::error!("User creation error: {res:?}");
+        return (Status::Conflict, None);
+    };
+
+    let Ok(hashed) = bcrypt::hash(password, HASH_COST) else {
+        return (Status::InternalServerError, None);
+    };
+    // Insert the user into the sqlite database, using up the signup code if one was given
+    let (uid, pass) = (id.clone(), hashed.clone());
+    let (code, email) = (code.map(str::to_owned), email.map(str::to_owned));
+    let res = db
+        .run(move |d| {
+            let tx = d.transaction()?;
+            let rooms = match &code {
+                Some(code) => redeem_code(&tx, code, &uid.0, email.as_deref())?,
+                None => Vec::new(),
+            };
+            let params = params![uid.0, pass, email];
+            tx.execute(
+                "INSERT INTO users (user_id, password, email) VALUES (?1, ?2, ?3)",
+                params,
+            )?;
+            tx.execute(
+                "INSERT INTO timesheets (user_id, clocked_in, current_id) VALUES (?1, 0, NULL)",
+                &params[..1],
+            )?;
+            for room in rooms {
+                tx.execute(
+                    "INSERT OR IGNORE INTO chatroom_users (chatroom_id, user_id) \
+                    SELECT chatroom_id, ? FROM chatrooms WHERE chatroom_id = ?",
+                    params![uid.0, room.0],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await;
+    match res {
+        Ok(()) => {}
+        Err(RedeemError::InvalidCode) => return (Status::Forbidden, None),
+        Err(RedeemError::Db(e)) => {
+            log::error!("Failed to insert user into database: {}", e);
+            return (Status::InternalServerError, None);
+        }
+    }
+    // Insert the user into the database
+    user_db.insert(
+        id.clone(),
+        User {
+            name: id,
+            password: hashed,
+            status: UserStatus::Inactive,
+            watching_board: false,
+        },
+    );
+    (Status::Ok, Some(keys.encode(name)))
+}
@@ -0,0 +1,313 @@
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata},
+    reqwest::async_http_client,
+    AccessTokenHash, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce,
+    OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    response::Redirect,
+    time::Duration,
+    tokio::sync::OnceCell,
+    State,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{
+        add_external_user, create_external_user, keys::Keys, may_log_in, AuthConfig,
+        EXTERNAL_PASSWORD,
+    },
+    types::{UserDB, UserID},
+    SqliteDB,
+};
+
+/// Private cookie holding the in-flight login between `/login` and `/callback`
+const LOGIN_COOKIE: &str = "oidc_login";
+
+type OidcError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Which ID token claim becomes the user's name
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UsernameClaim {
+    #[default]
+    PreferredUsername,
+    /// Only used when the provider says the email is verified
+    Email,
+    Sub,
+}
+
+/// The `auth.oidc` table of `Rocket.toml`
+#[derive(Deserialize, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to, i.e. this server's `/auth/oidc/callback`
+    pub redirect_url: String,
+    #[serde(default)]
+    pub username_claim: UsernameClaim,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Where users end up after logging in, with `#token=<jwt>` appended
+    #[serde(default = "default_post_login")]
+    pub post_login_redirect: String,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["profile".into(), "email".into()]
+}
+
+fn default_post_login() -> String {
+    "/".into()
+}
+
+/// Client for the configured identity provider, discovered on first use
+#[derive(Default)]
+pub struct Oidc {
+    client: OnceCell<CoreClient>,
+}
+
+impl Oidc {
+    async fn client(&self, config: &OidcConfig) -> Result<&CoreClient, OidcError> {
+        self.client
+            .get_or_try_init(|| async {
+                let metadata = CoreProviderMetadata::discover_async(
+                    IssuerUrl::new(config.issuer_url.clone())?,
+                    async_http_client,
+                )
+                .await?;
+                Ok(CoreClient::from_provider_metadata(
+                    metadata,
+                    ClientId::new(config.client_id.clone()),
+                    config.client_secret.clone().map(ClientSecret::new),
+                )
+                .set_redirect_uri(RedirectUrl::new(config.redirect_url.clone())?))
+            })
+            .await
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    pkce_verifier: String,
+}
+
+fn oidc_config(config: &AuthConfig) -> Result<&OidcConfig, Status> {
+    config.oidc.as_ref().ok_or(Status::NotFound)
+}
+
+#[get("/oidc/login")]
+pub async fn oidc_login(
+    config: &State<AuthConfig>,
+    oidc: &State<Oidc>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, Status> {
+    let config = oidc_config(config)?;
+    let client = oidc.client(config).await.map_err(|e| {
+        log::error!("Failed to reach identity provider: {e}");
+        Status::BadGateway
+    })?;
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, state, nonce) = config
+        .scopes
+        .iter()
+        .fold(
+            client.authorize_url(
+                CoreAuthenticationFlow::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            ),
+            |req, scope| req.add_scope(Scope::new(scope.clone())),
+        )
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+    let pending = PendingLogin {
+        state: state.secret().clone(),
+        nonce: nonce.secret().clone(),
+        pkce_verifier: pkce_verifier.secret().clone(),
+    };
+    let pending = serde_json::to_string(&pending).map_err(|_| Status::InternalServerError)?;
+    // Lax so the cookie survives the redirect back from the provider
+    cookies.add_private(
+        Cookie::build((LOGIN_COOKIE, pending))
+            .same_site(SameSite::Lax)
+            .http_only(true)
+            .max_age(Duration::minutes(10)),
+    );
+    Ok(Redirect::to(url.to_string()))
+}
+
+/// Query the provider redirects back with
+#[derive(FromForm)]
+pub struct Callback {
+    code: String,
+    state: String,
+}
+
+#[get("/oidc/callback?<callback..>")]
+pub async fn oidc_callback(
+    callback: Callback,
+    config: &State<AuthConfig>,
+    oidc: &State<Oidc>,
+    cookies: &CookieJar<'_>,
+    keys: &State<Keys>,
+    user_db: &State<UserDB>,
+    db: SqliteDB,
+) -> Result<Redirect, Status> {
+    let config = oidc_config(config)?;
+    let Callback { code, state } = callback;
+    let Some(pending) = cookies
+        .get_private(LOGIN_COOKIE)
+        .and_then(|c| serde_json::from_str::<PendingLogin>(c.value()).ok())
+    else {
+        return Err(Status::BadRequest);
+    };
+    cookies.remove_private(LOGIN_COOKIE);
+    if pending.state != state {
+        log::warn!("OIDC callback with mismatched state");
+        return Err(Status::BadRequest);
+    }
+    let claims = match verify_login(oidc, config, code, pending).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            log::error!("OIDC login failed: {e}");
+            return Err(Status::Unauthorized);
+        }
+    };
+    let Some(name) = username(&claims, config.username_claim) else {
+        log::error!("ID token for {:?} has no usable username", claims.subject());
+        return Err(Status::Unauthorized);
+    };
+    let issuer = claims.issuer().to_string();
+    let subject = claims.subject().to_string();
+    let Some(user) = provision_oidc_user(&db, user_db, issuer, subject, name).await else {
+        return Err(Status::Forbidden);
+    };
+    let token = keys.encode(&user.0);
+    Ok(Redirect::to(format!(
+        "{}#token={token}",
+        config.post_login_redirect
+    )))
+}
+
+/// Trades the authorization code for tokens and validates the ID token
+async fn verify_login(
+    oidc: &Oidc,
+    config: &OidcConfig,
+    code: String,
+    pending: PendingLogin,
+) -> Result<CoreIdTokenClaims, OidcError> {
+    let client = oidc.client(config).await?;
+    let response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+        .request_async(async_http_client)
+        .await?;
+    let id_token = response
+        .id_token()
+        .ok_or("Provider did not return an ID token")?;
+    let claims = id_token.claims(&client.id_token_verifier(), &Nonce::new(pending.nonce))?;
+    if let Some(expected) = claims.access_token_hash() {
+        let actual =
+            AccessTokenHash::from_token(response.access_token(), &id_token.signing_alg()?)?;
+        if actual != *expected {
+            return Err("Access token hash mismatch".into());
+        }
+    }
+    Ok(claims.clone())
+}
+
+fn username(claims: &CoreIdTokenClaims, claim: UsernameClaim) -> Option<String> {
+    match claim {
+        UsernameClaim::PreferredUsername => claims.preferred_username().map(|u| u.to_string()),
+        UsernameClaim::Email => claims
+            .email()
+            .filter(|_| claims.email_verified() == Some(true))
+            .map(|e| e.to_string()),
+        UsernameClaim::Sub => Some(claims.subject().to_string()),
+    }
+}
+
+/// The account the identity `subject` of `issuer` logs in to, and whether it was just created.
+/// An identity gets linked on its first login, to a new account named `name` or to an existing
+/// one another backend created. `None` if `name` belongs to someone else: accounts with a
+/// local password, service accounts and accounts linked to another identity.
+pub(crate) fn oidc_account(
+    d: &rusqlite::Connection,
+    issuer: &str,
+    subject: &str,
+    name: &str,
+) -> rusqlite::Result<Option<(UserID, bool)>> {
+    let linked = d
+        .query_row(
+            "SELECT user_id FROM oidc_identities WHERE issuer = ? AND subject = ?",
+            params![issuer, subject],
+            |r| r.get(0),
+        )
+        .optional()?;
+    if let Some(user) = linked {
+        return Ok(Some((user, false)));
+    }
+    let id = UserID(name.into());
+    let created = create_external_user(d, &id)?;
+    if !created {
+        let taken: bool = d.query_row(
+            "SELECT password != ?2 OR service_account \
+              OR EXISTS (SELECT 1 FROM oidc_identities WHERE user_id = ?1) \
+            FROM users WHERE user_id = ?1",
+            params![id.0, EXTERNAL_PASSWORD],
+            |r| r.get(0),
+        )?;
+        if taken {
+            return Ok(None);
+        }
+    }
+    d.execute(
+        "INSERT INTO oidc_identities (issuer, subject, user_id, linked_at) VALUES (?, ?, ?, ?)",
+        params![issuer, subject, id.0, chrono::Utc::now()],
+    )?;
+    Ok(Some((id, created)))
+}
+
+/// Finds or creates the account of someone the provider vouched for, if they may log in
+async fn provision_oidc_user(
+    db: &SqliteDB,
+    user_db: &UserDB,
+    issuer: String,
+    subject: String,
+    name: String,
+) -> Option<UserID> {
+    let sub = subject.clone();
+    let res = db
+        .run(move |d| {
+            let tx = d.transaction()?;
+            let account = match oidc_account(&tx, &issuer, &sub, &name)? {
+                Some((id, created)) => Some((may_log_in(&tx, &id)?, id, created)),
+                None => None,
+            };
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(account)
+        })
+        .await;
+    match res {
+        Ok(Some((allowed, id, created))) => {
+            if created {
+                add_external_user(user_db, id.clone()).await;
+            }
+            allowed.then_some(id)
+        }
+        Ok(None) => {
+            log::warn!("Refused OIDC login of {subject}, its username belongs to another account");
+            None
+        }
+        Err(e) => {
+            log::error!("Failed to provision OIDC user {subject}: {e}");
+            None
+        }
+    }
+}
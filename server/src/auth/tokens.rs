@@ -0,0 +1,237 @@
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::{http::Status, serde::json::Json};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::{Admin, Jwt},
+    types::UserID,
+    SqliteDB,
+};
+
+/// Prefix that tells API tokens apart from session JWTs
+pub const TOKEN_PREFIX: &str = "mlg_";
+const SECRET_LENGTH: usize = 40;
+
+/// What an API token is allowed to do. Session JWTs are allowed everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "chat:read")]
+    ChatRead,
+    #[serde(rename = "chat:write")]
+    ChatWrite,
+    #[serde(rename = "time:read")]
+    TimeRead,
+    #[serde(rename = "time:write")]
+    TimeWrite,
+}
+
+/// An API token as listed to admins, never including the secret itself
+#[derive(Serialize)]
+pub struct ApiToken {
+    id: i64,
+    user: UserID,
+    name: String,
+    scopes: Vec<Scope>,
+    created_by: UserID,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+fn token_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ApiToken> {
+    let scopes: String = row.get(3)?;
+    Ok(ApiToken {
+        id: row.get(0)?,
+        user: row.get(1)?,
+        name: row.get(2)?,
+        scopes: serde_json::from_str(&scopes).unwrap_or_default(),
+        created_by: row.get(4)?,
+        created_at: row.get(5)?,
+        expires_at: row.get(6)?,
+        last_used_at: row.get(7)?,
+        revoked_at: row.get(8)?,
+    })
+}
+
+/// A random secret to hand out, of which only the hash gets stored
+pub(crate) fn new_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct NewApiToken {
+    /// The service account the token acts as
+    user: UserID,
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// A freshly created token, the only time the secret is ever shown
+#[derive(Serialize)]
+pub struct CreatedApiToken {
+    token: String,
+    #[serde(flatten)]
+    info: ApiToken,
+}
+
+#[post("/tokens", data = "<new>")]
+pub async fn create_token(
+    new: Json<NewApiToken>,
+    admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<CreatedApiToken>, Status> {
+    let NewApiToken {
+        user,
+        name,
+        scopes,
+        expires_at,
+    } = new.into_inner();
+    let token = format!("{TOKEN_PREFIX}{}", new_secret());
+    let hash = hash_token(&token);
+    let scope_json = serde_json::to_string(&scopes).map_err(|_| Status::InternalServerError)?;
+    let created_by = admin.0.name;
+    let info = db
+        .run(move |d| {
+            // Tokens can only be handed to service accounts
+            let is_service: Option<bool> = d
+                .query_row(
+                    "SELECT service_account FROM users WHERE user_id = ? AND active = 1",
+                    params![user.0],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            if is_service != Some(true) {
+                return Ok(None);
+            }
+            let created_at = Utc::now();
+            d.execute(
+                "INSERT INTO api_tokens (user_id, name, token_hash, scopes, created_by, \
+                created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    user.0,
+                    name,
+                    hash,
+                    scope_json,
+                    created_by.0,
+                    created_at,
+                    expires_at
+                ],
+            )?;
+            Ok::<_, rusqlite::Error>(Some(ApiToken {
+                id: d.last_insert_rowid(),
+                user,
+                name,
+                scopes,
+                created_by,
+                created_at,
+                expires_at,
+                last_used_at: None,
+                revoked_at: None,
+            }))
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to create API token: {e}");
+            Status::InternalServerError
+        })?
+        .ok_or(Status::BadRequest)?;
+    Ok(Json(CreatedApiToken { token, info }))
+}
+
+#[get("/tokens?<user>")]
+pub async fn list_tokens(
+    user: Option<String>,
+    _admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<Vec<ApiToken>>, Status> {
+    db.run(move |d| {
+        d.prepare(
+            "SELECT token_id, user_id, name, scopes, created_by, created_at, expires_at, \
+            last_used_at, revoked_at FROM api_tokens \
+            WHERE ?1 IS NULL OR user_id = ?1 ORDER BY created_at",
+        )?
+        .query_map(params![user], token_from_row)?
+        .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list API tokens: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[delete("/tokens/<id>")]
+pub async fn revoke_token(id: i64, _admin: Admin, db: SqliteDB) -> Status {
+    match db
+        .run(move |d| {
+            d.execute(
+                "UPDATE api_tokens SET revoked_at = ? WHERE token_id = ? AND revoked_at IS NULL",
+                params![Utc::now(), id],
+            )
+        })
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::Ok,
+        Err(e) => {
+            log::error!("Failed to revoke API token: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+type TokenRow = (i64, UserID, String, Option<DateTime<Utc>>);
+
+/// Looks up an unrevoked, unexpired API token belonging to an active user
+pub(crate) fn lookup_token(d: &rusqlite::Connection, token: &str) -> rusqlite::Result<Option<Jwt>> {
+    let Some((id, name, scopes, expires_at)): Option<TokenRow> = d
+        .query_row(
+            "SELECT t.token_id, t.user_id, t.scopes, t.expires_at FROM api_tokens t \
+            INNER JOIN users u ON u.user_id = t.user_id \
+            WHERE t.token_hash = ? AND t.revoked_at IS NULL AND u.active = 1",
+            params![hash_token(token)],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    if expires_at.is_some_and(|exp| exp < Utc::now()) {
+        return Ok(None);
+    }
+    d.execute(
+        "UPDATE api_tokens SET last_used_at = ? WHERE token_id = ?",
+        params![Utc::now(), id],
+    )?;
+    Ok(Some(Jwt {
+        name,
+        exp: expires_at.map_or(u64::MAX, |exp| exp.timestamp() as u64),
+        scopes: Some(serde_json::from_str(&scopes).unwrap_or_default()),
+    }))
+}
+
+pub async fn resolve_token(db: &SqliteDB, token: &str) -> Option<Jwt> {
+    let token = token.to_owned();
+    db.run(move |d| lookup_token(d, &token))
+        .await
+        .inspect_err(|e| log::error!("Failed to look up API token: {e}"))
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{
+    auth::{require_self_or_admin, tokens::Scope, Jwt},
+    types::{ChatRoomID, UserID},
+    SqliteDB,
+};
+
+#[derive(Serialize)]
+pub struct Block {
+    user: UserID,
+    blocked_at: DateTime<Utc>,
+}
+
+/// Whether `blocker` has blocked `blocked`
+pub fn has_blocked(
+    d: &rusqlite::Connection,
+    blocker: &UserID,
+    blocked: &UserID,
+) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM user_blocks WHERE blocker_id = ? AND blocked_id = ?)",
+        params![blocker.0, blocked.0],
+        |r| r.get(0),
+    )
+}
+
+/// Whether `room` is a direct message room and the other person in it blocked `sender`
+pub fn blocked_in_direct_room(
+    d: &rusqlite::Connection,
+    room: &ChatRoomID,
+    sender: &UserID,
+) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM chatrooms c \
+          INNER JOIN chatroom_users cu ON cu.chatroom_id = c.chatroom_id \
+          INNER JOIN user_blocks b ON b.blocker_id = cu.user_id AND b.blocked_id = ?2 \
+        WHERE c.chatroom_id = ?1 AND c.direct = 1)",
+        params![room.0, sender.0],
+        |r| r.get(0),
+    )
+}
+
+/// Everyone who has blocked `id`
+pub fn blocked_by(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<HashSet<UserID>> {
+    d.prepare("SELECT blocker_id FROM user_blocks WHERE blocked_id = ?")?
+        .query_map(params![id.0], |r| r.get(0))?
+        .collect()
+}
+
+/// Everyone `id` has blocked
+pub fn blocked_users(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<HashSet<UserID>> {
+    d.prepare("SELECT blocked_id FROM user_blocks WHERE blocker_id = ?")?
+        .query_map(params![id.0], |r| r.get(0))?
+        .collect()
+}
+
+#[get("/<id>/blocks")]
+pub async fn list_blocks(id: UserID, user: Jwt, db: SqliteDB) -> Result<Json<Vec<Block>>, Status> {
+    user.require(Scope::ChatRead)?;
+    require_self_or_admin(&user, &id, &db).await?;
+    db.run(move |d| {
+        d.prepare(
+            "SELECT blocked_id, created_at FROM user_blocks \
+            WHERE blocker_id = ? ORDER BY created_at",
+        )?
+        .query_map(params![id.0], |r| {
+            Ok(Block {
+                user: r.get(0)?,
+                blocked_at: r.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list blocks: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[put("/<id>/blocks/<blocked>")]
+pub async fn block_user(id: UserID, blocked: UserID, user: Jwt, db: SqliteDB) -> Status {
+    if let Err(status) = user.require(Scope::ChatWrite) {
+        return status;
+    }
+    if let Err(status) = require_self_or_admin(&user, &id, &db).await {
+        return status;
+    }
+    if id == blocked {
+        return Status::BadRequest;
+    }
+    match db
+        .run(move |d| {
+            d.execute(
+                "INSERT OR IGNORE INTO user_blocks (blocker_id, blocked_id, created_at) \
+                SELECT ?1, user_id, ?3 FROM users WHERE user_id = ?2",
+                params![id.0, blocked.0, Utc::now()],
+            )?;
+            has_blocked(d, &id, &blocked)
+        })
+        .await
+    {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::NotFound,
+        Err(e) => {
+            log::error!("Failed to block user: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+#[delete("/<id>/blocks/<blocked>")]
+pub async fn unblock_user(id: UserID, blocked: UserID, user: Jwt, db: SqliteDB) -> Status {
+    if let Err(status) = user.require(Scope::ChatWrite) {
+        return status;
+    }
+    if let Err(status) = require_self_or_admin(&user, &id, &db).await {
+        return status;
+    }
+    match db
+        .run(move |d| {
+            d.execute(
+                "DELETE FROM user_blocks WHERE blocker_id = ? AND blocked_id = ?",
+                params![id.0, blocked.0],
+            )
+        })
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::Ok,
+        Err(e) => {
+            log::error!("Failed to unblock user: {e}");
+            Status::InternalServerError
+        }
+    }
+}
@@ -1,18 +1,22 @@
+use crate::blocks::{blocked_by, blocked_users};
 use crate::logger::Log;
+use crate::notifications::deliveries;
+use crate::profiles::active_profiles;
 use crate::types::{message_from_row, ChatRoomID, ServerAction, UserAction, UserDB};
 use crate::ws_handler::WebSocketHandler;
-use crate::SqliteDB;
 use crate::{
-    auth::{decode_jwt, Jwt},
+    auth::{authenticate, keys::Keys, tokens::Scope, Jwt},
     types::{ChatMessage, UserStatus},
+    users::SYSTEM_USER,
     UserID,
 };
+use crate::{SendError, SqliteDB};
 
 use rocket::futures::{SinkExt, StreamExt};
 use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::tokio::select;
-use rocket::tokio::sync::broadcast::Receiver;
+use rocket::tokio::sync::broadcast::{error::RecvError, Receiver};
 use rocket::{Shutdown, State};
 use rusqlite::params;
 use std::path::PathBuf;
@@ -24,25 +28,27 @@ pub async fn connect<'r>(
     ws: ws::WebSocket,
     db: SqliteDB,
     user_db: &'r State<UserDB>,
+    keys: &'r State<Keys>,
     log: &'r State<Log>,
     shutdown: Shutdown,
 ) -> ws::Channel<'r> {
     ws.channel(move |stream| {
-        Box::pin(async move { handle_connection(stream, shutdown, db, user_db, log).await })
+        Box::pin(async move { handle_connection(stream, shutdown, db, user_db, keys, log).await })
     })
 }
 
 #[get("/list")]
-pub async fn list_rooms(db: SqliteDB, user: Jwt) -> Json<Vec<String>> {
+pub async fn list_rooms(db: SqliteDB, user: Jwt) -> Result<Json<Vec<String>>, Status> {
+    user.require(Scope::ChatRead)?;
     let rooms: Vec<_> = db
         .run(move |d| {
-            let mut stmt = d.prepare("select chatroom_id from chatrooms where user_id = ?")?;
+            let mut stmt = d.prepare("select chatroom_id from chatroom_users where user_id = ?")?;
             let stmt = stmt.query_map(params![user.name.0], |r| r.get(0))?;
             stmt.collect::<Result<Vec<_>, _>>()
         })
         .await
         .unwrap_or_default();
-    Json(rooms)
+    Ok(Json(rooms))
 }
 
 #[post("/adduser/<room>/<user_id>")]
@@ -73,55 +79,41 @@ pub async fn add_user_to_room(
 }
 
 #[post("/chatroom", data = "<msg>")]
-pub async fn send_message(msg: Json<ChatMessage>, user_db: &State<UserDB>, db: SqliteDB) {
-    let chatroom_id = msg.0.room.clone();
-    let users = db
-        .run(move |d| {
-            let mut stmt = d.prepare("select user_id from chatroom_users where chatroom_id = ?")?;
-            let stmt = stmt.query_map(params![chatroom_id.0], |r| r.get(0).map(UserID))?;
-            stmt.collect::<Result<Vec<_>, _>>()
-        })
-        .await
-        .unwrap_or_default();
-    let user_db = user_db.read().await;
-    let action = ServerAction::Message(msg.0);
-    for user in &users {
-        let Some(user) = user_db.get(user) else {
-            log::error!("User not found: {:?}", user);
-            continue;
-        };
-
-        if let UserStatus::Active(sender) = &user.status {
-            let Ok(_) = sender.send(action.clone()) else {
-                log::error!("Failed to send message to user: {:?}", user.name);
-                continue;
-            };
-        }
+pub async fn send_message(
+    msg: Json<ChatMessage>,
+    user: Jwt,
+    user_db: &State<UserDB>,
+    db: SqliteDB,
+) -> Status {
+    if user.require(Scope::ChatWrite).is_err() {
+        return Status::Forbidden;
+    }
+    let mut msg = msg.into_inner();
+    // Messages always come from whoever is authenticated
+    msg.sender = user.name;
+    match db.send_msg(msg, user_db).await {
+        Ok(()) => Status::Ok,
+        Err(SendError::Blocked) => Status::Forbidden,
+        Err(SendError::Db(_)) => Status::InternalServerError,
     }
 }
 
-#[post("/create/<name>/<users..>")]
+/// Creates a room, or with `direct` a direct message room with exactly one other user
+#[post("/create/<name>/<users..>?<direct>")]
 pub async fn create_room(
     name: ChatRoomID,
+    direct: Option<bool>,
     db: SqliteDB,
     user_db: &State<UserDB>,
     users: PathBuf,
     user: Jwt,
 ) -> Status {
+    if user.require(Scope::ChatWrite).is_err() {
+        return Status::Forbidden;
+    }
+    let direct = direct.unwrap_or(false);
     let room = name.0.replace('\'', "\'");
     let r2 = room.clone();
-    if let Err(e) = db
-        .run(move |d| {
-            d.execute(
-                "INSERT INTO chatrooms (chatroom_id) values (?)",
-                params![room],
-            )
-        })
-        .await
-    {
-        println!("Error creating room: {:?}", e);
-        return Status::InternalServerError;
-    };
 
     let udb = user_db.read().await;
 
@@ -135,11 +127,41 @@ pub async fn create_room(
     if !users.contains(&user.name) {
         users.push(user.name.clone());
     }
+    users.sort();
+    users.dedup();
+    // Checked before dropping blockers, so a direct message doesn't tell you you're blocked
+    if direct && users.len() != 2 {
+        return Status::BadRequest;
+    }
+
+    // Nobody gets pulled into a room by someone they blocked
+    let creator = user.name.clone();
+    match db.run(move |d| blocked_by(d, &creator)).await {
+        Ok(blockers) => users.retain(|u| !blockers.contains(u)),
+        Err(e) => {
+            log::error!("Failed to check blocks: {e}");
+            return Status::InternalServerError;
+        }
+    }
 
-    if users.is_empty() {
+    // A direct room with one of its two members gone is no direct room at all
+    if users.is_empty() || (direct && users.len() != 2) {
         return Status::NotFound;
     }
 
+    if let Err(e) = db
+        .run(move |d| {
+            d.execute(
+                "INSERT INTO chatrooms (chatroom_id, direct) values (?, ?)",
+                params![room, direct],
+            )
+        })
+        .await
+    {
+        println!("Error creating room: {:?}", e);
+        return Status::InternalServerError;
+    };
+
     let uclone = users.clone();
 
     if let Err(e) = db
@@ -160,33 +182,44 @@ pub async fn create_room(
         return Status::InternalServerError;
     };
 
-    user_db
-        .write_to(
-            ChatMessage {
-                // id: MessageID(0),
-                sender: "admin".into(),
-                room: name,
-                content: format!("{} created the room", user.name.0),
-                timestamp: jsonwebtoken::get_current_timestamp() as f64,
-            },
-            &uclone,
-        )
-        .await;
+    let msg = ChatMessage {
+        // id: MessageID(0),
+        sender: SYSTEM_USER.into(),
+        room: name,
+        content: format!("{} created the room", user.name.0),
+        timestamp: jsonwebtoken::get_current_timestamp() as f64,
+        notify: false,
+        flagged: false,
+    };
+    let message = msg.clone();
+    match db.run(move |d| deliveries(d, &message, uclone)).await {
+        Ok(deliveries) => user_db.write_to(msg, &deliveries).await,
+        Err(e) => log::error!("Failed to work out who to notify: {e}"),
+    }
     Status::Ok
 }
 
-async fn get_auth(stream: &mut DuplexStream) -> Option<UserID> {
-    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET not set");
+async fn get_auth(stream: &mut DuplexStream, keys: &Keys, db: &SqliteDB) -> Option<Jwt> {
     let Some(Ok(auth_token)) = stream.next().await else {
         log::error!("Received no auth token from client");
         return None;
     };
     let token_string = auth_token.into_text().ok()?;
-    let Some(token) = decode_jwt(&token_string, &secret) else {
-        log::error!("Failed to decode JWT token");
-        return None;
+    let token = match authenticate(&token_string, keys, db).await {
+        Ok(token) => token,
+        Err(e) => {
+            log::error!("Failed to authenticate connection: {e}");
+            return None;
+        }
     };
-    Some(token.name)
+    if !token.allows(Scope::ChatRead) {
+        log::error!(
+            "API token without chat:read tried to connect: {:?}",
+            token.name
+        );
+        return None;
+    }
+    Some(token)
 }
 
 async fn send_action(action: ServerAction, user_db: &UserDB, id: &UserID) {
@@ -211,12 +244,16 @@ async fn handle_connection(
     mut shutdown: Shutdown,
     db: SqliteDB,
     user_db: &UserDB,
+    keys: &Keys,
     log: &Log,
 ) -> ws::result::Result<()> {
-    let Some(id) = get_auth(&mut stream).await else {
+    let Some(auth) = get_auth(&mut stream, keys, &db).await else {
         let _ = stream.send(Message::Close(None)).await;
         return Ok(());
     };
+    let id = auth.name.clone();
+    // API tokens without chat:write only get to listen
+    let read_only = !auth.allows(Scope::ChatWrite);
 
     let Some((mut rx, chat)) = grab_user(user_db, &id, &mut stream, &db).await? else {
         let _ = stream.send(Message::Close(None)).await;
@@ -236,11 +273,24 @@ async fn handle_connection(
                 break;
             },
             // A message has been sent to this user
-            recv_msg = rx.recv() => if let Ok(msg) = recv_msg {
-                let _ = stream.send(Message::binary(serde_json::to_vec(&msg).unwrap())).await;
+            recv_msg = rx.recv() => match recv_msg {
+                Ok(msg) => {
+                    let _ = stream.send(Message::binary(serde_json::to_vec(&msg).unwrap())).await;
+                }
+                // The user was removed from the user db, e.g. deactivated
+                Err(RecvError::Closed) => {
+                    let _ = stream.send(Message::Close(None)).await;
+                    log::info!("Closing connection for removed user: {:?}", id);
+                    break;
+                }
+                Err(RecvError::Lagged(n)) => log::warn!("{:?} missed {n} messages", id),
             },
             // A message has been received from the user
             sent_msg = stream.next() => if let Some(Ok(msg)) = sent_msg {
+                if read_only {
+                    log::warn!("Ignoring message from read-only token: {:?}", id);
+                    continue;
+                }
                 // if handle_user_message(msg, &id, &db, user_db, log).await {
                 //     let _ = stream.send(Message::Close(None)).await;
                 //     user_db.close_user(&id).await;
@@ -275,8 +325,10 @@ async fn grab_user(
     }
     let (tx, rx) = rocket::tokio::sync::broadcast::channel(16);
     let messages = db.run(move |d| {
+      let blocked = blocked_users(d, &uida)?;
       d.prepare("SELECT m.* FROM messages m INNER JOIN chatroom_users cu ON m.chatroom_id = cu.chatroom_id WHERE cu.user_id = ?")?
         .query_map(params![uida.0], message_from_row)?
+        .map(|m| m.map(|m| ChatMessage { flagged: blocked.contains(&m.sender), ..m }))
         .collect::<Result<Vec<_>, _>>()
     }).await.map_err(|e| {
       println!("Error gathering messages: {:?}", e);
@@ -288,6 +340,7 @@ async fn grab_user(
         log::info!("Found {} messages for {:?}", messages.len(), id);
     }
     user.status = UserStatus::Active(tx);
+    user.watching_board = false;
     Ok(Some((rx, messages)))
 }
 
@@ -389,8 +442,10 @@ async fn handle_user_message(
         //     .await;
         // }
         UserAction::ListUsers => {
-            let user: Vec<_> = user_db.read().await.keys().cloned().collect();
-            send_action(ServerAction::List(user), user_db, id).await;
+            let Ok(users) = db.run(|d| active_profiles(d)).await else {
+                return false;
+            };
+            send_action(ServerAction::List(users), user_db, id).await;
         }
         UserAction::TimeIn(note) => {}
         UserAction::TimeOut(note) => {}
@@ -2,7 +2,9 @@ use rusqlite::params;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    blocks::has_blocked,
     types::{ChatMessage, ChatRoomID, UserDB, UserID},
+    users::SYSTEM_USER,
     ws_handler::UserEvent,
     SqliteDB,
 };
@@ -32,7 +34,7 @@ impl std::fmt::Display for RoomEvent {
 #[async_trait]
 impl UserEvent for RoomEgress {
     type State = (SqliteDB, UserDB);
-    async fn handle(self, user_id: &UserID, (db, user_db): &Self::State) {
+    async fn handle(self, sender: &UserID, (db, user_db): &Self::State) {
         let Self {
             room_id,
             user_id,
@@ -64,6 +66,17 @@ impl UserEvent for RoomEgress {
                 }
             }
             RoomEvent::Join => {
+                // Invites from someone the invitee blocked are dropped
+                let (invitee, inviter) = (UserID(user_id.clone()), sender.clone());
+                if invitee != inviter
+                    && db
+                        .run(move |d| has_blocked(d, &invitee, &inviter))
+                        .await
+                        .unwrap_or(true)
+                {
+                    log::info!("Dropped invite of {user_id} to {room_id} by {sender}");
+                    return;
+                }
                 add_user(
                     UserID(user_id.clone()),
                     ChatRoomID(room_id.clone()),
@@ -84,7 +97,7 @@ async fn add_user(user_id: UserID, room: ChatRoomID, db: &SqliteDB, user_db: &Us
     if let Err(e) = db
 .run(move |d| {
           let _: String = d.query_row(
-              "select c.chatroom_id from chatrooms c inner join chatroom_users cu on c.chatroom_id = cu.chatroom_id where cu.user_id != ? and c.chatroom_id = ?",
+              "select c.chatroom_id from chatrooms c inner join chatroom_users cu on c.chatroom_id = cu.chatroom_id where cu.user_id != ? and c.chatroom_id = ? and c.direct = 0",
               params![user_id.0, room.0],
               |r| r.get(0),
           )?;
@@ -95,16 +108,19 @@ async fn add_user(user_id: UserID, room: ChatRoomID, db: &SqliteDB, user_db: &Us
         return false;
     };
 
-    db.send_msg(
-        ChatMessage {
-            sender: "admin".into(),
-            content,
-            room: room_id,
-            timestamp: jsonwebtoken::get_current_timestamp() as f64,
-        },
-        user_db,
-    )
-    .await;
+    let _ = db
+        .send_msg(
+            ChatMessage {
+                sender: SYSTEM_USER.into(),
+                content,
+                room: room_id,
+                timestamp: jsonwebtoken::get_current_timestamp() as f64,
+                notify: false,
+                flagged: false,
+            },
+            user_db,
+        )
+        .await;
     true
 }
 
@@ -127,16 +143,19 @@ async fn remove_user(user_id: UserID, room: ChatRoomID, db: &SqliteDB, user_db:
         })
         .await;
     if removed {
-        db.send_msg(
-            ChatMessage {
-                sender: "admin".into(),
-                content,
-                room: room_id,
-                timestamp: jsonwebtoken::get_current_timestamp() as f64,
-            },
-            user_db,
-        )
-        .await;
+        let _ = db
+            .send_msg(
+                ChatMessage {
+                    sender: SYSTEM_USER.into(),
+                    content,
+                    room: room_id,
+                    timestamp: jsonwebtoken::get_current_timestamp() as f64,
+                    notify: false,
+                    flagged: false,
+                },
+                user_db,
+            )
+            .await;
     }
     removed
 }
@@ -1,4 +1,5 @@
 use crate::{
+    profiles::active_profiles,
     types::{ServerAction, UserDB, UserID, UserStatus},
     ws_handler::UserEvent,
     SqliteDB,
@@ -22,7 +23,13 @@ impl UserEvent for ListUsers {
             println!("User not active: {user_id}");
             return;
         };
-        let users = udb.all_users().await;
+        let users = match sqldb.run(|d| active_profiles(d)).await {
+            Ok(users) => users,
+            Err(e) => {
+                log::error!("Failed to list users: {e}");
+                return;
+            }
+        };
         if let Err(e) = tx.send(ServerAction::List(users)) {
             log::error!("Failed to send list of users: {e:?}");
         };
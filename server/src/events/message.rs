@@ -1,7 +1,7 @@
 use crate::{
-    types::{ChatMessage, UserDB, UserID},
+    types::{ChatMessage, ServerAction, UserDB, UserID},
     ws_handler::UserEvent,
-    SqliteDB,
+    SendError, SqliteDB,
 };
 use rusqlite::params;
 
@@ -9,7 +9,12 @@ use rusqlite::params;
 #[async_trait]
 impl UserEvent for ChatMessage {
     type State = (SqliteDB, UserDB);
-    async fn handle(self, user_id: &UserID, (db, user_db): &Self::State) {
-        db.send_msg(self, user_db).await;
+    async fn handle(mut self, user_id: &UserID, (db, user_db): &Self::State) {
+        // Messages always come from whoever is connected
+        self.sender = user_id.clone();
+        if let Err(SendError::Blocked) = db.send_msg(self, user_db).await {
+            let error = ServerAction::Error("This user has blocked you".into());
+            user_db.send_to(error, std::slice::from_ref(user_id)).await;
+        }
     }
 }
@@ -5,4 +5,6 @@ mod timing;
 pub use list::ListUsers;
 
 pub use egress::RoomEgress;
-pub use timing::{CheckTime, TimingAction};
+pub use timing::{
+    send_status, AllowTime, CheckTime, ClockType, TimingAction, UnwatchBoard, WatchBoard,
+};
@@ -1,22 +1,78 @@
-use rusqlite::params;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    types::{ServerAction, UserDB, UserID, UserStatus},
+    timing::{
+        approvals::{
+            notify_reviewed, oldest_submitted, require_reviewer, review, ApprovalStatus,
+            ReviewError,
+        },
+        board::{clock_changed, watch_board},
+        breaks::{end_break, is_on_break, start_break},
+        clock_in_to, clock_out, is_clocked_in,
+        projects::ProjectTag,
+        switch_project, PunchError,
+    },
+    types::{ServerAction, UserDB, UserID},
     ws_handler::UserEvent,
     SqliteDB,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum ClockType {
     TimeIn,
     TimeOut,
+    BreakStart,
+    BreakEnd,
+    /// Clocks out and back in on another project
+    Switch,
+}
+
+impl ClockType {
+    /// Punches `user`'s time, returning the time entry or break punched
+    pub fn punch(
+        self,
+        d: &mut rusqlite::Connection,
+        user: &UserID,
+        note: Option<&str>,
+        paid: bool,
+        tag: &ProjectTag,
+    ) -> Result<i64, PunchError> {
+        match self {
+            ClockType::TimeIn => clock_in_to(d, user, note, tag),
+            ClockType::TimeOut => clock_out(d, user, note),
+            ClockType::BreakStart => start_break(d, user, paid),
+            ClockType::BreakEnd => end_break(d, user),
+            ClockType::Switch => switch_project(d, user, note, tag),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TimingAction {
     action: ClockType,
     note: Option<String>,
+    /// Whether a `BreakStart` starts a paid break
+    #[serde(default)]
+    paid: bool,
+    /// Project and task of a `TimeIn` or `Switch`
+    #[serde(flatten)]
+    tag: ProjectTag,
+}
+
+/// Tells the user whether they're clocked in and on a break
+pub async fn send_status(user_id: &UserID, db: &SqliteDB, users: &UserDB) {
+    let id = user_id.clone();
+    let (timed_in, on_break) = db
+        .run(move |d| Ok::<_, rusqlite::Error>((is_clocked_in(d, &id)?, is_on_break(d, &id)?)))
+        .await
+        .inspect_err(|e| log::error!("Failed to check time: {}", e))
+        .unwrap_or((false, false));
+    for action in [
+        ServerAction::TimedIn(timed_in),
+        ServerAction::OnBreak(on_break),
+    ] {
+        users.send_to(action, std::slice::from_ref(user_id)).await;
+    }
 }
 
 pub struct CheckTime;
@@ -26,29 +82,7 @@ impl UserEvent for CheckTime {
     type State = (SqliteDB, UserDB);
     async fn handle(self, user_id: &UserID, state: &Self::State) {
         let (db, users) = state;
-        let id = user_id.clone();
-        let timed_in = db
-            .run(move |d| {
-                d.query_row(
-                    "select clocked_in from timesheets where user_id = ?",
-                    params![id.0],
-                    |r| r.get(0),
-                )
-                .inspect_err(|e| log::error!("Failed to check time: {}", e))
-                .unwrap_or(false)
-            })
-            .await;
-        let Some(user) = users.get_user(user_id).await else {
-            log::error!("User not found: {user_id}");
-            return;
-        };
-        let UserStatus::Active(tx) = user.status else {
-            log::error!("User not active: {user_id}");
-            return;
-        };
-        if let Err(e) = tx.send(ServerAction::TimedIn(timed_in)) {
-            log::error!("Failed to send time status: {e:?}");
-        };
+        send_status(user_id, db, users).await;
     }
 }
 
@@ -58,53 +92,109 @@ impl UserEvent for TimingAction {
     async fn handle(self, user_id: &UserID, state: &Self::State) {
         let (db, users) = state;
         let id = user_id.clone();
-        match self.action {
-            ClockType::TimeIn => {
-                log::info!("{} Timed in: {:?}", id, self.note);
-                if let Err(e) = db
-                    .run(move |d| {
-                        let statement = format!(
-                            "BEGIN TRANSACTION; \
-                INSERT INTO time_entries (timesheet_id, start_time, start_note) \
-                SELECT timesheet_id, CURRENT_TIMESTAMP, '{}' \
-                FROM timesheets WHERE user_id = '{id}' AND clocked_in = 0;
-                UPDATE timesheets SET clocked_in = 1 WHERE user_id = '{id}' AND clocked_in = 0;
-                COMMIT;",
-                            self.note.as_deref().unwrap_or("NULL")
-                        );
-                        d.execute_batch(&statement)
-                    })
-                    .await
-                {
-                    log::error!("Failed to insert time entry: {}", e);
-                };
+        let TimingAction {
+            action,
+            note,
+            paid,
+            tag,
+        } = self;
+        let res = db
+            .run(move |d| action.punch(d, &id, note.as_deref(), paid, &tag))
+            .await;
+        match res {
+            Ok(id) => {
+                log::info!("{user_id} punched time entry or break {id}");
+                clock_changed(db, users, user_id).await;
+            }
+            Err(e) => {
+                if let PunchError::Db(e) = &e {
+                    log::error!("Failed to punch time for {user_id}: {e}");
+                }
+                users
+                    .send_to(
+                        ServerAction::Error(e.to_string()),
+                        std::slice::from_ref(user_id),
+                    )
+                    .await;
             }
-            ClockType::TimeOut => {
-                log::info!("{} Timed out: {:?}", id, self.note);
-                if let Err(e) = db
-                    .run(move |d| {
-                        let statement = format!(
-                            "BEGIN TRANSACTION; \
-                UPDATE time_entries \
-                SET end_time = CURRENT_TIMESTAMP, end_note = '{}' \
-                WHERE time_entry_id = (
-                  SELECT current_id
-                  FROM timesheets
-                  WHERE user_id = '{id}' AND clocked_in = 1
-                );
-                UPDATE timesheets
-                SET clocked_in = 0, current_id = NULL
-                WHERE user_id = '{id}' AND clocked_in = 1;
-                COMMIT;",
-                            self.note.as_deref().unwrap_or("NULL")
-                        );
-                        d.execute_batch(&statement)
-                    })
-                    .await
-                {
-                    log::error!("Failed to insert time entry: {}", e);
+        }
+        // Confirms the punch, or corrects the client if it was out of sync
+        send_status(user_id, db, users).await;
+    }
+}
+
+/// Sent by managers and admins to get live updates of who's on the clock
+pub struct WatchBoard;
+
+#[async_trait]
+impl UserEvent for WatchBoard {
+    type State = (SqliteDB, UserDB);
+    async fn handle(self, user_id: &UserID, state: &Self::State) {
+        let (db, users) = state;
+        if watch_board(db, users, user_id).await.is_err() {
+            let error = ServerAction::Error("You can't watch who's on the clock".into());
+            users.send_to(error, std::slice::from_ref(user_id)).await;
+        }
+    }
+}
+
+pub struct UnwatchBoard;
+
+#[async_trait]
+impl UserEvent for UnwatchBoard {
+    type State = (SqliteDB, UserDB);
+    async fn handle(self, user_id: &UserID, state: &Self::State) {
+        let (_, users) = state;
+        if let Some(user) = users.write().await.get_mut(user_id) {
+            user.watching_board = false;
+        }
+    }
+}
+
+/// Sent by a manager to approve the oldest timesheet `.0` submitted
+#[derive(Serialize, Deserialize)]
+pub struct AllowTime(UserID);
+
+#[async_trait]
+impl UserEvent for AllowTime {
+    type State = (SqliteDB, UserDB);
+    async fn handle(self, user_id: &UserID, state: &Self::State) {
+        let (db, users) = state;
+        let Self(employee) = self;
+        if require_reviewer(user_id, &employee, db).await.is_err() {
+            let error = ServerAction::Error(format!("You can't review timesheets of {employee}"));
+            users.send_to(error, std::slice::from_ref(user_id)).await;
+            return;
+        }
+        let reviewer = user_id.clone();
+        let res = db
+            .run(move |d| {
+                let Some(period) = oldest_submitted(d, &employee)? else {
+                    return Err(ReviewError::NotSubmitted);
                 };
+                review(
+                    d,
+                    &reviewer,
+                    &employee,
+                    period,
+                    ApprovalStatus::Approved,
+                    None,
+                )
+            })
+            .await;
+        match res {
+            Ok(approval) => notify_reviewed(db, users, &approval).await,
+            Err(e) => {
+                if let ReviewError::Db(e) = &e {
+                    log::error!("Failed to approve timesheet: {e}");
+                }
+                users
+                    .send_to(
+                        ServerAction::Error(e.to_string()),
+                        std::slice::from_ref(user_id),
+                    )
+                    .await;
             }
-        };
+        }
     }
 }
@@ -1,12 +1,16 @@
 mod auth;
+mod blocks;
 mod chat;
 mod cors;
 mod events;
 mod logger;
+mod notifications;
+mod profiles;
 #[cfg(test)]
 mod test;
 mod timing;
 mod types;
+mod users;
 mod ws_handler;
 #[macro_use]
 extern crate rocket;
@@ -14,6 +18,7 @@ const FILE_PATH: &str = "./public";
 type LockedSet<T> = RwLock<HashSet<T>>;
 use std::collections::HashSet;
 
+use blocks::blocked_in_direct_room;
 use logger::Log;
 // Map Users to their sender which is sending to their active websocket connection
 // and a Vec of messages that have been sent to them while they were offline
@@ -35,37 +40,133 @@ use types::{ChatMessage, ChatRoomID, User, UserDB, UserID, UserStatus};
 #[database("sqlite_db")]
 pub struct SqliteDB(SqliteConnection);
 
+/// Why a message wasn't sent
+#[derive(Debug)]
+pub enum SendError {
+    /// The other person in a direct message has blocked the sender
+    Blocked,
+    Db(rusqlite::Error),
+}
+
 impl SqliteDB {
-    async fn send_msg(&self, msg: ChatMessage, user_db: &UserDB) {
-        let chatroom_id = msg.room.clone();
-        let users: Vec<_> = match self
+    async fn send_msg(&self, msg: ChatMessage, user_db: &UserDB) -> Result<(), SendError> {
+        let (chatroom_id, message) = (msg.room.clone(), msg.clone());
+        let deliveries = match self
             .run(move |d| {
-                d.prepare("select user_id from chatroom_users where chatroom_id = ?")?
+                let users = d
+                    .prepare(
+                        "select cu.user_id from chatroom_users cu \
+                    inner join users u on u.user_id = cu.user_id \
+                    where cu.chatroom_id = ? and u.active = 1",
+                    )?
                     .query_map(params![chatroom_id.0], |r| {
                         r.get(0).map(crate::types::UserID)
                     })?
-                    .collect::<Result<Vec<_>, _>>()
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Direct messages get refused outright when blocked
+                if users.contains(&message.sender)
+                    && blocked_in_direct_room(d, &message.room, &message.sender)?
+                {
+                    return Ok(None);
+                }
+                notifications::deliveries(d, &message, users).map(Some)
             })
             .await
         {
-            Ok(users) => users,
+            Ok(Some(deliveries)) => deliveries,
+            Ok(None) => return Err(SendError::Blocked),
             Err(e) => {
                 log::error!("Failed to get users from chatroom: {}", e);
-                return;
+                return Err(SendError::Db(e));
             }
         };
-        user_db.write_to(msg.clone(), &users).await;
-        if let Err(e) = self.run(move |d| {
-          d.execute(
-              "INSERT INTO messages (user_id, chatroom_id, message, created_at) VALUES (?, ?, ?, ?)",
-              params![msg.sender.0, msg.room.0, msg.content, msg.timestamp],
-          )
-      })
-      .await
-  {
-      log::error!("Failed to insert message into database: {}", e);
-  };
+        user_db.write_to(msg.clone(), &deliveries).await;
+        self.run(move |d| {
+            d.execute(
+                "INSERT INTO messages (user_id, chatroom_id, message, created_at) \
+                VALUES (?, ?, ?, ?)",
+                params![msg.sender.0, msg.room.0, msg.content, msg.timestamp],
+            )
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to insert message into database: {}", e);
+            SendError::Db(e)
+        })?;
+        Ok(())
+    }
+}
+
+/// Columns added to tables after they were first created. `CREATE TABLE IF NOT EXISTS`
+/// leaves existing databases alone, so these get added to any existing table before
+/// running the migrations.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("users", "active", "BOOLEAN NOT NULL DEFAULT 1"),
+    ("users", "is_admin", "BOOLEAN NOT NULL DEFAULT 0"),
+    ("users", "email", "TEXT"),
+    ("users", "service_account", "BOOLEAN NOT NULL DEFAULT 0"),
+    ("users", "department", "TEXT"),
+    ("chatrooms", "direct", "BOOLEAN NOT NULL DEFAULT 0"),
+    ("time_entries", "reminded_at", "DATETIME"),
+    ("time_entries", "flagged", "BOOLEAN NOT NULL DEFAULT 0"),
+    ("time_entries", "project_id", "INTEGER"),
+    ("time_entries", "task", "TEXT"),
+    ("leave_requests", "cancelled_by", "TEXT"),
+    ("leave_requests", "cancelled_at", "DATETIME"),
+];
+
+fn upgrade_schema(d: &mut SqliteConnection) -> rusqlite::Result<()> {
+    for (table, column, decl) in ADDED_COLUMNS {
+        let columns = d
+            .prepare(&format!("SELECT name FROM pragma_table_info('{table}')"))?
+            .query_map([], |r| r.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // Missing tables get created with every column by the migrations
+        if !columns.is_empty() && !columns.iter().any(|c| c == column) {
+            d.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))?;
+        }
+    }
+    merge_timesheets(d)
+}
+
+/// Folds extra timesheets of a user into their first one, so the migrations can make
+/// `timesheets.user_id` unique
+fn merge_timesheets(d: &mut SqliteConnection) -> rusqlite::Result<()> {
+    let exists: bool = d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'timesheets')",
+        [],
+        |r| r.get(0),
+    )?;
+    let duplicates = exists
+        && d.query_row(
+            "SELECT EXISTS (SELECT 1 FROM timesheets GROUP BY user_id HAVING COUNT(*) > 1)",
+            [],
+            |r| r.get(0),
+        )?;
+    if !duplicates {
+        return Ok(());
     }
+    let tx = d.transaction()?;
+    // The first timesheet takes over whatever entry its user is clocked in to
+    tx.execute_batch(
+        "UPDATE timesheets SET clocked_in = 1, current_id = (
+            SELECT o.current_id FROM timesheets o
+            WHERE o.user_id = timesheets.user_id AND o.clocked_in = 1 AND o.current_id IS NOT NULL
+            ORDER BY o.timesheet_id DESC LIMIT 1)
+        WHERE timesheet_id IN (SELECT MIN(timesheet_id) FROM timesheets GROUP BY user_id)
+          AND EXISTS (SELECT 1 FROM timesheets o WHERE o.user_id = timesheets.user_id
+            AND o.clocked_in = 1 AND o.current_id IS NOT NULL);
+        UPDATE time_entries SET timesheet_id = (
+            SELECT MIN(f.timesheet_id) FROM timesheets t
+            INNER JOIN timesheets f ON f.user_id = t.user_id
+            WHERE t.timesheet_id = time_entries.timesheet_id)
+        WHERE timesheet_id IN (SELECT timesheet_id FROM timesheets);
+        DELETE FROM timesheets
+        WHERE timesheet_id NOT IN (SELECT MIN(timesheet_id) FROM timesheets GROUP BY user_id);",
+    )?;
+    tx.commit()?;
+    log::warn!("Merged users' duplicate timesheets");
+    Ok(())
 }
 
 use tokio::runtime::{Handle, Runtime};
@@ -91,7 +192,8 @@ where
     }
 }
 
-#[get("/<file..>")]
+// Ranked last so it doesn't shadow dynamic routes mounted elsewhere
+#[get("/<file..>", rank = 10)]
 async fn file_server(file: PathBuf) -> std::io::Result<NamedFile> {
     let file_str = file.to_str();
     let path = if file_str.map_or(false, str::is_empty) {
@@ -136,8 +238,30 @@ async fn rocket() -> _ {
     tokio::spawn(periodic_flush(log.clone()));
     // tokio::spawn(timing_flush(time_db.clone()));
     let udb = UserDB::default();
-    rocket::build()
+    let rocket = rocket::build();
+    let auth_config: auth::AuthConfig = match rocket.figment().extract_inner("auth") {
+        Ok(config) => config,
+        Err(e) if e.missing() => Default::default(),
+        Err(e) => panic!("Invalid auth config: {e}"),
+    };
+    let time_config: timing::TimeConfig = match rocket.figment().extract_inner("time") {
+        Ok(config) => config,
+        Err(e) if e.missing() => Default::default(),
+        Err(e) => panic!("Invalid time config: {e}"),
+    };
+    let keys = match auth_config.keys() {
+        Ok(keys) => keys,
+        Err(e) => panic!("Failed to load signing keys: {e}"),
+    };
+    let watch_config = time_config.clone();
+    rocket
         // .manage(server_state)
+        .manage(auth_config.backend())
+        .manage(auth::oidc::Oidc::default())
+        .manage(keys)
+        .manage(auth_config)
+        .manage(time_config)
+        .manage(timing::kiosk::KioskLimits::default())
         .manage(log)
         .manage(udb.clone())
         .attach(cors::Cors)
@@ -147,12 +271,14 @@ async fn rocket() -> _ {
                 let Some(db) = SqliteDB::get_one(rocket).await else {
                     panic!("Failed to get database");
                 };
+                db.run(upgrade_schema).await.unwrap();
                 db.run(move |d| d.execute_batch(include_str!("../migrations/up.sql")))
                     .await
                     .unwrap();
+                db.run(|d| auth::unlock_seeded_admin(d)).await.unwrap();
                 let users: Vec<(UserID, User)> = db
                     .run(move |d| {
-                        d.prepare("SELECT user_id, password FROM users")
+                        d.prepare("SELECT user_id, password FROM users WHERE active = 1")
                             .unwrap()
                             .query_map([], |row| {
                                 let id: UserID = row.get::<_, String>(0).unwrap().into();
@@ -163,6 +289,7 @@ async fn rocket() -> _ {
                                         name: id,
                                         password: pass,
                                         status: UserStatus::Inactive,
+                                        watching_board: false,
                                     },
                                 ))
                             })
@@ -171,8 +298,15 @@ async fn rocket() -> _ {
                             .collect::<Vec<_>>()
                     })
                     .await;
-                let mut udb = udb.write().await;
-                udb.extend(users.into_iter());
+                let mut users_db = udb.write().await;
+                users_db.extend(users.into_iter());
+                drop(users_db);
+                match SqliteDB::get_one(rocket).await {
+                    Some(db) => {
+                        tokio::spawn(timing::reminders::watch_punches(db, udb, watch_config));
+                    }
+                    None => log::error!("Failed to get a database connection for time reminders"),
+                }
             })
         }))
         // .attach(AdHoc::on_shutdown("Save Dbs", |rocket| {
@@ -193,9 +327,96 @@ async fn rocket() -> _ {
         )
         .mount(
             "/auth",
-            routes![auth::create_user, auth::login_user, auth::check_user],
+            routes![
+                auth::create_user,
+                auth::login_user,
+                auth::check_user,
+                auth::invites::create_code,
+                auth::invites::list_codes,
+                auth::invites::delete_code,
+                auth::tokens::create_token,
+                auth::tokens::list_tokens,
+                auth::tokens::revoke_token,
+                auth::oidc::oidc_login,
+                auth::oidc::oidc_callback,
+                auth::keys::public_keys
+            ],
+        )
+        .mount(
+            "/users",
+            routes![
+                users::deactivate_user,
+                users::reactivate_user,
+                users::delete_user,
+                users::export_user,
+                users::create_service_account,
+                users::set_department,
+                users::set_admin,
+                notifications::get_settings,
+                notifications::put_settings,
+                blocks::list_blocks,
+                blocks::block_user,
+                blocks::unblock_user,
+                timing::approvals::list_managers,
+                timing::approvals::add_manager,
+                timing::approvals::remove_manager,
+                profiles::get_user_profile,
+                profiles::update_profile,
+                profiles::upload_avatar,
+                profiles::get_avatar,
+                timing::kiosk::put_pin,
+                timing::kiosk::delete_pin
+            ],
+        )
+        .mount(
+            "/",
+            routes![
+                file_server,
+                report,
+                timing::get_time,
+                timing::report::time_report,
+                timing::export::export_time,
+                timing::approvals::list_approvals,
+                timing::approvals::submit_timesheet,
+                timing::approvals::review_timesheet,
+                timing::corrections::list_corrections,
+                timing::corrections::request_correction,
+                timing::corrections::review_time_correction,
+                timing::corrections::time_audit,
+                timing::projects::list_projects,
+                timing::projects::create_project,
+                timing::projects::update_project,
+                timing::leave::list_leave_types,
+                timing::leave::put_leave_type,
+                timing::leave::list_holidays,
+                timing::leave::put_holiday,
+                timing::leave::delete_holiday,
+                timing::leave::leave_balances,
+                timing::leave::set_leave_balance,
+                timing::leave::list_leave,
+                timing::leave::request_time_off,
+                timing::leave::review_time_off,
+                timing::leave::cancel_time_off,
+                timing::schedule::list_shifts,
+                timing::schedule::shifts_ics,
+                timing::schedule::create_shift,
+                timing::schedule::delete_shift,
+                timing::schedule::publish_shifts,
+                timing::feeds::create_feed,
+                timing::feeds::delete_feed,
+                timing::feeds::calendar_feed,
+                timing::board::time_board,
+                timing::kiosk::create_kiosk,
+                timing::kiosk::list_kiosks,
+                timing::kiosk::revoke_kiosk,
+                timing::kiosk::list_kiosk_punches,
+                timing::kiosk::kiosk_punch,
+                timing::periods::list_closed_periods,
+                timing::periods::pay_period_log,
+                timing::periods::close_pay_period,
+                timing::periods::reopen_pay_period
+            ],
         )
-        .mount("/", routes![file_server, report, timing::get_time])
 
     // .register("/", catchers![echo_catcher])
 }
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use rocket::{http::Status, serde::json::Json};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{require_self_or_admin, tokens::Scope, Jwt},
+    blocks::blocked_by,
+    profiles::user_timezone,
+    types::{ChatMessage, ChatRoomID, UserID},
+    SqliteDB,
+};
+
+/// How much a user wants to hear about a room
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    /// Only when mentioned with `@name` or a keyword alert matches
+    Mentions,
+    Mute,
+}
+
+impl NotifyLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            NotifyLevel::All => "all",
+            NotifyLevel::Mentions => "mentions",
+            NotifyLevel::Mute => "mute",
+        }
+    }
+
+    fn parse(level: &str) -> Self {
+        match level {
+            "mentions" => NotifyLevel::Mentions,
+            "mute" => NotifyLevel::Mute,
+            _ => NotifyLevel::All,
+        }
+    }
+}
+
+/// A recurring do-not-disturb window in the user's own timezone
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct DndWindow {
+    /// Days the window starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    /// Can be before `start` for windows that run past midnight
+    pub end: NaiveTime,
+}
+
+impl DndWindow {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn contains(&self, local: NaiveDateTime) -> bool {
+        let (day, time) = (local.weekday(), local.time());
+        if self.start <= self.end {
+            self.starts_on(day) && self.start <= time && time < self.end
+        } else {
+            (self.starts_on(day) && time >= self.start)
+                || (self.starts_on(day.pred()) && time < self.end)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct NotificationSettings {
+    #[serde(default)]
+    pub dnd: Vec<DndWindow>,
+    /// Rooms that aren't listed notify for every message
+    #[serde(default)]
+    pub rooms: HashMap<ChatRoomID, NotifyLevel>,
+    /// Words that notify even in mentions-only rooms, matched case-insensitively
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl NotificationSettings {
+    /// Whether `msg` should make a sound or pop up for `user` at `now`
+    pub fn should_notify(
+        &self,
+        user: &UserID,
+        tz: Tz,
+        msg: &ChatMessage,
+        now: DateTime<Utc>,
+    ) -> bool {
+        if msg.sender == *user {
+            return false;
+        }
+        let level = self.rooms.get(&msg.room).copied().unwrap_or_default();
+        let local = now.with_timezone(&tz).naive_local();
+        if level == NotifyLevel::Mute || self.dnd.iter().any(|w| w.contains(local)) {
+            return false;
+        }
+        let content = msg.content.to_lowercase();
+        let mentioned = content.contains(&format!("@{}", user.0.to_lowercase()));
+        let keyword = self
+            .keywords
+            .iter()
+            .any(|k| !k.is_empty() && content.contains(&k.to_lowercase()));
+        level == NotifyLevel::All || mentioned || keyword
+    }
+}
+
+pub fn load_settings(
+    d: &rusqlite::Connection,
+    id: &UserID,
+) -> rusqlite::Result<NotificationSettings> {
+    let dnd = d
+        .prepare("SELECT days, start_time, end_time FROM dnd_windows WHERE user_id = ?")?
+        .query_map(params![id.0], |r| {
+            let days: String = r.get(0)?;
+            Ok(DndWindow {
+                days: serde_json::from_str(&days).unwrap_or_default(),
+                start: r.get(1)?,
+                end: r.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    let rooms = d
+        .prepare("SELECT chatroom_id, level FROM room_notifications WHERE user_id = ?")?
+        .query_map(params![id.0], |r| {
+            Ok((r.get(0)?, NotifyLevel::parse(&r.get::<_, String>(1)?)))
+        })?
+        .collect::<Result<_, _>>()?;
+    let keywords = d
+        .prepare("SELECT keyword FROM keyword_alerts WHERE user_id = ?")?
+        .query_map(params![id.0], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(NotificationSettings {
+        dnd,
+        rooms,
+        keywords,
+    })
+}
+
+fn save_settings(
+    d: &mut rusqlite::Connection,
+    id: &UserID,
+    settings: &NotificationSettings,
+) -> rusqlite::Result<()> {
+    let tx = d.transaction()?;
+    for table in ["dnd_windows", "room_notifications", "keyword_alerts"] {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE user_id = ?"),
+            params![id.0],
+        )?;
+    }
+    for window in &settings.dnd {
+        let days = serde_json::to_string(&window.days).unwrap_or_else(|_| "[]".into());
+        tx.execute(
+            "INSERT INTO dnd_windows (user_id, days, start_time, end_time) VALUES (?, ?, ?, ?)",
+            params![id.0, days, window.start, window.end],
+        )?;
+    }
+    for (room, level) in &settings.rooms {
+        tx.execute(
+            "INSERT INTO room_notifications (user_id, chatroom_id, level) VALUES (?, ?, ?)",
+            params![id.0, room.0, level.as_str()],
+        )?;
+    }
+    for keyword in &settings.keywords {
+        tx.execute(
+            "INSERT OR IGNORE INTO keyword_alerts (user_id, keyword) VALUES (?, ?)",
+            params![id.0, keyword],
+        )?;
+    }
+    tx.commit()
+}
+
+/// A message on its way to one recipient
+pub struct Delivery {
+    pub user: UserID,
+    pub notify: bool,
+    /// The recipient has blocked the sender
+    pub flagged: bool,
+}
+
+/// Works out, for each of `users`, whether `msg` should notify them and whether they blocked
+/// its sender
+pub fn deliveries(
+    d: &rusqlite::Connection,
+    msg: &ChatMessage,
+    users: Vec<UserID>,
+) -> rusqlite::Result<Vec<Delivery>> {
+    let now = Utc::now();
+    let blockers = blocked_by(d, &msg.sender)?;
+    users
+        .into_iter()
+        .map(|user| {
+            let settings = load_settings(d, &user)?;
+            let tz = user_timezone(d, &user)?.unwrap_or(Tz::UTC);
+            let flagged = blockers.contains(&user);
+            Ok(Delivery {
+                notify: !flagged && settings.should_notify(&user, tz, msg, now),
+                flagged,
+                user,
+            })
+        })
+        .collect()
+}
+
+#[get("/<id>/notifications")]
+pub async fn get_settings(
+    id: UserID,
+    user: Jwt,
+    db: SqliteDB,
+) -> Result<Json<NotificationSettings>, Status> {
+    user.require(Scope::ChatRead)?;
+    require_self_or_admin(&user, &id, &db).await?;
+    db.run(move |d| load_settings(d, &id))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!("Failed to load notification settings: {e}");
+            Status::InternalServerError
+        })
+}
+
+/// Replaces all of a user's notification settings
+#[put("/<id>/notifications", data = "<settings>")]
+pub async fn put_settings(
+    id: UserID,
+    settings: Json<NotificationSettings>,
+    user: Jwt,
+    db: SqliteDB,
+) -> Result<Json<NotificationSettings>, Status> {
+    user.require(Scope::ChatWrite)?;
+    require_self_or_admin(&user, &id, &db).await?;
+    let settings = settings.into_inner();
+    db.run(move |d| {
+        save_settings(d, &id, &settings)?;
+        load_settings(d, &id)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to save notification settings: {e}");
+        Status::InternalServerError
+    })
+}
@@ -0,0 +1,368 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rocket::{
+    data::{Data, ToByteUnit},
+    http::{ContentType, Status},
+    serde::json::Json,
+    State,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    auth::{require_self_or_admin, tokens::Scope, Jwt},
+    types::{ServerAction, UserDB, UserID},
+    SqliteDB,
+};
+
+/// Largest avatar accepted, in kibibytes
+const AVATAR_LIMIT_KIB: u64 = 512;
+
+/// Custom status text set by a user, hidden once it expires
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct CustomStatus {
+    pub text: Option<String>,
+    pub emoji: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Profile {
+    pub user: UserID,
+    pub display_name: Option<String>,
+    pub title: Option<String>,
+    pub status: Option<CustomStatus>,
+    /// IANA name, e.g. `Europe/Berlin`
+    pub timezone: Option<String>,
+    /// Path the avatar is served from, if one was uploaded
+    pub avatar: Option<String>,
+    /// Set by admins
+    pub department: Option<String>,
+    pub clocked_in: bool,
+    pub on_break: bool,
+}
+
+/// The parts of a profile shown next to a user's name, sent in user lists and profile updates
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ProfileSummary {
+    pub user: UserID,
+    pub display_name: Option<String>,
+    pub status: Option<CustomStatus>,
+    pub avatar: Option<String>,
+    /// Whether they're on the clock, shown as presence in chat
+    #[serde(default)]
+    pub clocked_in: bool,
+    #[serde(default)]
+    pub on_break: bool,
+}
+
+impl From<Profile> for ProfileSummary {
+    fn from(p: Profile) -> Self {
+        ProfileSummary {
+            user: p.user,
+            display_name: p.display_name,
+            status: p.status,
+            avatar: p.avatar,
+            clocked_in: p.clocked_in,
+            on_break: p.on_break,
+        }
+    }
+}
+
+const SELECT_PROFILES: &str = "SELECT u.user_id, p.display_name, p.title, p.status_text, \
+    p.status_emoji, p.status_expires_at, p.timezone, p.avatar IS NOT NULL, \
+    COALESCE(ts.clocked_in, 0), EXISTS (SELECT 1 FROM time_breaks b \
+      WHERE b.time_entry_id = ts.current_id AND ts.clocked_in = 1 AND b.end_time IS NULL), \
+    u.department FROM users u LEFT JOIN user_profiles p ON p.user_id = u.user_id \
+    LEFT JOIN timesheets ts ON ts.user_id = u.user_id";
+
+fn profile_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Profile> {
+    let user: UserID = row.get(0)?;
+    let status = CustomStatus {
+        text: row.get(3)?,
+        emoji: row.get(4)?,
+        expires_at: row.get(5)?,
+    };
+    let expired = status.expires_at.is_some_and(|exp| exp < Utc::now());
+    let has_status = status.text.is_some() || status.emoji.is_some();
+    let has_avatar: Option<bool> = row.get(7)?;
+    Ok(Profile {
+        avatar: has_avatar
+            .unwrap_or(false)
+            .then(|| format!("/users/{user}/avatar")),
+        user,
+        display_name: row.get(1)?,
+        title: row.get(2)?,
+        status: (has_status && !expired).then_some(status),
+        timezone: row.get(6)?,
+        clocked_in: row.get(8)?,
+        on_break: row.get(9)?,
+        department: row.get(10)?,
+    })
+}
+
+/// Profile of a single user, empty if they never set one up
+pub fn get_profile(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<Option<Profile>> {
+    d.query_row(
+        &format!("{SELECT_PROFILES} WHERE u.user_id = ?"),
+        params![id.0],
+        profile_from_row,
+    )
+    .optional()
+}
+
+/// Profiles of every active user, for user lists
+pub fn active_profiles(d: &rusqlite::Connection) -> rusqlite::Result<Vec<ProfileSummary>> {
+    d.prepare(&format!(
+        "{SELECT_PROFILES} WHERE u.active = 1 ORDER BY u.user_id"
+    ))?
+    .query_map([], profile_from_row)?
+    .map(|p| p.map(ProfileSummary::from))
+    .collect()
+}
+
+/// Timezone a user set in their profile
+pub fn user_timezone(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<Option<Tz>> {
+    let tz: Option<String> = d
+        .query_row(
+            "SELECT timezone FROM user_profiles WHERE user_id = ?",
+            params![id.0],
+            |r| r.get(0),
+        )
+        .optional()?
+        .flatten();
+    Ok(tz.and_then(|tz| tz.parse().ok()))
+}
+
+/// Tells everyone sharing a room with `id`, and `id` themself, about a changed profile
+pub(crate) async fn broadcast_profile(db: &SqliteDB, user_db: &UserDB, summary: ProfileSummary) {
+    let id = summary.user.clone();
+    let users = db
+        .run(move |d| {
+            d.prepare(
+                "SELECT DISTINCT other.user_id FROM chatroom_users own \
+                INNER JOIN chatroom_users other ON other.chatroom_id = own.chatroom_id \
+                WHERE own.user_id = ?1 \
+                UNION SELECT ?1",
+            )?
+            .query_map(params![id.0], |r| r.get(0))?
+            .collect::<Result<Vec<UserID>, _>>()
+        })
+        .await;
+    match users {
+        Ok(users) => {
+            user_db
+                .send_to(ServerAction::Profile(summary), &users)
+                .await
+        }
+        Err(e) => log::error!("Failed to find who to send a profile update to: {e}"),
+    }
+}
+
+/// Lets a field be left out to keep it, or set to `null` to clear it
+pub(crate) fn nullable<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    d: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(d).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(default, deserialize_with = "nullable")]
+    display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    status: Option<Option<CustomStatus>>,
+    #[serde(default, deserialize_with = "nullable")]
+    timezone: Option<Option<String>>,
+}
+
+/// Only the user themself or an admin get to change a profile
+async fn check_can_edit(user: &Jwt, id: &UserID, db: &SqliteDB) -> Result<(), Status> {
+    user.require(Scope::ChatWrite)?;
+    require_self_or_admin(user, id, db).await
+}
+
+#[get("/<id>")]
+pub async fn get_user_profile(
+    id: UserID,
+    user: Jwt,
+    db: SqliteDB,
+) -> Result<Json<Profile>, Status> {
+    user.require(Scope::ChatRead)?;
+    db.run(move |d| get_profile(d, &id))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get profile: {e}");
+            Status::InternalServerError
+        })?
+        .map(Json)
+        .ok_or(Status::NotFound)
+}
+
+#[patch("/<id>", data = "<update>")]
+pub async fn update_profile(
+    id: UserID,
+    update: Json<ProfileUpdate>,
+    user: Jwt,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Json<Profile>, Status> {
+    check_can_edit(&user, &id, &db).await?;
+    let ProfileUpdate {
+        display_name,
+        title,
+        status,
+        timezone,
+    } = update.into_inner();
+    if let Some(Some(tz)) = &timezone {
+        if tz.parse::<Tz>().is_err() {
+            return Err(Status::UnprocessableEntity);
+        }
+    }
+    let uid = id.clone();
+    let profile = db
+        .run(move |d| {
+            let tx = d.transaction()?;
+            if get_profile(&tx, &uid)?.is_none() {
+                return Ok(None);
+            }
+            tx.execute(
+                "INSERT OR IGNORE INTO user_profiles (user_id) VALUES (?)",
+                params![uid.0],
+            )?;
+            let set = |column: &str, value: &dyn rusqlite::ToSql| {
+                tx.execute(
+                    &format!("UPDATE user_profiles SET {column} = ? WHERE user_id = ?"),
+                    params![value, uid.0],
+                )
+            };
+            if let Some(display_name) = display_name {
+                set("display_name", &display_name)?;
+            }
+            if let Some(title) = title {
+                set("title", &title)?;
+            }
+            if let Some(timezone) = timezone {
+                set("timezone", &timezone)?;
+            }
+            if let Some(status) = status {
+                let status = status.as_ref();
+                set("status_text", &status.and_then(|s| s.text.clone()))?;
+                set("status_emoji", &status.and_then(|s| s.emoji.clone()))?;
+                set("status_expires_at", &status.and_then(|s| s.expires_at))?;
+            }
+            set("updated_at", &Utc::now())?;
+            let profile = get_profile(&tx, &uid)?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(profile)
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to update profile of {id}: {e}");
+            Status::InternalServerError
+        })?
+        .ok_or(Status::NotFound)?;
+    broadcast_profile(&db, user_db, profile.clone().into()).await;
+    Ok(Json(profile))
+}
+
+const AVATAR_TYPES: [ContentType; 4] = [
+    ContentType::PNG,
+    ContentType::JPEG,
+    ContentType::GIF,
+    ContentType::WEBP,
+];
+
+/// Checks an avatar fits the size limit and really is an image of the type it was sent as
+pub(crate) fn check_avatar(content_type: &ContentType, image: &[u8]) -> Result<(), Status> {
+    if image.len() as u64 > AVATAR_LIMIT_KIB * 1024 {
+        return Err(Status::PayloadTooLarge);
+    }
+    let matches = if *content_type == ContentType::PNG {
+        image.starts_with(b"\x89PNG\r\n\x1a\n")
+    } else if *content_type == ContentType::JPEG {
+        image.starts_with(b"\xff\xd8\xff")
+    } else if *content_type == ContentType::GIF {
+        image.starts_with(b"GIF87a") || image.starts_with(b"GIF89a")
+    } else if *content_type == ContentType::WEBP {
+        image.starts_with(b"RIFF") && image.get(8..12) == Some(b"WEBP")
+    } else {
+        false
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(Status::UnsupportedMediaType)
+    }
+}
+
+/// Uploads a new avatar, sent as the raw image with its content type
+#[put("/<id>/avatar", data = "<image>")]
+pub async fn upload_avatar(
+    id: UserID,
+    content_type: &ContentType,
+    image: Data<'_>,
+    user: Jwt,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Json<Profile>, Status> {
+    check_can_edit(&user, &id, &db).await?;
+    if !AVATAR_TYPES.contains(content_type) {
+        return Err(Status::UnsupportedMediaType);
+    }
+    let image = image
+        .open(AVATAR_LIMIT_KIB.kibibytes())
+        .into_bytes()
+        .await
+        .map_err(|_| Status::BadRequest)?;
+    if !image.is_complete() {
+        return Err(Status::PayloadTooLarge);
+    }
+    check_avatar(content_type, &image)?;
+    let (uid, image, mime) = (id.clone(), image.into_inner(), content_type.to_string());
+    let profile = db
+        .run(move |d| {
+            let tx = d.transaction()?;
+            if get_profile(&tx, &uid)?.is_none() {
+                return Ok(None);
+            }
+            tx.execute(
+                "INSERT INTO user_profiles (user_id, avatar, avatar_type, updated_at) \
+                VALUES (?1, ?2, ?3, ?4) ON CONFLICT (user_id) DO UPDATE \
+                SET avatar = ?2, avatar_type = ?3, updated_at = ?4",
+                params![uid.0, image, mime, Utc::now()],
+            )?;
+            let profile = get_profile(&tx, &uid)?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(profile)
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to store avatar of {id}: {e}");
+            Status::InternalServerError
+        })?
+        .ok_or(Status::NotFound)?;
+    broadcast_profile(&db, user_db, profile.clone().into()).await;
+    Ok(Json(profile))
+}
+
+/// Public, since an `<img src>` can't send an `Authorization` header
+#[get("/<id>/avatar")]
+pub async fn get_avatar(id: UserID, db: SqliteDB) -> Option<(ContentType, Vec<u8>)> {
+    let (mime, image): (String, Vec<u8>) = db
+        .run(move |d| {
+            d.query_row(
+                "SELECT avatar_type, avatar FROM user_profiles \
+                WHERE user_id = ? AND avatar IS NOT NULL",
+                params![id.0],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()
+        })
+        .await
+        .inspect_err(|e| log::error!("Failed to get avatar: {e}"))
+        .ok()??;
+    Some((ContentType::parse_flexible(&mime)?, image))
+}
@@ -1,6 +1,227 @@
 use std::time::Duration;
 
-use crate::{run_or_block, timing::TimeState, types::UserID};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc, Weekday};
+
+use crate::{
+    auth::{
+        account_active,
+        invites::glob_match,
+        keys::{KeyConfig, Keys},
+        ldap::{local_hash, LdapBackend, LdapConfig},
+        oidc::oidc_account,
+        tokens::{hash_token, lookup_token, new_secret, Scope, TOKEN_PREFIX},
+        Jwt,
+    },
+    blocks::blocked_in_direct_room,
+    events::ClockType,
+    get_runtime_handle,
+    notifications::{DndWindow, NotificationSettings, NotifyLevel},
+    profiles::check_avatar,
+    run_or_block,
+    timing::{
+        approvals::{review, ApprovalStatus, ReviewError},
+        board::{clock_status, on_the_clock},
+        breaks::{end_break, start_break},
+        clock_in_to, clock_out,
+        corrections::{audit_log, review_correction, AuditAction, CorrectionError},
+        export::{timesheet_export, to_csv, to_xlsx},
+        feeds::feed_events,
+        ical::{calendar, Event, When},
+        is_clocked_in,
+        kiosk::{
+            kiosk_punches, pin_hash, record_kiosk_punch, set_pin, valid_pin, KioskOutcome,
+            RateLimiter,
+        },
+        leave::{
+            accrue, cancel_leave, get_leave_type, leave_days, request_leave, review_leave,
+            LeaveError,
+        },
+        overtime::{HolidayRate, OvertimePolicy, PaySplit, Threshold},
+        periods::{
+            close_period, closed_periods, period_log, reopen_period, PeriodAction, PeriodError,
+        },
+        projects::ProjectTag,
+        reminders::{auto_clock_out, due_reminders, AUTO_CLOCK_OUT_NOTE},
+        report::{build_report, DayTotal, PeriodTotal, ProjectTotal, WeekTotal},
+        rounding::{rounded_entries, RoundingPolicy, RoundingRule},
+        schedule::attendance,
+        switch_project, time_entries, time_entries_between, PayPeriod, PunchError, TimeConfig,
+        TimeRange, TimeState,
+    },
+    types::{ChatMessage, ChatRoomID, UserID},
+    users::{
+        deactivate_account, delete_account, user_export, DeleteError, MessageHandling, SYSTEM_USER,
+    },
+};
+
+/// Starts a new time entry for `user` outside of any project
+fn clock_in(
+    d: &mut rusqlite::Connection,
+    user: &UserID,
+    note: Option<&str>,
+) -> Result<i64, PunchError> {
+    clock_in_to(d, user, note, &ProjectTag::default())
+}
+
+/// A fresh in-memory database with `users` in it
+fn test_db(users: &[&str]) -> rusqlite::Connection {
+    let d = rusqlite::Connection::open_in_memory().unwrap();
+    d.execute_batch(include_str!("../migrations/up.sql"))
+        .unwrap();
+    for user in users {
+        d.execute(
+            "INSERT INTO users (user_id, password) VALUES (?, '!')",
+            [user],
+        )
+        .unwrap();
+    }
+    d
+}
+
+#[test]
+fn seeded_admin_survives_restarts() {
+    let d = test_db(&[]);
+    d.execute_batch(
+        "UPDATE users SET password = 'hash', active = 0, email = 'root@example.com'
+            WHERE user_id = 'admin';",
+    )
+    .unwrap();
+    // The migrations run again on every start
+    d.execute_batch(include_str!("../migrations/up.sql"))
+        .unwrap();
+    let admin: (String, bool, bool, Option<String>) = d
+        .query_row(
+            "SELECT password, is_admin, active, email FROM users WHERE user_id = 'admin'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        admin,
+        (
+            "______________".into(),
+            true,
+            false,
+            Some("root@example.com".into())
+        )
+    );
+}
+
+#[test]
+fn accounts_deactivate_delete_and_export() {
+    let mut d = test_db(&["jim", "pam", "kim"]);
+    d.execute(
+        "UPDATE users SET password = ? WHERE user_id = 'jim'",
+        [bcrypt::hash("pw", 4).unwrap()],
+    )
+    .unwrap();
+    d.execute_batch(
+        "INSERT INTO chatrooms (chatroom_id) VALUES ('lobby');
+        INSERT INTO chatroom_users (chatroom_id, user_id) VALUES ('lobby', 'jim'), ('lobby', 'pam'),
+            ('lobby', 'kim');
+        INSERT INTO messages (user_id, chatroom_id, message, created_at)
+            VALUES ('jim', 'lobby', 'hi', 1709542800),
+            ('jim', 'lobby', 'bye', 1709571600),
+            ('pam', 'lobby', 'hello', 1709542860),
+            ('kim', 'lobby', 'hey', 1709542920);",
+    )
+    .unwrap();
+    let token = format!("{TOKEN_PREFIX}{}", new_secret());
+    d.execute(
+        "INSERT INTO api_tokens (user_id, name, token_hash, scopes, created_by, created_at) \
+        VALUES ('jim', 'cli', ?, '[]', 'jim', ?)",
+        rusqlite::params![hash_token(&token), Utc::now()],
+    )
+    .unwrap();
+    let (jim, pam, kim) = (
+        UserID("jim".into()),
+        UserID("pam".into()),
+        UserID("kim".into()),
+    );
+    clock_in(&mut d, &jim, Some("hi")).ok().unwrap();
+    clock_out(&mut d, &jim, None).ok().unwrap();
+    let written_by = |d: &rusqlite::Connection, user: &str| -> i64 {
+        d.query_row(
+            "SELECT COUNT(*) FROM messages WHERE user_id = ?",
+            [user],
+            |r| r.get(0),
+        )
+        .unwrap()
+    };
+
+    let export = serde_json::to_value(user_export(&d, &jim).unwrap()).unwrap();
+    assert_eq!(export["user"], "jim");
+    assert_eq!(export["active"], true);
+    assert_eq!(export["rooms"].as_array().unwrap().len(), 1);
+    assert_eq!(export["messages"].as_array().unwrap().len(), 2);
+    assert_eq!(export["time_entries"][0]["start"]["note"], "hi");
+    assert!(user_export(&d, &UserID("nobody".into())).is_err());
+
+    // Deactivated accounts can't log in or use their tokens, but keep their messages
+    assert!(local_hash(&d, "jim").unwrap().is_some());
+    assert!(account_active(&d, &jim).unwrap());
+    assert!(lookup_token(&d, &token).unwrap().is_some());
+    assert!(deactivate_account(&mut d, &jim, MessageHandling::Keep).unwrap());
+    assert!(!deactivate_account(&mut d, &jim, MessageHandling::Keep).unwrap());
+    assert!(local_hash(&d, "jim").unwrap().is_none());
+    assert!(!account_active(&d, &jim).unwrap());
+    assert!(lookup_token(&d, &token).unwrap().is_none());
+    assert_eq!(written_by(&d, "jim"), 2);
+    assert_eq!(
+        serde_json::to_value(user_export(&d, &jim).unwrap()).unwrap()["active"],
+        false
+    );
+    assert!(deactivate_account(&mut d, &pam, MessageHandling::Anonymize).unwrap());
+    assert_eq!(written_by(&d, "pam"), 0);
+    assert_eq!(written_by(&d, "deleted"), 1);
+
+    // Deleting takes everything but the audit trail along
+    assert!(delete_account(&mut d, &jim, MessageHandling::Delete)
+        .ok()
+        .unwrap());
+    assert_eq!(written_by(&d, "jim"), 0);
+    assert!(!account_active(&d, &jim).unwrap());
+    assert!(time_entries(&d, &jim).unwrap().is_empty());
+    let left: i64 = d
+        .query_row(
+            "SELECT (SELECT COUNT(*) FROM chatroom_users WHERE user_id = 'jim') \
+            + (SELECT COUNT(*) FROM api_tokens WHERE user_id = 'jim')",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(left, 0);
+    assert_eq!(audit_log(&d, &jim, None).unwrap().len(), 2);
+    assert!(!delete_account(&mut d, &jim, MessageHandling::Delete)
+        .ok()
+        .unwrap());
+    // The same name made again doesn't get the old token back
+    d.execute_batch("INSERT INTO users (user_id, password) VALUES ('jim', '!');")
+        .unwrap();
+    assert!(lookup_token(&d, &token).unwrap().is_none());
+
+    // Time in a closed pay period has to stay for payroll
+    clock_in(&mut d, &kim, None).ok().unwrap();
+    clock_out(&mut d, &kim, None).ok().unwrap();
+    let today = Utc::now().date_naive();
+    d.execute(
+        "INSERT INTO closed_periods (period_start, period_end, closed_by, closed_at) \
+        VALUES (?, ?, 'pam', ?)",
+        rusqlite::params![today, today, Utc::now()],
+    )
+    .unwrap();
+    assert!(matches!(
+        delete_account(&mut d, &kim, MessageHandling::Anonymize),
+        Err(DeleteError::Locked)
+    ));
+    assert!(account_active(&d, &kim).unwrap());
+    d.execute_batch("DELETE FROM closed_periods;").unwrap();
+    assert!(delete_account(&mut d, &kim, MessageHandling::Anonymize)
+        .ok()
+        .unwrap());
+    assert_eq!(written_by(&d, "kim"), 0);
+    assert_eq!(written_by(&d, "deleted"), 2);
+}
 
 #[test]
 fn what_is_the_json() {
@@ -20,3 +241,1349 @@ fn what_is_the_json() {
     // let json = serde_json::to_string_pretty(&ts).unwrap();
     // println!("{}", json);
 }
+
+#[test]
+fn signup_code_username_patterns() {
+    assert!(glob_match("ops-*", "ops-jim"));
+    assert!(glob_match("ops-*", "ops-"));
+    assert!(glob_match("*-contractor", "jim-contractor"));
+    assert!(glob_match("j?m", "jim"));
+    assert!(glob_match("*a*b*", "xxaxxbxx"));
+    assert!(!glob_match("ops-*", "dev-jim"));
+    assert!(!glob_match("j?m", "jimmy"));
+    assert!(!glob_match("*a*b", "xxbxxa"));
+}
+
+#[test]
+fn signing_key_rotation() {
+    std::env::set_var("TEST_KEY_OLD", "old secret");
+    std::env::set_var("TEST_KEY_NEW", "new secret");
+    let key = |kid: &str, var: &str| KeyConfig {
+        kid: kid.into(),
+        algorithm: jsonwebtoken::Algorithm::HS512,
+        secret_env: Some(var.into()),
+        private_key: None,
+        public_key: None,
+    };
+    let legacy = Keys::load(&[], None, Some("legacy".into())).unwrap();
+    let old = Keys::load(&[key("old", "TEST_KEY_OLD")], None, None).unwrap();
+    let rotated = Keys::load(
+        &[key("old", "TEST_KEY_OLD"), key("new", "TEST_KEY_NEW")],
+        Some("new"),
+        Some("legacy".into()),
+    )
+    .unwrap();
+    // Tokens from before the rotation keep working, new ones need the new key
+    assert!(rotated.decode(&legacy.encode("jim")).is_some());
+    assert!(rotated.decode(&old.encode("jim")).is_some());
+    assert!(old.decode(&rotated.encode("jim")).is_none());
+    assert_eq!(
+        rotated.decode(&rotated.encode("jim")).unwrap().name.0,
+        "jim"
+    );
+    assert!(Keys::load(&[key("old", "TEST_KEY_OLD")], Some("new"), None).is_err());
+    assert!(Keys::load(&[], None, None).is_err());
+}
+
+#[test]
+fn api_tokens_are_scoped_and_expire() {
+    let d = test_db(&[]);
+    d.execute(
+        "INSERT INTO users (user_id, password, service_account) VALUES ('bot', '!', 1)",
+        [],
+    )
+    .unwrap();
+    assert_eq!(
+        hash_token("abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    let add = |scopes: &str, expires_at: Option<DateTime<Utc>>| {
+        let token = format!("{TOKEN_PREFIX}{}", new_secret());
+        d.execute(
+            "INSERT INTO api_tokens (user_id, name, token_hash, scopes, created_by, created_at, \
+            expires_at) VALUES ('bot', 'ci', ?, ?, 'admin', ?, ?)",
+            rusqlite::params![hash_token(&token), scopes, Utc::now(), expires_at],
+        )
+        .unwrap();
+        (token, d.last_insert_rowid())
+    };
+    let hour = chrono::TimeDelta::hours(1);
+
+    let (token, id) = add(r#"["time:read"]"#, Some(Utc::now() + hour));
+    let auth = lookup_token(&d, &token).unwrap().unwrap();
+    assert_eq!(auth.name, UserID("bot".into()));
+    assert_eq!(auth.scopes, Some(vec![Scope::TimeRead]));
+    assert!(auth.require(Scope::TimeRead).is_ok());
+    assert_eq!(
+        auth.require(Scope::ChatWrite),
+        Err(rocket::http::Status::Forbidden)
+    );
+    let used: Option<DateTime<Utc>> = d
+        .query_row(
+            "SELECT last_used_at FROM api_tokens WHERE token_id = ?",
+            [id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert!(used.is_some());
+    // Only the whole token with its prefix is stored
+    let secret = token.strip_prefix(TOKEN_PREFIX).unwrap();
+    assert!(lookup_token(&d, secret).unwrap().is_none());
+    // Session tokens can do anything
+    let session = Jwt {
+        name: UserID("bot".into()),
+        exp: 0,
+        scopes: None,
+    };
+    assert!(session.require(Scope::ChatWrite).is_ok());
+
+    let (expired, _) = add(r#"["time:read"]"#, Some(Utc::now() - hour));
+    assert!(lookup_token(&d, &expired).unwrap().is_none());
+    d.execute(
+        "UPDATE api_tokens SET revoked_at = ? WHERE token_id = ?",
+        rusqlite::params![Utc::now(), id],
+    )
+    .unwrap();
+    assert!(lookup_token(&d, &token).unwrap().is_none());
+    let (forever, _) = add("[]", None);
+    assert!(lookup_token(&d, &forever).unwrap().is_some());
+    d.execute("UPDATE users SET active = 0 WHERE user_id = 'bot'", [])
+        .unwrap();
+    assert!(lookup_token(&d, &forever).unwrap().is_none());
+}
+
+#[test]
+fn oidc_logins_stick_to_their_identity() {
+    let d = test_db(&["pam"]);
+    d.execute(
+        "INSERT INTO users (user_id, password, service_account) VALUES ('bot', '!', 1)",
+        [],
+    )
+    .unwrap();
+    let (jim, pam) = (UserID("jim".into()), UserID("pam".into()));
+    let idp = "https://idp.example.com";
+    // Accounts with a local password and service accounts can't be taken over
+    assert_eq!(oidc_account(&d, idp, "1", "admin").unwrap(), None);
+    assert_eq!(oidc_account(&d, idp, "1", "bot").unwrap(), None);
+
+    assert_eq!(
+        oidc_account(&d, idp, "1", "jim").unwrap(),
+        Some((jim.clone(), true))
+    );
+    // Renaming yourself at the provider keeps your account
+    assert_eq!(
+        oidc_account(&d, idp, "1", "jimbo").unwrap(),
+        Some((jim.clone(), false))
+    );
+    assert_eq!(
+        oidc_account(&d, "https://other.example.com", "1", "jim").unwrap(),
+        None
+    );
+    // Accounts another backend made get linked to the first identity only
+    assert_eq!(
+        oidc_account(&d, idp, "2", "pam").unwrap(),
+        Some((pam.clone(), false))
+    );
+    assert_eq!(oidc_account(&d, idp, "3", "pam").unwrap(), None);
+}
+
+#[test]
+fn avatars_must_be_small_images() {
+    use rocket::http::{ContentType, Status};
+    let png = b"\x89PNG\r\n\x1a\n rest of the image";
+    assert_eq!(check_avatar(&ContentType::PNG, png), Ok(()));
+    assert_eq!(
+        check_avatar(&ContentType::JPEG, b"\xff\xd8\xff\xe0 jfif"),
+        Ok(())
+    );
+    assert_eq!(check_avatar(&ContentType::GIF, b"GIF89a..."), Ok(()));
+    assert_eq!(
+        check_avatar(&ContentType::WEBP, b"RIFF\0\0\0\0WEBPVP8 "),
+        Ok(())
+    );
+    // Other types, and files that aren't what they claim to be
+    assert_eq!(
+        check_avatar(&ContentType::SVG, b"<svg></svg>"),
+        Err(Status::UnsupportedMediaType)
+    );
+    assert_eq!(
+        check_avatar(&ContentType::JPEG, png),
+        Err(Status::UnsupportedMediaType)
+    );
+    assert_eq!(
+        check_avatar(&ContentType::PNG, b"<html>"),
+        Err(Status::UnsupportedMediaType)
+    );
+    let mut big = png.to_vec();
+    big.resize(512 * 1024, 0);
+    assert_eq!(check_avatar(&ContentType::PNG, &big), Ok(()));
+    big.push(0);
+    assert_eq!(
+        check_avatar(&ContentType::PNG, &big),
+        Err(Status::PayloadTooLarge)
+    );
+}
+
+#[test]
+fn blocks_refuse_direct_messages_only() {
+    let d = test_db(&["jim", "pam", "dwight"]);
+    d.execute_batch(
+        "INSERT INTO chatrooms (chatroom_id, direct) VALUES ('jim-pam', 1), ('sales', 0);
+        INSERT INTO chatroom_users (chatroom_id, user_id) VALUES ('jim-pam', 'jim'),
+            ('jim-pam', 'pam'), ('sales', 'jim'), ('sales', 'dwight');",
+    )
+    .unwrap();
+    let (jim, pam, dwight) = (
+        UserID("jim".into()),
+        UserID("pam".into()),
+        UserID("dwight".into()),
+    );
+    let blocked = |room: &str, sender: &UserID| {
+        blocked_in_direct_room(&d, &ChatRoomID(room.into()), sender).unwrap()
+    };
+    assert!(!blocked("jim-pam", &jim));
+    d.execute_batch(
+        "INSERT INTO user_blocks (blocker_id, blocked_id, created_at) VALUES
+            ('pam', 'jim', '2024-03-01T00:00:00Z'), ('dwight', 'jim', '2024-03-01T00:00:00Z');",
+    )
+    .unwrap();
+    assert!(blocked("jim-pam", &jim));
+    assert!(!blocked("jim-pam", &pam));
+    // A room of two that isn't a direct message still gets the message, flagged
+    assert!(!blocked("sales", &jim));
+    assert!(!blocked("sales", &dwight));
+}
+
+#[test]
+fn notify_respects_dnd_and_room_levels() {
+    let jim = UserID("jim".into());
+    let msg = |room: &str, content: &str| ChatMessage {
+        sender: "bob".into(),
+        room: ChatRoomID(room.into()),
+        content: content.into(),
+        timestamp: 0.0,
+        notify: false,
+        flagged: false,
+    };
+    let settings = NotificationSettings {
+        // Quiet from 22:00 to 07:00 starting on weekdays
+        dnd: vec![DndWindow {
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+        }],
+        rooms: [
+            (ChatRoomID("random".into()), NotifyLevel::Mentions),
+            (ChatRoomID("bots".into()), NotifyLevel::Mute),
+        ]
+        .into(),
+        keywords: vec!["Outage".into()],
+    };
+    let tz: chrono_tz::Tz = "America/New_York".parse().unwrap();
+    let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+    // Wednesday 12:00 in New York
+    let noon = at("2024-06-05T16:00:00Z");
+    assert!(settings.should_notify(&jim, tz, &msg("general", "hi"), noon));
+    assert!(!settings.should_notify(&jim, tz, &msg("random", "hi"), noon));
+    assert!(settings.should_notify(&jim, tz, &msg("random", "hi @Jim"), noon));
+    assert!(settings.should_notify(&jim, tz, &msg("random", "db outage"), noon));
+    assert!(!settings.should_notify(&jim, tz, &msg("bots", "hi @jim"), noon));
+    // Saturday 02:00 is still inside Friday night's window, Sunday 02:00 isn't
+    assert!(!settings.should_notify(&jim, tz, &msg("general", "hi"), at("2024-06-08T06:00:00Z")));
+    assert!(settings.should_notify(&jim, tz, &msg("general", "hi"), at("2024-06-09T06:00:00Z")));
+    // Nobody gets notified about their own messages
+    let bob = UserID("bob".into());
+    assert!(!settings.should_notify(&bob, tz, &msg("general", "hi"), noon));
+}
+
+#[test]
+fn punch_in_and_out() {
+    let mut d = test_db(&["jim"]);
+    let jim = UserID("jim".into());
+
+    // Timesheets get created on the first punch
+    let first = clock_in(&mut d, &jim, Some("it's monday")).ok().unwrap();
+    assert!(is_clocked_in(&d, &jim).unwrap());
+    assert!(matches!(
+        clock_in(&mut d, &jim, None),
+        Err(PunchError::AlreadyClockedIn)
+    ));
+    assert_eq!(clock_out(&mut d, &jim, None).ok(), Some(first));
+    assert!(matches!(
+        clock_out(&mut d, &jim, None),
+        Err(PunchError::NotClockedIn)
+    ));
+
+    // A timesheet marked clocked in without an open entry gets repaired
+    d.execute(
+        "UPDATE timesheets SET clocked_in = 1, current_id = NULL",
+        [],
+    )
+    .unwrap();
+    assert!(!is_clocked_in(&d, &jim).unwrap());
+    let second = clock_in(&mut d, &jim, None).ok().unwrap();
+    assert_ne!(first, second);
+
+    let entries = serde_json::to_value(time_entries(&d, &jim).unwrap()).unwrap();
+    assert_eq!(entries[0]["id"], first);
+    assert_eq!(entries[0]["start"]["note"], "it's monday");
+    assert!(entries[0]["end"]["time"].is_string());
+    assert_eq!(entries[1]["id"], second);
+    assert!(entries[1]["end"].is_null());
+}
+
+#[test]
+fn time_entries_filtered_by_local_date() {
+    let d = test_db(&["jim"]);
+    // Friday 22:00 and Saturday 09:00 in New York
+    d.execute_batch(
+        "INSERT INTO user_profiles (user_id, timezone) VALUES ('jim', 'America/New_York');
+        INSERT INTO timesheets (timesheet_id, user_id, clocked_in) VALUES (1, 'jim', 0);
+        INSERT INTO time_entries (timesheet_id, start_time, end_time) VALUES
+            (1, '2024-03-09T03:00:00Z', '2024-03-09T07:00:00Z'),
+            (1, '2024-03-09T14:00:00Z', '2024-03-09T18:00:00Z');",
+    )
+    .unwrap();
+    let jim = UserID("jim".into());
+    let ids = |start: &str, end: &str| {
+        let (start, end) = (start.parse().ok(), end.parse().ok());
+        let entries = time_entries_between(&d, &jim, start, end).unwrap();
+        let json = serde_json::to_value(entries).unwrap();
+        json.as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["id"].as_i64().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids("2024-03-08", "2024-03-08"), [1]);
+    assert_eq!(ids("2024-03-09", "2024-03-09"), [2]);
+    assert_eq!(ids("2024-03-08", "2024-03-09"), [1, 2]);
+    assert_eq!(ids("", ""), [1, 2]);
+}
+
+#[test]
+fn approved_periods_lock_punches() {
+    let mut d = test_db(&["jim", "boss"]);
+    d.execute_batch("INSERT INTO user_managers (user_id, manager_id) VALUES ('jim', 'boss');")
+        .unwrap();
+    let (jim, boss) = (UserID("jim".into()), UserID("boss".into()));
+    let (start, end) = TimeConfig::default().pay_period(Utc::now().date_naive());
+    d.execute(
+        "INSERT INTO timesheet_approvals (user_id, period_start, period_end, status, submitted_at) \
+        VALUES ('jim', ?, ?, 'submitted', ?)",
+        rusqlite::params![start, end, Utc::now()],
+    )
+    .unwrap();
+
+    clock_in(&mut d, &jim, None).ok().unwrap();
+    let approve = |d: &mut rusqlite::Connection| {
+        review(
+            d,
+            &boss,
+            &jim,
+            start,
+            ApprovalStatus::Approved,
+            Some("thanks"),
+        )
+    };
+    assert!(matches!(approve(&mut d), Err(ReviewError::ClockedIn)));
+    clock_out(&mut d, &jim, None).ok().unwrap();
+    let approval = approve(&mut d).ok().unwrap();
+    assert_eq!(approval.status, ApprovalStatus::Approved);
+    assert_eq!(approval.reviewed_by, Some(boss.clone()));
+    assert!(matches!(approve(&mut d), Err(ReviewError::NotSubmitted)));
+    assert!(matches!(
+        clock_in(&mut d, &jim, None),
+        Err(PunchError::Locked)
+    ));
+}
+
+#[test]
+fn closed_periods_lock_everyone() {
+    let mut d = test_db(&["jim", "boss"]);
+    let (jim, boss) = (UserID("jim".into()), UserID("boss".into()));
+    let today = Utc::now().date_naive();
+    let period = TimeConfig::default().pay_period(today);
+    let after = period.1 + chrono::Days::new(1);
+    assert!(matches!(
+        close_period(&mut d, &boss, period, today, None),
+        Err(PeriodError::NotOver)
+    ));
+    clock_in(&mut d, &jim, None).ok().unwrap();
+    match close_period(&mut d, &boss, period, after, None) {
+        Err(PeriodError::ClockedIn(users)) => assert_eq!(users, std::slice::from_ref(&jim)),
+        _ => panic!("closed a period someone is clocked in to"),
+    }
+    clock_out(&mut d, &jim, None).ok().unwrap();
+    let closed = close_period(&mut d, &boss, period, after, Some("payroll ran"))
+        .ok()
+        .unwrap();
+    assert_eq!((closed.start, closed.end), period);
+    assert!(matches!(
+        close_period(&mut d, &boss, period, after, None),
+        Err(PeriodError::Closed)
+    ));
+    assert_eq!(closed_periods(&mut d).unwrap(), [closed]);
+    // Nobody has approved timesheets, the period is locked for everyone
+    assert!(matches!(
+        clock_in(&mut d, &jim, None),
+        Err(PunchError::Locked)
+    ));
+    assert!(matches!(
+        clock_in(&mut d, &boss, None),
+        Err(PunchError::Locked)
+    ));
+
+    assert!(matches!(
+        reopen_period(&mut d, &boss, after, "typo"),
+        Err(PeriodError::NotClosed)
+    ));
+    let reopened = reopen_period(&mut d, &boss, today, "missed a shift")
+        .ok()
+        .unwrap();
+    assert_eq!((reopened.start, reopened.end), period);
+    assert!(closed_periods(&mut d).unwrap().is_empty());
+    clock_in(&mut d, &jim, None).ok().unwrap();
+    let log = period_log(&mut d).unwrap();
+    let actions: Vec<_> = log
+        .iter()
+        .map(|change| (change.action, change.reason.as_deref()))
+        .collect();
+    assert_eq!(
+        actions,
+        [
+            (PeriodAction::Close, Some("payroll ran")),
+            (PeriodAction::Reopen, Some("missed a shift"))
+        ]
+    );
+    assert_eq!(log[1].changed_by, boss);
+    assert!(d
+        .execute("UPDATE pay_period_log SET reason = 'nothing to see'", [])
+        .is_err());
+    assert!(d.execute("DELETE FROM pay_period_log", []).is_err());
+    assert_eq!(period_log(&mut d).unwrap().len(), 2);
+}
+
+#[test]
+fn corrections_are_audited() {
+    let mut d = test_db(&["jim", "boss"]);
+    d.execute_batch("INSERT INTO user_managers (user_id, manager_id) VALUES ('jim', 'boss');")
+        .unwrap();
+    let (jim, boss) = (UserID("jim".into()), UserID("boss".into()));
+    // Jim forgot to clock out
+    let entry = clock_in(&mut d, &jim, Some("hi")).ok().unwrap();
+    let (start, end) = (
+        Utc::now() - chrono::TimeDelta::hours(3),
+        Utc::now() - chrono::TimeDelta::hours(1),
+    );
+    d.execute(
+        "INSERT INTO time_corrections (user_id, kind, time_entry_id, start_time, end_time, \
+        reason, status, requested_at) VALUES ('jim', 'edit', ?, ?, ?, 'forgot', 'submitted', ?)",
+        rusqlite::params![entry, start, end, Utc::now()],
+    )
+    .unwrap();
+    let id = d.last_insert_rowid();
+
+    let correction = review_correction(&mut d, &boss, id, ApprovalStatus::Approved, None)
+        .ok()
+        .unwrap();
+    assert_eq!(correction.status, ApprovalStatus::Approved);
+    assert!(!is_clocked_in(&d, &jim).unwrap());
+    assert!(matches!(
+        review_correction(&mut d, &boss, id, ApprovalStatus::Rejected, None),
+        Err(CorrectionError::Reviewed)
+    ));
+
+    let log = audit_log(&d, &jim, Some(entry)).unwrap();
+    let actions: Vec<_> = log.iter().map(|audit| audit.action).collect();
+    assert_eq!(actions, [AuditAction::ClockIn, AuditAction::Edit]);
+    let json = serde_json::to_value(&log).unwrap();
+    let (punch, edit) = (&json[0], &json[1]);
+    assert_eq!(edit["changed_by"], "boss");
+    assert_eq!(edit["correction"], id);
+    // The original punch is still on record
+    assert_eq!(edit["before"], punch["after"]);
+    assert_eq!(edit["before"]["start"]["note"], "hi");
+    assert!(edit["before"]["end"].is_null());
+    assert_eq!(
+        edit["after"]["start"]["time"],
+        serde_json::to_value(start).unwrap()
+    );
+    assert!(d.execute("DELETE FROM time_entry_audit", []).is_err());
+    assert!(d
+        .execute("UPDATE time_entry_audit SET changed_by = 'jim'", [])
+        .is_err());
+}
+
+#[test]
+fn forgotten_punches_get_closed() {
+    let mut d = test_db(&["jim"]);
+    let jim = UserID("jim".into());
+    let entry = clock_in(&mut d, &jim, None).ok().unwrap();
+    let hours = chrono::TimeDelta::hours;
+    let later = |h| Utc::now() + hours(h);
+
+    assert!(due_reminders(&mut d, hours(10), later(9))
+        .unwrap()
+        .is_empty());
+    let due = due_reminders(&mut d, hours(10), later(11)).unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].0, jim);
+    // Only once per entry
+    assert!(due_reminders(&mut d, hours(10), later(12))
+        .unwrap()
+        .is_empty());
+
+    assert!(auto_clock_out(&mut d, hours(16), later(15))
+        .unwrap()
+        .is_empty());
+    let closed = auto_clock_out(&mut d, hours(16), later(20)).unwrap();
+    assert_eq!(closed.len(), 1);
+    assert!(!is_clocked_in(&d, &jim).unwrap());
+    let json = serde_json::to_value(time_entries(&d, &jim).unwrap()).unwrap();
+    assert_eq!(json[0]["id"], entry);
+    assert_eq!(json[0]["flagged"], true);
+    assert_eq!(json[0]["end"]["note"], AUTO_CLOCK_OUT_NOTE);
+    let log = audit_log(&d, &jim, Some(entry)).unwrap();
+    assert_eq!(log.last().unwrap().action, AuditAction::AutoClockOut);
+    assert_eq!(log.last().unwrap().changed_by.0, SYSTEM_USER);
+}
+
+#[test]
+fn report_totals_in_local_time() {
+    let date = |s: &str| s.parse::<NaiveDate>().unwrap();
+    let entries: Vec<TimeRange> = serde_json::from_value(serde_json::json!([
+        // Friday 22:00 to Saturday 02:00 in New York
+        {"id": 1, "start": {"time": "2024-03-09T03:00:00Z", "note": null},
+            "end": {"time": "2024-03-09T07:00:00Z", "note": null}},
+        // Monday 09:00 after the switch to DST, still clocked in
+        {"id": 2, "start": {"time": "2024-03-11T13:00:00Z", "note": null}, "end": null},
+    ]))
+    .unwrap();
+    let now = "2024-03-11T15:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let config = TimeConfig::default();
+    let report = build_report(
+        UserID("jim".into()),
+        &entries,
+        "America/New_York".parse().unwrap(),
+        &config,
+        date("2024-03-01"),
+        date("2024-03-31"),
+        now,
+    );
+    let regular = |hours| PaySplit {
+        regular: hours,
+        ..Default::default()
+    };
+    let day = |d: &str, hours| DayTotal {
+        date: date(d),
+        hours,
+        pay: regular(hours),
+    };
+    assert_eq!(
+        report.days,
+        [
+            day("2024-03-08", 2.0),
+            day("2024-03-09", 2.0),
+            day("2024-03-11", 2.0)
+        ]
+    );
+    assert_eq!(
+        report.weeks,
+        [
+            WeekTotal {
+                year: 2024,
+                week: 10,
+                hours: 4.0,
+                pay: regular(4.0),
+            },
+            WeekTotal {
+                year: 2024,
+                week: 11,
+                hours: 2.0,
+                pay: regular(2.0),
+            }
+        ]
+    );
+    assert_eq!(
+        report.pay_periods,
+        [
+            PeriodTotal {
+                start: date("2024-02-26"),
+                end: date("2024-03-10"),
+                hours: 4.0,
+                pay: regular(4.0),
+            },
+            PeriodTotal {
+                start: date("2024-03-11"),
+                end: date("2024-03-24"),
+                hours: 2.0,
+                pay: regular(2.0),
+            }
+        ]
+    );
+    assert_eq!(report.total, 6.0);
+
+    let semimonthly = TimeConfig {
+        pay_period: PayPeriod::Semimonthly,
+        ..config
+    };
+    assert_eq!(
+        semimonthly.pay_period(date("2024-02-20")),
+        (date("2024-02-16"), date("2024-02-29"))
+    );
+}
+
+#[test]
+fn exports_read_back() {
+    let mut d = test_db(&["jim", "JIM", "Time Entries"]);
+    let jim = UserID("jim".into());
+    clock_in(&mut d, &jim, Some("=1+1")).ok().unwrap();
+    clock_out(&mut d, &jim, Some("left at 5")).ok().unwrap();
+    let now = Utc::now();
+    let today = now.date_naive();
+    let period = (today - chrono::Days::new(1), today + chrono::Days::new(1));
+    let config = TimeConfig::default();
+    let export =
+        |user: &str| timesheet_export(&d, UserID(user.into()), &config, period, now).unwrap();
+
+    let csv = to_csv(&[export("jim")]).unwrap();
+    let mut reader = csv::Reader::from_reader(csv.as_slice());
+    assert_eq!(reader.headers().unwrap().len(), 12);
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(&rows[0][0], "jim");
+    assert_eq!(&rows[0][2], today.to_string());
+    // Notes can't turn into formulas
+    assert_eq!(&rows[0][6], "'=1+1");
+    assert_eq!(&rows[0][7], "left at 5");
+
+    // Sheet names are unique ignoring case, even for the same user twice
+    let sheets: Vec<_> = ["jim", "JIM", "Time Entries", "jim"]
+        .into_iter()
+        .map(export)
+        .collect();
+    let xlsx = to_xlsx(&sheets).unwrap();
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(xlsx)).unwrap();
+    let mut read = |path: &str| {
+        let mut xml = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name(path).unwrap(), &mut xml).unwrap();
+        xml
+    };
+    let workbook = read("xl/workbook.xml");
+    let names: Vec<_> = workbook
+        .split("<sheet name=\"")
+        .skip(1)
+        .map(|sheet| sheet.split('"').next().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "Time entries",
+            "jim",
+            "JIM (2)",
+            "Time Entries (2)",
+            "jim (3)"
+        ]
+    );
+    let entries = read("xl/worksheets/sheet1.xml");
+    assert!(!entries.contains("<f>"));
+    assert!(read("xl/sharedStrings.xml").contains("=1+1"));
+}
+
+#[test]
+fn unpaid_breaks_are_not_worked() {
+    let mut d = test_db(&["jim"]);
+    let jim = UserID("jim".into());
+    assert!(matches!(
+        start_break(&mut d, &jim, false),
+        Err(PunchError::NotClockedIn)
+    ));
+    let entry = clock_in(&mut d, &jim, None).ok().unwrap();
+    start_break(&mut d, &jim, false).ok().unwrap();
+    assert!(matches!(
+        start_break(&mut d, &jim, true),
+        Err(PunchError::OnBreak)
+    ));
+    end_break(&mut d, &jim).ok().unwrap();
+    assert!(matches!(
+        end_break(&mut d, &jim),
+        Err(PunchError::NotOnBreak)
+    ));
+    // Clocking out ends any open break
+    start_break(&mut d, &jim, true).ok().unwrap();
+    clock_out(&mut d, &jim, None).ok().unwrap();
+    let json = serde_json::to_value(time_entries(&d, &jim).unwrap()).unwrap();
+    assert_eq!(json[0]["id"], entry);
+    assert_eq!(json[0]["breaks"].as_array().unwrap().len(), 2);
+    assert!(!json[0]["breaks"][1]["end"].is_null());
+    let actions: Vec<_> = audit_log(&d, &jim, Some(entry))
+        .unwrap()
+        .into_iter()
+        .map(|change| change.action)
+        .collect();
+    assert_eq!(
+        actions,
+        [
+            AuditAction::ClockIn,
+            AuditAction::BreakStart,
+            AuditAction::BreakEnd,
+            AuditAction::BreakStart,
+            AuditAction::ClockOut
+        ]
+    );
+    // No breaks in a closed pay period
+    clock_in(&mut d, &jim, None).ok().unwrap();
+    let today = Utc::now().date_naive();
+    d.execute(
+        "INSERT INTO closed_periods (period_start, period_end, closed_by, closed_at) \
+        VALUES (?, ?, 'jim', ?)",
+        rusqlite::params![today, today, Utc::now()],
+    )
+    .unwrap();
+    assert!(matches!(
+        start_break(&mut d, &jim, false),
+        Err(PunchError::Locked)
+    ));
+
+    let date = |s: &str| s.parse::<NaiveDate>().unwrap();
+    let entries: Vec<TimeRange> = serde_json::from_value(serde_json::json!([
+        // 09:00 to 17:30 with a paid coffee break and an unpaid lunch
+        {"id": 1, "start": {"time": "2024-03-04T09:00:00Z", "note": null},
+            "end": {"time": "2024-03-04T17:30:00Z", "note": null},
+            "breaks": [
+                {"id": 1, "start": "2024-03-04T10:00:00Z", "end": "2024-03-04T10:15:00Z", "paid": true},
+                {"id": 2, "start": "2024-03-04T12:00:00Z", "end": "2024-03-04T12:30:00Z", "paid": false},
+            ]},
+        // 7 hours straight
+        {"id": 2, "start": {"time": "2024-03-05T09:00:00Z", "note": null},
+            "end": {"time": "2024-03-05T16:00:00Z", "note": null}},
+    ]))
+    .unwrap();
+    let config = TimeConfig {
+        break_required_after_hours: 6,
+        ..TimeConfig::default()
+    };
+    let report = build_report(
+        jim,
+        &entries,
+        chrono_tz::Tz::UTC,
+        &config,
+        date("2024-03-01"),
+        date("2024-03-31"),
+        Utc::now(),
+    );
+    assert_eq!(report.total, 15.0);
+    assert_eq!(report.days[0].hours, 8.0);
+    assert_eq!(report.missing_breaks, [2]);
+}
+
+#[test]
+fn switching_projects_splits_entries() {
+    let mut d = test_db(&["jim"]);
+    d.execute_batch(
+        "INSERT INTO projects (project_id, name, active, created_at) VALUES
+          (1, 'Website', 1, '2024-01-01T00:00:00Z'), (2, 'Old app', 0, '2024-01-01T00:00:00Z');",
+    )
+    .unwrap();
+    let jim = UserID("jim".into());
+    let tag = |project, task: Option<&str>| ProjectTag {
+        project: Some(project),
+        task: task.map(Into::into),
+    };
+    assert!(matches!(
+        clock_in_to(&mut d, &jim, None, &tag(2, None)),
+        Err(PunchError::InactiveProject)
+    ));
+    clock_in_to(&mut d, &jim, None, &tag(1, Some("design")))
+        .ok()
+        .unwrap();
+    assert!(matches!(
+        switch_project(&mut d, &jim, None, &tag(2, None)),
+        Err(PunchError::InactiveProject)
+    ));
+    switch_project(&mut d, &jim, Some("bugs"), &Default::default())
+        .ok()
+        .unwrap();
+    assert!(is_clocked_in(&d, &jim).unwrap());
+    let json = serde_json::to_value(time_entries(&d, &jim).unwrap()).unwrap();
+    assert_eq!(json[0]["project"], 1);
+    assert_eq!(json[0]["task"], "design");
+    assert_eq!(json[0]["end"]["time"], json[1]["start"]["time"]);
+    assert!(json[1]["project"].is_null());
+    assert!(json[1]["end"].is_null());
+
+    let entries: Vec<TimeRange> = serde_json::from_value(serde_json::json!([
+        {"id": 1, "start": {"time": "2024-03-04T09:00:00Z", "note": null},
+            "end": {"time": "2024-03-04T12:00:00Z", "note": null}, "project": 1},
+        {"id": 2, "start": {"time": "2024-03-04T12:00:00Z", "note": null},
+            "end": {"time": "2024-03-04T13:30:00Z", "note": null}},
+        {"id": 3, "start": {"time": "2024-03-05T09:00:00Z", "note": null},
+            "end": {"time": "2024-03-05T10:00:00Z", "note": null}, "project": 1},
+    ]))
+    .unwrap();
+    let date = |s: &str| s.parse::<NaiveDate>().unwrap();
+    let mut report = build_report(
+        jim,
+        &entries,
+        chrono_tz::Tz::UTC,
+        &TimeConfig::default(),
+        date("2024-03-01"),
+        date("2024-03-31"),
+        Utc::now(),
+    );
+    report.name_projects(&[(1, "Website".to_string())].into());
+    assert_eq!(
+        report.projects,
+        [
+            ProjectTotal {
+                project: None,
+                name: None,
+                hours: 1.5
+            },
+            ProjectTotal {
+                project: Some(1),
+                name: Some("Website".into()),
+                hours: 4.0
+            }
+        ]
+    );
+}
+
+#[test]
+fn leave_accrues_and_shows_in_reports() {
+    let mut d = test_db(&["jim", "boss"]);
+    d.execute_batch(
+        "INSERT INTO leave_types (leave_type, name, paid, accrual_hours, max_balance)
+            VALUES ('vacation', 'Vacation', 1, 4, 10);
+        INSERT INTO holidays (date, name, hours) VALUES ('2024-03-08', 'Founders day', 8);",
+    )
+    .unwrap();
+    let (jim, boss) = (UserID("jim".into()), UserID("boss".into()));
+    let date = |s: &str| s.parse::<NaiveDate>().unwrap();
+    let config = TimeConfig {
+        pay_period: PayPeriod::Weekly,
+        ..TimeConfig::default()
+    };
+    let vacation = |d: &rusqlite::Connection, today: &str| {
+        let leave = get_leave_type(d, "vacation").unwrap().unwrap();
+        accrue(d, &jim, &leave, &config, date(today))
+            .unwrap()
+            .unwrap()
+    };
+    // Balances start with the current pay period, then three weeks go by, capped at 10
+    assert_eq!(vacation(&d, "2024-03-04").hours, 0.0);
+    assert_eq!(vacation(&d, "2024-03-25").hours, 10.0);
+    let request = |d: &mut rusqlite::Connection, start: &str, end: &str, per_day: f64| {
+        request_leave(
+            d,
+            &jim,
+            "vacation",
+            date(start),
+            date(end),
+            per_day,
+            None,
+            &config,
+            date("2024-03-25"),
+        )
+    };
+    // A weekend alone has no working days
+    assert!(matches!(
+        request(&mut d, "2024-03-09", "2024-03-10", 8.0),
+        Err(LeaveError::Invalid)
+    ));
+    // Wednesday to Monday skips the holiday and the weekend: 3 days
+    assert!(matches!(
+        request(&mut d, "2024-03-06", "2024-03-11", 8.0),
+        Err(LeaveError::InsufficientBalance)
+    ));
+    let leave = request(&mut d, "2024-03-06", "2024-03-11", 2.0)
+        .ok()
+        .unwrap();
+    assert_eq!(leave.hours, 6.0);
+    let reviewed = review_leave(
+        &mut d,
+        &boss,
+        leave.id,
+        ApprovalStatus::Approved,
+        None,
+        &config,
+        date("2024-03-25"),
+    )
+    .ok()
+    .unwrap();
+    assert_eq!(reviewed.status, ApprovalStatus::Approved);
+    assert_eq!(vacation(&d, "2024-03-25").hours, 4.0);
+    assert!(matches!(
+        review_leave(
+            &mut d,
+            &boss,
+            leave.id,
+            ApprovalStatus::Rejected,
+            None,
+            &config,
+            date("2024-03-25"),
+        ),
+        Err(LeaveError::Reviewed)
+    ));
+
+    let days = leave_days(
+        &d,
+        &jim,
+        &config.work_days,
+        date("2024-03-01"),
+        date("2024-03-31"),
+    )
+    .unwrap();
+    let kinds: Vec<_> = days
+        .iter()
+        .map(|day| (day.date.to_string(), day.kind.as_str(), day.hours))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ("2024-03-06".into(), "vacation", 2.0),
+            ("2024-03-07".into(), "vacation", 2.0),
+            ("2024-03-08".into(), "holiday", 8.0),
+            ("2024-03-11".into(), "vacation", 2.0),
+        ]
+    );
+    let mut report = build_report(
+        jim.clone(),
+        &[],
+        chrono_tz::Tz::UTC,
+        &config,
+        date("2024-03-01"),
+        date("2024-03-31"),
+        Utc::now(),
+    );
+    report.add_leave(days);
+    assert_eq!(report.total, 0.0);
+    assert_eq!(report.paid_leave, 14.0);
+
+    // Cancelling approved leave gives the hours back, once
+    let cancelled = cancel_leave(&mut d, &jim, leave.id).ok().unwrap();
+    assert_eq!(cancelled.status, ApprovalStatus::Cancelled);
+    assert_eq!(cancelled.cancelled_by, Some(jim.clone()));
+    assert_eq!(vacation(&d, "2024-03-25").hours, 10.0);
+    assert!(matches!(
+        cancel_leave(&mut d, &jim, leave.id),
+        Err(LeaveError::Cancelled)
+    ));
+    let days = leave_days(
+        &d,
+        &jim,
+        &config.work_days,
+        date("2024-03-01"),
+        date("2024-03-31"),
+    )
+    .unwrap();
+    assert_eq!(days.len(), 1);
+
+    // Nobody works Fridays in a four day week
+    let config = TimeConfig {
+        work_days: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu],
+        ..config
+    };
+    assert!(matches!(
+        request_leave(
+            &mut d,
+            &jim,
+            "vacation",
+            date("2024-03-15"),
+            date("2024-03-17"),
+            8.0,
+            None,
+            &config,
+            date("2024-03-25"),
+        ),
+        Err(LeaveError::Invalid)
+    ));
+}
+
+#[test]
+fn shifts_flag_late_arrivals_and_no_shows() {
+    let time = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+    let entries: Vec<TimeRange> = serde_json::from_value(serde_json::json!([
+        {"id": 1, "start": {"time": "2024-03-04T09:20:00Z", "note": null},
+            "end": {"time": "2024-03-04T16:00:00Z", "note": null}},
+        {"id": 2, "start": {"time": "2024-03-05T08:57:00Z", "note": null},
+            "end": {"time": "2024-03-05T16:58:00Z", "note": null}},
+        {"id": 3, "start": {"time": "2024-03-07T09:00:00Z", "note": null}, "end": null},
+    ]))
+    .unwrap();
+    let grace = chrono::TimeDelta::minutes(5);
+    let now = time("2024-03-07T12:00:00Z");
+    let shift = |day: &str| {
+        attendance(
+            time(&format!("2024-03-{day}T09:00:00Z")),
+            time(&format!("2024-03-{day}T17:00:00Z")),
+            &entries,
+            grace,
+            now,
+        )
+    };
+    let late = shift("04").unwrap();
+    assert_eq!((late.late_minutes, late.left_early_minutes), (20, 60));
+    // Within the grace period both ways
+    let on_time = shift("05").unwrap();
+    assert_eq!((on_time.late_minutes, on_time.left_early_minutes), (0, 0));
+    assert!(shift("06").unwrap().no_show);
+    let missing = attendance(
+        time("2024-03-07T08:00:00Z"),
+        time("2024-03-07T13:00:00Z"),
+        &[],
+        grace,
+        now,
+    )
+    .unwrap();
+    assert_eq!((missing.late_minutes, missing.no_show), (240, false));
+    let working = shift("07").unwrap();
+    assert_eq!(working.clocked_out, None);
+    assert!(!working.no_show);
+    // Not started yet
+    assert_eq!(shift("08"), None);
+
+    let ics = calendar(
+        "Shifts of jim",
+        &[Event {
+            uid: "shift-1@melangerie".into(),
+            when: When::Timed(time("2024-03-04T09:00:00Z"), time("2024-03-04T17:00:00Z")),
+            summary: "Cashier, front; desk".into(),
+            description: Some(
+                "Bring keys\nand a very long note that has to be folded somewhere".into(),
+            ),
+        }],
+    );
+    assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+    assert!(ics.contains("\r\nDTSTART:20240304T090000Z\r\n"));
+    assert!(ics.contains("\r\nSUMMARY:Cashier\\, front\\; desk\r\n"));
+    assert!(ics.lines().all(|line| line.len() <= 75));
+    assert!(ics.contains("\r\n "));
+}
+
+#[test]
+fn calendar_feeds_show_time_leave_and_shifts() {
+    let mut d = test_db(&["jim"]);
+    d.execute_batch(
+        "INSERT INTO leave_types (leave_type, name, paid) VALUES ('sick', 'Sick leave', 1);
+        INSERT INTO leave_requests (user_id, leave_type, start_date, end_date, hours_per_day,
+            hours, status, requested_at)
+            VALUES ('jim', 'sick', '2024-03-04', '2024-03-05', 8, 16, 'approved', '2024-03-01T00:00:00Z');
+        INSERT INTO shifts (user_id, start_time, end_time, role, created_by, published_at)
+            VALUES ('jim', '2024-03-06T09:00:00Z', '2024-03-06T17:00:00Z', 'cashier', 'boss',
+                '2024-03-01T00:00:00Z'),
+            ('jim', '2024-03-07T09:00:00Z', '2024-03-07T17:00:00Z', 'draft', 'boss', NULL);",
+    )
+    .unwrap();
+    let jim = UserID("jim".into());
+    clock_in(&mut d, &jim, Some("opening")).ok().unwrap();
+    let work_days = TimeConfig::default().work_days;
+    let events = feed_events(&d, &jim, &work_days).unwrap();
+    // Still clocked in, so there's no time to show yet
+    assert_eq!(events.len(), 2);
+    clock_out(&mut d, &jim, None).ok().unwrap();
+    // Friday to Monday is two days off, not four
+    d.execute_batch(
+        "INSERT INTO leave_requests (user_id, leave_type, start_date, end_date, hours_per_day,
+            hours, status, requested_at)
+            VALUES ('jim', 'sick', '2024-03-08', '2024-03-11', 8, 16, 'approved', '2024-03-01T00:00:00Z');",
+    )
+    .unwrap();
+    let ics = calendar("Time of jim", &feed_events(&d, &jim, &work_days).unwrap());
+    assert!(ics.contains("SUMMARY:Worked\r\nDESCRIPTION:opening\r\n"));
+    assert!(ics.contains(
+        "DTSTART;VALUE=DATE:20240304\r\nDTEND;VALUE=DATE:20240306\r\nSUMMARY:Sick leave\r\n"
+    ));
+    assert!(ics.contains("DTSTART;VALUE=DATE:20240308\r\nDTEND;VALUE=DATE:20240309\r\n"));
+    assert!(ics.contains("DTSTART;VALUE=DATE:20240311\r\nDTEND;VALUE=DATE:20240312\r\n"));
+    assert!(!ics.contains("DTSTART;VALUE=DATE:20240309"));
+    assert!(ics.contains("SUMMARY:cashier"));
+    assert!(!ics.contains("draft"));
+}
+
+#[test]
+fn board_shows_who_is_on_the_clock() {
+    let mut d = test_db(&["jim", "pam", "boss"]);
+    d.execute_batch("INSERT INTO user_managers (user_id, manager_id) VALUES ('jim', 'boss');")
+        .unwrap();
+    let (jim, pam, boss) = (
+        UserID("jim".into()),
+        UserID("pam".into()),
+        UserID("boss".into()),
+    );
+    assert_eq!(clock_status(&d, &jim, Utc::now()).unwrap(), None);
+    clock_in(&mut d, &jim, None).ok().unwrap();
+    clock_in(&mut d, &pam, None).ok().unwrap();
+    start_break(&mut d, &jim, false).ok().unwrap();
+    let everyone = on_the_clock(&d, None, Utc::now()).unwrap();
+    assert_eq!(everyone.len(), 2);
+    let managed = on_the_clock(&d, Some(&boss), Utc::now()).unwrap();
+    assert_eq!(managed.len(), 1);
+    assert_eq!(managed[0].user, jim);
+    assert!(managed[0].on_break);
+    assert_eq!(
+        clock_status(&d, &jim, Utc::now()).unwrap().as_ref(),
+        managed.first()
+    );
+    clock_out(&mut d, &pam, None).ok().unwrap();
+    assert_eq!(on_the_clock(&d, None, Utc::now()).unwrap(), managed);
+}
+
+#[test]
+fn kiosks_check_pins_and_limit_attempts() {
+    let d = test_db(&["jim"]);
+    d.execute_batch(
+        "INSERT INTO users (user_id, password, service_account) VALUES ('bot', '!', 1);
+        INSERT INTO kiosks (name, token_hash, created_by, created_at)
+            VALUES ('Warehouse', 'x', 'boss', '2024-03-01T00:00:00Z');",
+    )
+    .unwrap();
+    assert!(valid_pin("0042"));
+    assert!(!valid_pin("123"));
+    assert!(!valid_pin("12a4"));
+    let (jim, bot) = (UserID("jim".into()), UserID("bot".into()));
+    assert_eq!(pin_hash(&d, &jim).unwrap(), None);
+    let hash = bcrypt::hash("1234", 4).unwrap();
+    set_pin(&d, &jim, &hash).unwrap();
+    set_pin(&d, &bot, &hash).unwrap();
+    let stored = pin_hash(&d, &jim).unwrap().unwrap();
+    assert!(bcrypt::verify("1234", &stored).unwrap());
+    // Separate from the password
+    let password: String = d
+        .query_row(
+            "SELECT password FROM users WHERE user_id = 'jim'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(password, "!");
+    // Service accounts can't punch on kiosks
+    assert_eq!(pin_hash(&d, &bot).unwrap(), None);
+    d.execute("UPDATE users SET active = 0 WHERE user_id = 'jim'", [])
+        .unwrap();
+    assert_eq!(pin_hash(&d, &jim).unwrap(), None);
+
+    record_kiosk_punch(
+        &d,
+        1,
+        "jim",
+        ClockType::TimeIn,
+        KioskOutcome::WrongPin,
+        None,
+    )
+    .unwrap();
+    record_kiosk_punch(
+        &d,
+        1,
+        "nobody",
+        ClockType::TimeOut,
+        KioskOutcome::LockedOut,
+        None,
+    )
+    .unwrap();
+    let punches = kiosk_punches(&d, Some(1), Some("jim")).unwrap();
+    assert_eq!(punches.len(), 1);
+    assert_eq!(punches[0].outcome, KioskOutcome::WrongPin);
+    assert!(matches!(punches[0].action, ClockType::TimeIn));
+    assert_eq!(kiosk_punches(&d, None, None).unwrap().len(), 2);
+    assert!(d
+        .execute("UPDATE kiosk_punches SET outcome = 'punched'", [])
+        .is_err());
+    assert!(d.execute("DELETE FROM kiosk_punches", []).is_err());
+
+    let time = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+    let limiter = RateLimiter::new(2, chrono::TimeDelta::minutes(15));
+    assert!(limiter.try_hit("jim", time("2024-03-04T09:00:00Z")));
+    assert!(limiter.try_hit("jim", time("2024-03-04T09:05:00Z")));
+    assert!(!limiter.try_hit("jim", time("2024-03-04T09:10:00Z")));
+    assert!(limiter.try_hit("pam", time("2024-03-04T09:10:00Z")));
+    // The first wrong PIN is old enough to not count anymore, refused tries never counted
+    assert!(limiter.try_hit("jim", time("2024-03-04T09:16:00Z")));
+    assert!(!limiter.try_hit("jim", time("2024-03-04T09:17:00Z")));
+    limiter.clear("jim");
+    assert!(limiter.try_hit("jim", time("2024-03-04T09:17:00Z")));
+}
+
+#[test]
+fn rounding_keeps_the_punches() {
+    let time = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+    let quarter = RoundingRule {
+        minutes: 15,
+        round_down_minutes: Some(7),
+        early_clock_in_minutes: 10,
+    };
+    let utc = chrono_tz::Tz::UTC;
+    // The 7 minute rule
+    assert_eq!(
+        quarter.round(time("2024-03-04T09:07:59Z"), utc),
+        time("2024-03-04T09:00:00Z")
+    );
+    assert_eq!(
+        quarter.round(time("2024-03-04T09:08:00Z"), utc),
+        time("2024-03-04T09:15:00Z")
+    );
+    // Marks are counted in local time, Kathmandu is 5:45 ahead
+    assert_eq!(
+        RoundingRule {
+            minutes: 30,
+            ..Default::default()
+        }
+        .round(time("2024-03-04T03:20:00Z"), chrono_tz::Asia::Kathmandu),
+        time("2024-03-04T03:15:00Z")
+    );
+
+    let d = test_db(&["jim", "dwight"]);
+    d.execute_batch(
+        "UPDATE users SET department = 'warehouse' WHERE user_id = 'dwight';
+        INSERT INTO shifts (user_id, start_time, end_time, created_by, published_at)
+            VALUES ('jim', '2024-03-04T09:00:00Z', '2024-03-04T17:00:00Z', 'boss',
+                '2024-03-01T00:00:00Z');",
+    )
+    .unwrap();
+    let (jim, dwight) = (UserID("jim".into()), UserID("dwight".into()));
+    let entries: Vec<TimeRange> = serde_json::from_value(serde_json::json!([
+        {"id": 1, "start": {"time": "2024-03-04T08:52:00Z", "note": null},
+            "end": {"time": "2024-03-04T17:08:00Z", "note": null},
+            "breaks": [{"id": 1, "start": "2024-03-04T12:03:00Z",
+                "end": "2024-03-04T12:29:00Z", "paid": false}]},
+        {"id": 2, "start": {"time": "2024-03-05T08:44:00Z", "note": null}, "end": null},
+    ]))
+    .unwrap();
+    let policy = RoundingPolicy {
+        rule: quarter,
+        departments: [(
+            "warehouse".to_string(),
+            RoundingRule {
+                minutes: 6,
+                ..Default::default()
+            },
+        )]
+        .into(),
+    };
+    let rounded = |user: &UserID| {
+        let rounded = rounded_entries(&d, user, &policy, &entries, utc).unwrap();
+        serde_json::to_value(rounded).unwrap()
+    };
+    let jims = rounded(&jim);
+    // Early to the shift, so it counts from 9:00
+    assert_eq!(jims[0]["start"]["time"], "2024-03-04T09:00:00Z");
+    assert_eq!(jims[0]["end"]["time"], "2024-03-04T17:15:00Z");
+    assert_eq!(jims[0]["breaks"][0]["start"], "2024-03-04T12:00:00Z");
+    assert_eq!(jims[0]["breaks"][0]["end"], "2024-03-04T12:30:00Z");
+    // No shift that day, and it's still open
+    assert_eq!(jims[1]["start"]["time"], "2024-03-05T08:45:00Z");
+    assert!(jims[1]["end"].is_null());
+    let dwights = rounded(&dwight);
+    assert_eq!(dwights[0]["start"]["time"], "2024-03-04T08:54:00Z");
+    assert_eq!(dwights[0]["end"]["time"], "2024-03-04T17:06:00Z");
+    // The punches themselves are left alone
+    let punched = serde_json::to_value(&entries).unwrap();
+    assert_eq!(punched[0]["start"]["time"], "2024-03-04T08:52:00Z");
+}
+
+#[test]
+fn overtime_splits_days_and_weeks() {
+    let date = |s: &str| s.parse::<NaiveDate>().unwrap();
+    let hours = |h: f64| (h * 3600.0) as i64;
+    let policy = OvertimePolicy {
+        holidays: vec![date("2024-03-10")],
+        holiday_rate: HolidayRate::DoubleTime,
+        ..Default::default()
+    };
+    // Ten hours every weekday, a long Saturday, then a bit on Sunday, a holiday
+    let mut days: std::collections::BTreeMap<_, _> = (4..=8)
+        .map(|day| (date(&format!("2024-03-0{day}")), hours(10.0)))
+        .collect();
+    days.insert(date("2024-03-09"), hours(13.0));
+    days.insert(date("2024-03-10"), hours(3.0));
+    days.insert(date("2024-03-11"), hours(5.0));
+    let split = |regular, overtime, double_time| PaySplit {
+        regular,
+        overtime,
+        double_time,
+    };
+    let splits = policy.split(&days);
+    assert_eq!(splits[&date("2024-03-04")], split(8.0, 2.0, 0.0));
+    // Past 40 regular hours for the week, and past 12 for the day
+    assert_eq!(splits[&date("2024-03-09")], split(0.0, 12.0, 1.0));
+    assert_eq!(splits[&date("2024-03-10")], split(0.0, 0.0, 3.0));
+    // A new week
+    assert_eq!(splits[&date("2024-03-11")], split(5.0, 0.0, 0.0));
+
+    let mut days: std::collections::BTreeMap<_, _> = (4..=7)
+        .map(|day| (date(&format!("2024-03-0{day}")), hours(8.0)))
+        .collect();
+    days.insert(date("2024-03-08"), hours(7.5));
+    assert_eq!(
+        policy.approaching(&days, date("2024-03-08")),
+        Some(Threshold::Daily)
+    );
+    days.insert(date("2024-03-08"), hours(6.0));
+    days.insert(date("2024-03-09"), hours(1.5));
+    assert_eq!(
+        policy.approaching(&days, date("2024-03-09")),
+        Some(Threshold::Weekly)
+    );
+    days.insert(date("2024-03-09"), hours(3.0));
+    assert_eq!(policy.approaching(&days, date("2024-03-09")), None);
+}
+
+/// Binds against glauth's sample config, e.g.
+#[test]
+fn ldap_falls_back_to_local_passwords() {
+    let config = LdapConfig {
+        url: "ldap://localhost:3893".into(),
+        bind_dn: "cn={username},ou=people,dc=example,dc=com".into(),
+        starttls: false,
+        timeout_secs: 5,
+    };
+    assert_eq!(
+        config.bind_dn_for("jim"),
+        "cn=jim,ou=people,dc=example,dc=com"
+    );
+    // Names can't add their own RDNs to the DN
+    assert_eq!(
+        config.bind_dn_for("jim,ou=admins"),
+        r"cn=jim\2cou\3dadmins,ou=people,dc=example,dc=com"
+    );
+
+    let d = test_db(&["jim"]);
+    let hash = bcrypt::hash("hunter2", 4).unwrap();
+    d.execute(
+        "INSERT INTO users (user_id, password) VALUES ('ops', ?)",
+        [&hash],
+    )
+    .unwrap();
+    assert_eq!(local_hash(&d, "ops").unwrap(), Some(hash));
+    // Users from the directory, the locked seeded admin and unknown names go to the directory
+    assert_eq!(local_hash(&d, "jim").unwrap(), None);
+    assert_eq!(local_hash(&d, "admin").unwrap(), None);
+    assert_eq!(local_hash(&d, "nobody").unwrap(), None);
+    d.execute("UPDATE users SET active = 0 WHERE user_id = 'ops'", [])
+        .unwrap();
+    assert_eq!(local_hash(&d, "ops").unwrap(), None);
+}
+
+/// `docker run -p 3893:3893 glauth/glauth` then `cargo test -- --ignored ldap`
+#[test]
+#[ignore = "needs a local LDAP server"]
+fn ldap_bind() {
+    let url = std::env::var("LDAP_TEST_URL").unwrap_or("ldap://localhost:3893".into());
+    let backend = LdapBackend::new(LdapConfig {
+        url,
+        bind_dn: "cn={username},ou=superheros,dc=glauth,dc=com".into(),
+        starttls: false,
+        timeout_secs: 5,
+    });
+    let (handle, _rt) = get_runtime_handle();
+    assert!(handle.block_on(backend.bind("hackers", "dogood")).unwrap());
+    assert!(!handle.block_on(backend.bind("hackers", "wrong")).unwrap());
+    assert!(!handle.block_on(backend.bind("hackers", "")).unwrap());
+}
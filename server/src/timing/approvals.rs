@@ -0,0 +1,472 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use rocket::{http::Status, serde::json::Json, State};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{notify, require_can_view, TimeConfig};
+use crate::{
+    auth::{is_admin, require_self_or_admin, tokens::Scope, Admin, Jwt},
+    profiles::user_timezone,
+    types::{UserDB, UserID},
+    users::SYSTEM_USER,
+    SqliteDB,
+};
+
+#[derive(Serialize, Deserialize, FromFormField, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalStatus {
+    Submitted,
+    Approved,
+    Rejected,
+    /// Taken back after the fact, only leave can be
+    Cancelled,
+}
+
+impl ApprovalStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApprovalStatus::Submitted => "submitted",
+            ApprovalStatus::Approved => "approved",
+            ApprovalStatus::Rejected => "rejected",
+            ApprovalStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(status: &str) -> Self {
+        match status {
+            "approved" => ApprovalStatus::Approved,
+            "rejected" => ApprovalStatus::Rejected,
+            "cancelled" => ApprovalStatus::Cancelled,
+            _ => ApprovalStatus::Submitted,
+        }
+    }
+}
+
+/// A pay period a user submitted for approval
+#[derive(Serialize, Debug, Clone)]
+pub struct Approval {
+    pub user: UserID,
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    pub status: ApprovalStatus,
+    pub submitted_at: DateTime<Utc>,
+    pub comment: Option<String>,
+    pub reviewed_by: Option<UserID>,
+    pub reviewed_at: Option<DateTime<Utc>>,
+    pub review_comment: Option<String>,
+}
+
+const SELECT_APPROVALS: &str = "SELECT user_id, period_start, period_end, status, \
+    submitted_at, comment, reviewed_by, reviewed_at, review_comment FROM timesheet_approvals";
+
+fn approval_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Approval> {
+    Ok(Approval {
+        user: row.get(0)?,
+        period_start: row.get(1)?,
+        period_end: row.get(2)?,
+        status: ApprovalStatus::parse(&row.get::<_, String>(3)?),
+        submitted_at: row.get(4)?,
+        comment: row.get(5)?,
+        reviewed_by: row.get(6)?,
+        reviewed_at: row.get(7)?,
+        review_comment: row.get(8)?,
+    })
+}
+
+fn get_approval(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    period_start: NaiveDate,
+) -> rusqlite::Result<Option<Approval>> {
+    d.query_row(
+        &format!("{SELECT_APPROVALS} WHERE user_id = ? AND period_start = ?"),
+        params![user.0, period_start],
+        approval_from_row,
+    )
+    .optional()
+}
+
+/// Whether `manager` is one of `user`'s managers
+pub fn manages(
+    d: &rusqlite::Connection,
+    manager: &UserID,
+    user: &UserID,
+) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM user_managers WHERE manager_id = ? AND user_id = ?)",
+        params![manager.0, user.0],
+        |r| r.get(0),
+    )
+}
+
+pub fn managers_of(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<Vec<UserID>> {
+    d.prepare("SELECT manager_id FROM user_managers WHERE user_id = ? ORDER BY manager_id")?
+        .query_map(params![user.0], |r| r.get(0))?
+        .collect()
+}
+
+/// Whether `date` falls in a pay period of `user` that can't be changed anymore, because
+/// their timesheet was approved or an admin closed it for everyone
+pub fn period_locked(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    date: NaiveDate,
+) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM timesheet_approvals WHERE user_id = ?1 \
+        AND status = 'approved' AND period_start <= ?2 AND period_end >= ?2) \
+        OR EXISTS (SELECT 1 FROM closed_periods WHERE period_start <= ?2 AND period_end >= ?2)",
+        params![user.0, date],
+        |r| r.get(0),
+    )
+}
+
+/// Only a user's managers and admins get to review their timesheets, and never their own
+pub async fn require_reviewer(
+    reviewer: &UserID,
+    user: &UserID,
+    db: &SqliteDB,
+) -> Result<(), Status> {
+    if reviewer == user {
+        return Err(Status::Forbidden);
+    }
+    let (id, uid) = (reviewer.clone(), user.clone());
+    let manager = db.run(move |d| manages(d, &id, &uid)).await.map_err(|e| {
+        log::error!("Failed to check managers: {e}");
+        Status::InternalServerError
+    })?;
+    if manager || is_admin(db, reviewer).await {
+        Ok(())
+    } else {
+        Err(Status::Forbidden)
+    }
+}
+
+pub enum ReviewError {
+    /// There's no submitted timesheet for that period
+    NotSubmitted,
+    /// The user is still clocked in to an entry in the period
+    ClockedIn,
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for ReviewError {
+    fn from(e: rusqlite::Error) -> Self {
+        ReviewError::Db(e)
+    }
+}
+
+impl std::fmt::Display for ReviewError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReviewError::NotSubmitted => f.write_str("There's no submitted timesheet to review"),
+            ReviewError::ClockedIn => f.write_str("They are still clocked in for that period"),
+            ReviewError::Db(_) => f.write_str("Failed to review the timesheet"),
+        }
+    }
+}
+
+/// Approves or rejects a submitted timesheet. Permissions have to be checked beforehand.
+pub fn review(
+    d: &mut rusqlite::Connection,
+    reviewer: &UserID,
+    user: &UserID,
+    period_start: NaiveDate,
+    status: ApprovalStatus,
+    comment: Option<&str>,
+) -> Result<Approval, ReviewError> {
+    let tx = d.transaction()?;
+    let Some(approval) = get_approval(&tx, user, period_start)? else {
+        return Err(ReviewError::NotSubmitted);
+    };
+    if approval.status != ApprovalStatus::Submitted {
+        return Err(ReviewError::NotSubmitted);
+    }
+    if status == ApprovalStatus::Approved {
+        let tz = user_timezone(&tx, user)?.unwrap_or(Tz::UTC);
+        let open_since: Option<DateTime<Utc>> = tx
+            .query_row(
+                "SELECT te.start_time FROM timesheets ts \
+                INNER JOIN time_entries te ON te.time_entry_id = ts.current_id \
+                WHERE ts.user_id = ? AND ts.clocked_in = 1 AND te.end_time IS NULL",
+                params![user.0],
+                |r| r.get(0),
+            )
+            .optional()?;
+        let open_date = open_since.map(|start| start.with_timezone(&tz).date_naive());
+        if open_date.is_some_and(|date| date <= approval.period_end) {
+            return Err(ReviewError::ClockedIn);
+        }
+    }
+    tx.execute(
+        "UPDATE timesheet_approvals SET status = ?, reviewed_by = ?, reviewed_at = ?, \
+        review_comment = ? WHERE user_id = ? AND period_start = ?",
+        params![
+            status.as_str(),
+            reviewer.0,
+            Utc::now(),
+            comment,
+            user.0,
+            period_start
+        ],
+    )?;
+    let approval = get_approval(&tx, user, period_start)?.ok_or(ReviewError::NotSubmitted)?;
+    tx.commit()?;
+    Ok(approval)
+}
+
+/// The oldest timesheet of `user` that's waiting for a review
+pub fn oldest_submitted(
+    d: &rusqlite::Connection,
+    user: &UserID,
+) -> rusqlite::Result<Option<NaiveDate>> {
+    d.query_row(
+        "SELECT period_start FROM timesheet_approvals WHERE user_id = ? AND status = 'submitted' \
+        ORDER BY period_start LIMIT 1",
+        params![user.0],
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+/// Tells a user their timesheet got reviewed
+pub async fn notify_reviewed(db: &SqliteDB, user_db: &UserDB, approval: &Approval) {
+    let reviewer = approval
+        .reviewed_by
+        .clone()
+        .unwrap_or_else(|| SYSTEM_USER.into());
+    let mut content = format!(
+        "{reviewer} {} your timesheet for {} to {}",
+        approval.status.as_str(),
+        approval.period_start,
+        approval.period_end
+    );
+    if let Some(comment) = &approval.review_comment {
+        content += &format!(": {comment}");
+    }
+    notify(db, user_db, vec![approval.user.clone()], content).await;
+}
+
+#[get("/<id>/managers")]
+pub async fn list_managers(
+    id: UserID,
+    user: Jwt,
+    db: SqliteDB,
+) -> Result<Json<Vec<UserID>>, Status> {
+    user.require(Scope::TimeRead)?;
+    require_self_or_admin(&user, &id, &db).await?;
+    db.run(move |d| managers_of(d, &id))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!("Failed to list managers: {e}");
+            Status::InternalServerError
+        })
+}
+
+#[put("/<id>/managers/<manager>")]
+pub async fn add_manager(id: UserID, manager: UserID, _admin: Admin, db: SqliteDB) -> Status {
+    if id == manager {
+        return Status::BadRequest;
+    }
+    match db
+        .run(move |d| {
+            d.execute(
+                "INSERT OR IGNORE INTO user_managers (user_id, manager_id) \
+                SELECT u.user_id, m.user_id FROM users u, users m \
+                WHERE u.user_id = ? AND m.user_id = ?",
+                params![id.0, manager.0],
+            )?;
+            manages(d, &manager, &id)
+        })
+        .await
+    {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::NotFound,
+        Err(e) => {
+            log::error!("Failed to add manager: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+#[delete("/<id>/managers/<manager>")]
+pub async fn remove_manager(id: UserID, manager: UserID, _admin: Admin, db: SqliteDB) -> Status {
+    match db
+        .run(move |d| {
+            d.execute(
+                "DELETE FROM user_managers WHERE user_id = ? AND manager_id = ?",
+                params![id.0, manager.0],
+            )
+        })
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::Ok,
+        Err(e) => {
+            log::error!("Failed to remove manager: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+/// Timesheets of `user`, or without one your own plus those of everyone you manage (everyone
+/// for admins)
+#[get("/time/approvals?<user>&<status>")]
+pub async fn list_approvals(
+    auth: Jwt,
+    user: Option<String>,
+    status: Option<ApprovalStatus>,
+    db: SqliteDB,
+) -> Result<Json<Vec<Approval>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let status = status.map(ApprovalStatus::as_str);
+    let approvals = match user.map(UserID) {
+        Some(user) => {
+            require_can_view(&auth, &user, &db).await?;
+            db.run(move |d| {
+                d.prepare(&format!(
+                    "{SELECT_APPROVALS} WHERE user_id = ?1 AND (?2 IS NULL OR status = ?2) \
+                    ORDER BY period_start DESC"
+                ))?
+                .query_map(params![user.0, status], approval_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+        }
+        None => {
+            let admin = is_admin(&db, &auth.name).await;
+            let viewer = auth.name.clone();
+            db.run(move |d| {
+                d.prepare(&format!(
+                    "{SELECT_APPROVALS} WHERE (?1 OR user_id = ?2 OR user_id IN \
+                      (SELECT user_id FROM user_managers WHERE manager_id = ?2)) \
+                    AND (?3 IS NULL OR status = ?3) ORDER BY period_start DESC, user_id"
+                ))?
+                .query_map(params![admin, viewer.0, status], approval_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+        }
+    };
+    approvals.map(Json).map_err(|e| {
+        log::error!("Failed to list timesheet approvals: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize)]
+pub struct Submission {
+    /// Any day in the pay period, defaults to today
+    date: Option<NaiveDate>,
+    comment: Option<String>,
+}
+
+/// Submits the authenticated user's timesheet for a pay period. Rejected timesheets can be
+/// submitted again.
+#[post("/time/approvals", data = "<submission>")]
+pub async fn submit_timesheet(
+    auth: Jwt,
+    submission: Json<Submission>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    config: &State<TimeConfig>,
+) -> Result<Json<Approval>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let Submission { date, comment } = submission.into_inner();
+    let config = config.inner().clone();
+    let user = auth.name.clone();
+    let (approval, managers) = db
+        .run(move |d| {
+            let tz = user_timezone(d, &user)?.unwrap_or(Tz::UTC);
+            let today = Utc::now().with_timezone(&tz).date_naive();
+            let (start, end) = config.pay_period(date.unwrap_or(today));
+            if start > today {
+                return Ok(Err(Status::BadRequest));
+            }
+            let changed = d.execute(
+                "INSERT INTO timesheet_approvals \
+                (user_id, period_start, period_end, status, submitted_at, comment) \
+                VALUES (?1, ?2, ?3, 'submitted', ?4, ?5) \
+                ON CONFLICT (user_id, period_start) DO UPDATE SET status = 'submitted', \
+                  submitted_at = ?4, comment = ?5, reviewed_by = NULL, reviewed_at = NULL, \
+                  review_comment = NULL \
+                WHERE status = 'rejected'",
+                params![user.0, start, end, Utc::now(), comment],
+            )?;
+            if changed == 0 {
+                return Ok(Err(Status::Conflict));
+            }
+            let approval =
+                get_approval(d, &user, start)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            Ok::<_, rusqlite::Error>(Ok((approval, managers_of(d, &user)?)))
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to submit timesheet: {e}");
+            Status::InternalServerError
+        })??;
+    let mut content = format!(
+        "{} submitted their timesheet for {} to {}",
+        approval.user, approval.period_start, approval.period_end
+    );
+    if let Some(comment) = &approval.comment {
+        content += &format!(": {comment}");
+    }
+    notify(&db, user_db, managers, content).await;
+    Ok(Json(approval))
+}
+
+#[derive(Deserialize)]
+pub struct Review {
+    pub status: ApprovalStatus,
+    pub comment: Option<String>,
+}
+
+/// Approves or rejects the timesheet of `user` for the pay period containing `date`
+#[put("/time/approvals/<user>/<date>", data = "<decision>")]
+pub async fn review_timesheet(
+    auth: Jwt,
+    user: UserID,
+    date: &str,
+    decision: Json<Review>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    config: &State<TimeConfig>,
+) -> Result<Json<Approval>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let date: NaiveDate = date.parse().map_err(|_| Status::BadRequest)?;
+    let Review { status, comment } = decision.into_inner();
+    if matches!(
+        status,
+        ApprovalStatus::Submitted | ApprovalStatus::Cancelled
+    ) {
+        return Err(Status::BadRequest);
+    }
+    require_reviewer(&auth.name, &user, &db).await?;
+    let (period_start, _) = config.pay_period(date);
+    let reviewer = auth.name.clone();
+    let approval = db
+        .run(move |d| {
+            review(
+                d,
+                &reviewer,
+                &user,
+                period_start,
+                status,
+                comment.as_deref(),
+            )
+        })
+        .await
+        .map_err(|e| match e {
+            ReviewError::NotSubmitted => Status::NotFound,
+            ReviewError::ClockedIn => Status::Conflict,
+            ReviewError::Db(e) => {
+                log::error!("Failed to review timesheet: {e}");
+                Status::InternalServerError
+            }
+        })?;
+    notify_reviewed(&db, user_db, &approval).await;
+    Ok(Json(approval))
+}
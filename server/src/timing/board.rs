@@ -0,0 +1,188 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{approvals::managers_of, get_entry, projects::project_names};
+use crate::{
+    auth::{is_admin, tokens::Scope, Jwt},
+    profiles::{broadcast_profile, get_profile},
+    types::{ServerAction, UserDB, UserID},
+    SqliteDB,
+};
+
+/// Someone who's clocked in right now
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct OnTheClock {
+    pub user: UserID,
+    /// The open time entry
+    pub entry: i64,
+    pub since: DateTime<Utc>,
+    pub project: Option<i64>,
+    pub project_name: Option<String>,
+    pub task: Option<String>,
+    pub on_break: bool,
+    /// Hours worked so far, without unpaid breaks
+    pub hours: f64,
+}
+
+fn on_the_clock_entry(
+    d: &rusqlite::Connection,
+    entry: i64,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<OnTheClock> {
+    let (user, entry) = get_entry(d, entry)?;
+    let project_name = match entry.project {
+        Some(project) => project_names(d)?.remove(&project),
+        None => None,
+    };
+    Ok(OnTheClock {
+        user,
+        entry: entry.id,
+        since: entry.start.time,
+        project: entry.project,
+        project_name,
+        task: entry.task.clone(),
+        on_break: entry.breaks.iter().any(|pause| pause.end.is_none()),
+        hours: entry
+            .worked(now)
+            .iter()
+            .map(|(start, end)| (*end - *start).num_seconds())
+            .sum::<i64>() as f64
+            / 3600.0,
+    })
+}
+
+/// Everyone clocked in, or just the people `manager` manages
+pub fn on_the_clock(
+    d: &rusqlite::Connection,
+    manager: Option<&UserID>,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Vec<OnTheClock>> {
+    let entries: Vec<i64> = d
+        .prepare(
+            "SELECT current_id FROM timesheets WHERE clocked_in = 1 AND current_id IS NOT NULL \
+            AND (?1 IS NULL OR user_id IN \
+              (SELECT user_id FROM user_managers WHERE manager_id = ?1)) \
+            ORDER BY user_id",
+        )?
+        .query_map(params![manager.map(|m| &m.0)], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    entries
+        .into_iter()
+        .map(|entry| on_the_clock_entry(d, entry, now))
+        .collect()
+}
+
+/// Where `user` is on the board, `None` if they're clocked out
+pub fn clock_status(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Option<OnTheClock>> {
+    let entry: Option<i64> = d
+        .query_row(
+            "SELECT current_id FROM timesheets \
+            WHERE user_id = ? AND clocked_in = 1 AND current_id IS NOT NULL",
+            params![user.0],
+            |r| r.get(0),
+        )
+        .optional()?;
+    entry
+        .map(|entry| on_the_clock_entry(d, entry, now))
+        .transpose()
+}
+
+/// Who `viewer` gets to see on the board: `None` for everyone, `Some` for just the people
+/// they manage. Fails for people who manage nobody.
+pub async fn board_scope(viewer: &UserID, db: &SqliteDB) -> Result<Option<UserID>, Status> {
+    if is_admin(db, viewer).await {
+        return Ok(None);
+    }
+    let id = viewer.clone();
+    let manager: bool = db
+        .run(move |d| {
+            d.query_row(
+                "SELECT EXISTS (SELECT 1 FROM user_managers WHERE manager_id = ?)",
+                params![id.0],
+                |r| r.get(0),
+            )
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to check managers: {e}");
+            Status::InternalServerError
+        })?;
+    if manager {
+        Ok(Some(viewer.clone()))
+    } else {
+        Err(Status::Forbidden)
+    }
+}
+
+/// Everyone on the clock the authenticated user manages, or everyone for admins
+#[get("/time/board")]
+pub async fn time_board(auth: Jwt, db: SqliteDB) -> Result<Json<Vec<OnTheClock>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let manager = board_scope(&auth.name, &db).await?;
+    db.run(move |d| on_the_clock(d, manager.as_ref(), Utc::now()))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!("Failed to get who's on the clock: {e}");
+            Status::InternalServerError
+        })
+}
+
+/// Tells the board watchers allowed to see `user`, and everyone sharing a room with them,
+/// that they punched
+pub async fn clock_changed(db: &SqliteDB, user_db: &UserDB, user: &UserID) {
+    let id = user.clone();
+    let found = db
+        .run(move |d| {
+            let profile = get_profile(d, &id)?;
+            let status = clock_status(d, &id, Utc::now())?;
+            Ok::<_, rusqlite::Error>((profile, status, managers_of(d, &id)?))
+        })
+        .await;
+    let (profile, entry, managers) = match found {
+        Ok(found) => found,
+        Err(e) => {
+            log::error!("Failed to get clock status of {user}: {e}");
+            return;
+        }
+    };
+    if let Some(profile) = profile {
+        broadcast_profile(db, user_db, profile.into()).await;
+    }
+    let mut watchers = Vec::new();
+    for watcher in user_db.board_watchers().await {
+        if managers.contains(&watcher) || is_admin(db, &watcher).await {
+            watchers.push(watcher);
+        }
+    }
+    let update = ServerAction::BoardUpdate {
+        user: user.clone(),
+        entry,
+    };
+    user_db.send_to(update, &watchers).await;
+}
+
+/// Starts sending `user` board updates, beginning with who's on the clock now
+pub async fn watch_board(db: &SqliteDB, user_db: &UserDB, user: &UserID) -> Result<(), Status> {
+    let manager = board_scope(user, db).await?;
+    let board = db
+        .run(move |d| on_the_clock(d, manager.as_ref(), Utc::now()))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get who's on the clock: {e}");
+            Status::InternalServerError
+        })?;
+    if let Some(watcher) = user_db.write().await.get_mut(user) {
+        watcher.watching_board = true;
+    }
+    user_db
+        .send_to(ServerAction::Board(board), std::slice::from_ref(user))
+        .await;
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    check_unlocked, current_entry, get_entry, record_audit, AuditAction, PunchError, TimeRange,
+};
+use crate::types::UserID;
+
+/// A break taken during a time entry
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Break {
+    pub id: i64,
+    pub start: DateTime<Utc>,
+    /// `None` while still on the break
+    pub end: Option<DateTime<Utc>>,
+    /// Paid breaks count as time worked
+    pub paid: bool,
+}
+
+fn break_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<(i64, Break)> {
+    Ok((
+        row.get(0)?,
+        Break {
+            id: row.get(1)?,
+            start: row.get(2)?,
+            end: row.get(3)?,
+            paid: row.get(4)?,
+        },
+    ))
+}
+
+/// Fills in the breaks of `entries`, which all belong to `user`
+pub fn attach_breaks(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    entries: &mut [TimeRange],
+) -> rusqlite::Result<()> {
+    let mut breaks: HashMap<i64, Vec<Break>> = HashMap::new();
+    for row in d
+        .prepare(
+            "SELECT b.time_entry_id, b.break_id, b.start_time, b.end_time, b.paid \
+            FROM time_breaks b INNER JOIN time_entries te ON te.time_entry_id = b.time_entry_id \
+            INNER JOIN timesheets ts ON ts.timesheet_id = te.timesheet_id \
+            WHERE ts.user_id = ? ORDER BY b.start_time",
+        )?
+        .query_map(params![user.0], break_from_row)?
+    {
+        let (entry, pause) = row?;
+        breaks.entry(entry).or_default().push(pause);
+    }
+    for entry in entries {
+        entry.breaks = breaks.remove(&entry.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Breaks taken during the time entry `entry`
+pub fn breaks_of(d: &rusqlite::Connection, entry: i64) -> rusqlite::Result<Vec<Break>> {
+    d.prepare(
+        "SELECT time_entry_id, break_id, start_time, end_time, paid FROM time_breaks \
+        WHERE time_entry_id = ? ORDER BY start_time",
+    )?
+    .query_map(params![entry], |row| Ok(break_from_row(row)?.1))?
+    .collect()
+}
+
+fn open_break(d: &rusqlite::Connection, entry: i64) -> rusqlite::Result<Option<i64>> {
+    d.query_row(
+        "SELECT break_id FROM time_breaks WHERE time_entry_id = ? AND end_time IS NULL",
+        params![entry],
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+/// Ends any break still open during `entry` at `time`, or when it started if that's later
+pub fn end_open_breaks(
+    d: &rusqlite::Connection,
+    entry: i64,
+    time: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    d.execute(
+        "UPDATE time_breaks SET end_time = MAX(start_time, ?) \
+        WHERE time_entry_id = ? AND end_time IS NULL",
+        params![time, entry],
+    )?;
+    Ok(())
+}
+
+/// Starts a break during the entry `user` is clocked in to, returning its id
+pub fn start_break(
+    d: &mut rusqlite::Connection,
+    user: &UserID,
+    paid: bool,
+) -> Result<i64, PunchError> {
+    let tx = d.transaction()?;
+    let (_, current) = current_entry(&tx, user)?;
+    let Some(entry) = current else {
+        tx.commit()?;
+        return Err(PunchError::NotClockedIn);
+    };
+    if open_break(&tx, entry)?.is_some() {
+        return Err(PunchError::OnBreak);
+    }
+    let now = Utc::now();
+    check_unlocked(&tx, user, now)?;
+    let (_, before) = get_entry(&tx, entry)?;
+    tx.execute(
+        "INSERT INTO time_breaks (time_entry_id, start_time, paid) VALUES (?, ?, ?)",
+        params![entry, now, paid],
+    )?;
+    let id = tx.last_insert_rowid();
+    let (_, after) = get_entry(&tx, entry)?;
+    record_audit(
+        &tx,
+        user,
+        AuditAction::BreakStart,
+        user,
+        None,
+        Some(&before),
+        Some(&after),
+    )?;
+    tx.commit()?;
+    Ok(id)
+}
+
+/// Ends the break `user` is on, returning its id
+pub fn end_break(d: &mut rusqlite::Connection, user: &UserID) -> Result<i64, PunchError> {
+    let tx = d.transaction()?;
+    let (_, current) = current_entry(&tx, user)?;
+    let Some(entry) = current else {
+        tx.commit()?;
+        return Err(PunchError::NotClockedIn);
+    };
+    let Some(id) = open_break(&tx, entry)? else {
+        return Err(PunchError::NotOnBreak);
+    };
+    let now = Utc::now();
+    check_unlocked(&tx, user, now)?;
+    let (_, before) = get_entry(&tx, entry)?;
+    tx.execute(
+        "UPDATE time_breaks SET end_time = ? WHERE break_id = ?",
+        params![now, id],
+    )?;
+    let (_, after) = get_entry(&tx, entry)?;
+    record_audit(
+        &tx,
+        user,
+        AuditAction::BreakEnd,
+        user,
+        None,
+        Some(&before),
+        Some(&after),
+    )?;
+    tx.commit()?;
+    Ok(id)
+}
+
+/// Whether `user` is on a break during the entry they're clocked in to
+pub fn is_on_break(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM timesheets ts \
+        INNER JOIN time_breaks b ON b.time_entry_id = ts.current_id \
+        WHERE ts.user_id = ? AND ts.clocked_in = 1 AND b.end_time IS NULL)",
+        params![user.0],
+        |r| r.get(0),
+    )
+}
@@ -0,0 +1,632 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, State};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    approvals::{managers_of, require_reviewer, ApprovalStatus, Review},
+    board::clock_changed,
+    current_entry, get_entry, locked_at, notify, require_can_view, TimeRange, Timestamp,
+};
+use crate::{
+    auth::{is_admin, tokens::Scope, Jwt},
+    types::{UserDB, UserID},
+    SqliteDB,
+};
+
+/// What happened to a time entry
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    ClockIn,
+    ClockOut,
+    /// Closed by the server after `auto_clock_out_hours`
+    AutoClockOut,
+    /// A break started or ended during the entry, at `changed_at`
+    BreakStart,
+    BreakEnd,
+    Add,
+    Edit,
+    Delete,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::ClockIn => "clock_in",
+            AuditAction::ClockOut => "clock_out",
+            AuditAction::AutoClockOut => "auto_clock_out",
+            AuditAction::BreakStart => "break_start",
+            AuditAction::BreakEnd => "break_end",
+            AuditAction::Add => "add",
+            AuditAction::Edit => "edit",
+            AuditAction::Delete => "delete",
+        }
+    }
+
+    fn parse(action: &str) -> Self {
+        match action {
+            "clock_in" => AuditAction::ClockIn,
+            "clock_out" => AuditAction::ClockOut,
+            "auto_clock_out" => AuditAction::AutoClockOut,
+            "break_start" => AuditAction::BreakStart,
+            "break_end" => AuditAction::BreakEnd,
+            "add" => AuditAction::Add,
+            "delete" => AuditAction::Delete,
+            _ => AuditAction::Edit,
+        }
+    }
+}
+
+/// Records a change to one of `user`'s time entries. `before` is `None` for new entries and
+/// `after` is `None` for deleted ones.
+pub fn record_audit(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    action: AuditAction,
+    changed_by: &UserID,
+    correction: Option<i64>,
+    before: Option<&TimeRange>,
+    after: Option<&TimeRange>,
+) -> rusqlite::Result<()> {
+    let Some(entry) = after.or(before).map(|entry| entry.id) else {
+        return Ok(());
+    };
+    let start = |range: Option<&TimeRange>| range.map(|r| (r.start.time, r.start.note.clone()));
+    let end = |range: Option<&TimeRange>| {
+        range
+            .and_then(|r| r.end.as_ref())
+            .map(|end| (end.time, end.note.clone()))
+    };
+    let (old_start, old_end) = (start(before).unzip(), end(before).unzip());
+    let (new_start, new_end) = (start(after).unzip(), end(after).unzip());
+    d.execute(
+        "INSERT INTO time_entry_audit (time_entry_id, user_id, action, changed_by, changed_at, \
+        correction_id, old_start_time, old_start_note, old_end_time, old_end_note, \
+        new_start_time, new_start_note, new_end_time, new_end_note) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            entry,
+            user.0,
+            action.as_str(),
+            changed_by.0,
+            Utc::now(),
+            correction,
+            old_start.0,
+            old_start.1.flatten(),
+            old_end.0,
+            old_end.1.flatten(),
+            new_start.0,
+            new_start.1.flatten(),
+            new_end.0,
+            new_end.1.flatten(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// A recorded change to a time entry
+#[derive(Serialize, Clone)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub entry: i64,
+    pub user: UserID,
+    pub action: AuditAction,
+    pub changed_by: UserID,
+    pub changed_at: DateTime<Utc>,
+    /// The correction that made the change, `None` for punches
+    pub correction: Option<i64>,
+    pub before: Option<TimeRange>,
+    pub after: Option<TimeRange>,
+}
+
+fn audit_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<AuditEntry> {
+    let entry: i64 = row.get(1)?;
+    // Columns `from` to `from + 3` hold the start and end of one side of the change
+    let range = |from: usize| -> rusqlite::Result<Option<TimeRange>> {
+        let Some(start) = row.get::<_, Option<DateTime<Utc>>>(from)? else {
+            return Ok(None);
+        };
+        let end: Option<DateTime<Utc>> = row.get(from + 2)?;
+        let end_note = row.get(from + 3)?;
+        Ok(Some(TimeRange {
+            id: entry,
+            start: Timestamp {
+                time: start,
+                note: row.get(from + 1)?,
+            },
+            end: end.map(|time| Timestamp {
+                time,
+                note: end_note,
+            }),
+            flagged: false,
+            breaks: Vec::new(),
+            project: None,
+            task: None,
+        }))
+    };
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        entry,
+        user: row.get(2)?,
+        action: AuditAction::parse(&row.get::<_, String>(3)?),
+        changed_by: row.get(4)?,
+        changed_at: row.get(5)?,
+        correction: row.get(6)?,
+        before: range(7)?,
+        after: range(11)?,
+    })
+}
+
+/// Every recorded change to the entries of `user`, oldest first
+pub fn audit_log(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    entry: Option<i64>,
+) -> rusqlite::Result<Vec<AuditEntry>> {
+    d.prepare(
+        "SELECT audit_id, time_entry_id, user_id, action, changed_by, changed_at, \
+        correction_id, old_start_time, old_start_note, old_end_time, old_end_note, \
+        new_start_time, new_start_note, new_end_time, new_end_note FROM time_entry_audit \
+        WHERE user_id = ?1 AND (?2 IS NULL OR time_entry_id = ?2) ORDER BY audit_id",
+    )?
+    .query_map(params![user.0, entry], audit_from_row)?
+    .collect()
+}
+
+/// A change someone wants made to their time
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Change {
+    Add {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    Edit {
+        entry: i64,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    Delete {
+        entry: i64,
+    },
+}
+
+impl Change {
+    fn kind(&self) -> &'static str {
+        match self {
+            Change::Add { .. } => "add",
+            Change::Edit { .. } => "edit",
+            Change::Delete { .. } => "delete",
+        }
+    }
+
+    fn entry(&self) -> Option<i64> {
+        match self {
+            Change::Add { .. } => None,
+            Change::Edit { entry, .. } | Change::Delete { entry } => Some(*entry),
+        }
+    }
+
+    fn range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        match *self {
+            Change::Add { start, end } | Change::Edit { start, end, .. } => Some((start, end)),
+            Change::Delete { .. } => None,
+        }
+    }
+}
+
+/// A correction a user asked for and what became of it
+#[derive(Serialize, Debug, Clone)]
+pub struct Correction {
+    pub id: i64,
+    pub user: UserID,
+    #[serde(flatten)]
+    pub change: Change,
+    pub reason: String,
+    pub status: ApprovalStatus,
+    pub requested_at: DateTime<Utc>,
+    pub reviewed_by: Option<UserID>,
+    pub reviewed_at: Option<DateTime<Utc>>,
+    pub review_comment: Option<String>,
+}
+
+const SELECT_CORRECTIONS: &str = "SELECT correction_id, user_id, kind, time_entry_id, \
+    start_time, end_time, reason, status, requested_at, reviewed_by, reviewed_at, \
+    review_comment FROM time_corrections";
+
+fn correction_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Correction> {
+    let kind: String = row.get(2)?;
+    let entry: Option<i64> = row.get(3)?;
+    let range: Option<(DateTime<Utc>, DateTime<Utc>)> =
+        match (row.get::<_, Option<_>>(4)?, row.get::<_, Option<_>>(5)?) {
+            (Some(start), Some(end)) => Some((start, end)),
+            _ => None,
+        };
+    let change = match (kind.as_str(), entry, range) {
+        ("add", _, Some((start, end))) => Change::Add { start, end },
+        ("edit", Some(entry), Some((start, end))) => Change::Edit { entry, start, end },
+        ("delete", Some(entry), _) => Change::Delete { entry },
+        _ => {
+            return Err(rusqlite::Error::InvalidColumnType(
+                2,
+                "kind".into(),
+                rusqlite::types::Type::Text,
+            ))
+        }
+    };
+    Ok(Correction {
+        id: row.get(0)?,
+        user: row.get(1)?,
+        change,
+        reason: row.get(6)?,
+        status: ApprovalStatus::parse(&row.get::<_, String>(7)?),
+        requested_at: row.get(8)?,
+        reviewed_by: row.get(9)?,
+        reviewed_at: row.get(10)?,
+        review_comment: row.get(11)?,
+    })
+}
+
+fn get_correction(d: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<Correction>> {
+    d.query_row(
+        &format!("{SELECT_CORRECTIONS} WHERE correction_id = ?"),
+        params![id],
+        correction_from_row,
+    )
+    .optional()
+}
+
+pub enum CorrectionError {
+    /// The entry doesn't exist or isn't theirs
+    NotFound,
+    /// The entry would end before it starts, or in the future
+    Invalid,
+    /// The entry would overlap another one
+    Overlaps,
+    /// Part of the change falls in a locked pay period
+    Locked,
+    /// The correction was already reviewed
+    Reviewed,
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for CorrectionError {
+    fn from(e: rusqlite::Error) -> Self {
+        CorrectionError::Db(e)
+    }
+}
+
+impl std::fmt::Display for CorrectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CorrectionError::NotFound => f.write_str("That time entry doesn't exist"),
+            CorrectionError::Invalid => f.write_str("Entries have to end after they start"),
+            CorrectionError::Overlaps => f.write_str("That would overlap another entry"),
+            CorrectionError::Locked => f.write_str("This pay period is locked"),
+            CorrectionError::Reviewed => f.write_str("That correction was already reviewed"),
+            CorrectionError::Db(_) => f.write_str("Failed to correct the time entry"),
+        }
+    }
+}
+
+impl From<CorrectionError> for Status {
+    fn from(e: CorrectionError) -> Self {
+        match e {
+            CorrectionError::NotFound => Status::NotFound,
+            CorrectionError::Invalid => Status::BadRequest,
+            CorrectionError::Overlaps | CorrectionError::Reviewed => Status::Conflict,
+            CorrectionError::Locked => Status::Locked,
+            CorrectionError::Db(e) => {
+                log::error!("Failed to correct time entry: {e}");
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+/// Whether `change` can be made to the time of `user` right now. Returns the entry it
+/// changes, if any.
+fn check_change(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    change: &Change,
+) -> Result<Option<TimeRange>, CorrectionError> {
+    let before = match change.entry() {
+        Some(id) => match get_entry(d, id).optional()? {
+            Some((owner, entry)) if &owner == user => Some(entry),
+            _ => return Err(CorrectionError::NotFound),
+        },
+        None => None,
+    };
+    if let Some(entry) = &before {
+        let end = entry.end.as_ref().map_or_else(Utc::now, |end| end.time);
+        if locked_at(d, user, entry.start.time)? || locked_at(d, user, end)? {
+            return Err(CorrectionError::Locked);
+        }
+    }
+    if let Some((start, end)) = change.range() {
+        if start >= end || end > Utc::now() {
+            return Err(CorrectionError::Invalid);
+        }
+        if locked_at(d, user, start)? || locked_at(d, user, end)? {
+            return Err(CorrectionError::Locked);
+        }
+        let overlaps: bool = d.query_row(
+            "SELECT EXISTS (SELECT 1 FROM timesheets ts \
+            INNER JOIN time_entries te ON ts.timesheet_id = te.timesheet_id \
+            WHERE ts.user_id = ?1 AND te.time_entry_id IS NOT ?2 \
+            AND te.start_time < ?4 AND COALESCE(te.end_time, ?5) > ?3)",
+            params![user.0, change.entry(), start, end, Utc::now()],
+            |r| r.get(0),
+        )?;
+        if overlaps {
+            return Err(CorrectionError::Overlaps);
+        }
+    }
+    Ok(before)
+}
+
+/// Makes the change of an approved correction, recording it in the audit log
+fn apply(
+    tx: &rusqlite::Transaction<'_>,
+    reviewer: &UserID,
+    correction: &Correction,
+) -> Result<(), CorrectionError> {
+    let user = &correction.user;
+    let before = check_change(tx, user, &correction.change)?;
+    let (sheet, current) = current_entry(tx, user)?;
+    let action = match correction.change {
+        Change::Add { start, end } => {
+            tx.execute(
+                "INSERT INTO time_entries (timesheet_id, start_time, end_time) VALUES (?, ?, ?)",
+                params![sheet, start, end],
+            )?;
+            let (_, after) = get_entry(tx, tx.last_insert_rowid())?;
+            return Ok(record_audit(
+                tx,
+                user,
+                AuditAction::Add,
+                reviewer,
+                Some(correction.id),
+                None,
+                Some(&after),
+            )?);
+        }
+        Change::Edit { entry, start, end } => {
+            tx.execute(
+                "UPDATE time_entries SET start_time = ?, end_time = ?, flagged = 0 \
+                WHERE time_entry_id = ?",
+                params![start, end, entry],
+            )?;
+            AuditAction::Edit
+        }
+        Change::Delete { entry } => {
+            tx.execute(
+                "DELETE FROM time_breaks WHERE time_entry_id = ?",
+                params![entry],
+            )?;
+            tx.execute(
+                "DELETE FROM time_entries WHERE time_entry_id = ?",
+                params![entry],
+            )?;
+            AuditAction::Delete
+        }
+    };
+    let entry = correction.change.entry();
+    // Fixing the entry someone forgot to clock out of clocks them out
+    if current.is_some() && current == entry {
+        tx.execute(
+            "UPDATE timesheets SET clocked_in = 0, current_id = NULL WHERE timesheet_id = ?",
+            params![sheet],
+        )?;
+    }
+    let after = match entry {
+        Some(id) if action == AuditAction::Edit => Some(get_entry(tx, id)?.1),
+        _ => None,
+    };
+    record_audit(
+        tx,
+        user,
+        action,
+        reviewer,
+        Some(correction.id),
+        before.as_ref(),
+        after.as_ref(),
+    )?;
+    Ok(())
+}
+
+/// Approves or rejects a requested correction, making the change if it's approved.
+/// Permissions have to be checked beforehand.
+pub fn review_correction(
+    d: &mut rusqlite::Connection,
+    reviewer: &UserID,
+    id: i64,
+    status: ApprovalStatus,
+    comment: Option<&str>,
+) -> Result<Correction, CorrectionError> {
+    let tx = d.transaction()?;
+    let Some(correction) = get_correction(&tx, id)? else {
+        return Err(CorrectionError::NotFound);
+    };
+    if correction.status != ApprovalStatus::Submitted {
+        return Err(CorrectionError::Reviewed);
+    }
+    if status == ApprovalStatus::Approved {
+        apply(&tx, reviewer, &correction)?;
+    }
+    tx.execute(
+        "UPDATE time_corrections SET status = ?, reviewed_by = ?, reviewed_at = ?, \
+        review_comment = ? WHERE correction_id = ?",
+        params![status.as_str(), reviewer.0, Utc::now(), comment, id],
+    )?;
+    let correction = get_correction(&tx, id)?.ok_or(CorrectionError::NotFound)?;
+    tx.commit()?;
+    Ok(correction)
+}
+
+/// Corrections asked for by `user`, or without one your own plus those of everyone you
+/// manage (everyone for admins)
+#[get("/time/corrections?<user>&<status>")]
+pub async fn list_corrections(
+    auth: Jwt,
+    user: Option<String>,
+    status: Option<ApprovalStatus>,
+    db: SqliteDB,
+) -> Result<Json<Vec<Correction>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let status = status.map(ApprovalStatus::as_str);
+    let corrections = match user.map(UserID) {
+        Some(user) => {
+            require_can_view(&auth, &user, &db).await?;
+            db.run(move |d| {
+                d.prepare(&format!(
+                    "{SELECT_CORRECTIONS} WHERE user_id = ?1 AND (?2 IS NULL OR status = ?2) \
+                    ORDER BY requested_at DESC"
+                ))?
+                .query_map(params![user.0, status], correction_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+        }
+        None => {
+            let admin = is_admin(&db, &auth.name).await;
+            let viewer = auth.name.clone();
+            db.run(move |d| {
+                d.prepare(&format!(
+                    "{SELECT_CORRECTIONS} WHERE (?1 OR user_id = ?2 OR user_id IN \
+                      (SELECT user_id FROM user_managers WHERE manager_id = ?2)) \
+                    AND (?3 IS NULL OR status = ?3) ORDER BY requested_at DESC"
+                ))?
+                .query_map(params![admin, viewer.0, status], correction_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+        }
+    };
+    corrections.map(Json).map_err(|e| {
+        log::error!("Failed to list time corrections: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize)]
+pub struct CorrectionRequest {
+    #[serde(flatten)]
+    change: Change,
+    reason: String,
+}
+
+/// Asks the authenticated user's managers to add, edit or delete one of their time entries
+#[post("/time/corrections", data = "<request>")]
+pub async fn request_correction(
+    auth: Jwt,
+    request: Json<CorrectionRequest>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Json<Correction>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let CorrectionRequest { change, reason } = request.into_inner();
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let user = auth.name.clone();
+    let (correction, managers) = db
+        .run(move |d| {
+            check_change(d, &user, &change)?;
+            let (start, end) = change.range().unzip();
+            d.execute(
+                "INSERT INTO time_corrections (user_id, kind, time_entry_id, start_time, \
+                end_time, reason, status, requested_at) \
+                VALUES (?, ?, ?, ?, ?, ?, 'submitted', ?)",
+                params![
+                    user.0,
+                    change.kind(),
+                    change.entry(),
+                    start,
+                    end,
+                    reason,
+                    Utc::now()
+                ],
+            )?;
+            let correction = get_correction(d, d.last_insert_rowid())?
+                .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            Ok::<_, CorrectionError>((correction, managers_of(d, &user)?))
+        })
+        .await?;
+    let what = match correction.change.entry() {
+        Some(entry) => format!("to {} time entry {entry}", correction.change.kind()),
+        None => "to add a time entry".into(),
+    };
+    let content = format!("{} asked {what}: {}", correction.user, correction.reason);
+    notify(&db, user_db, managers, content).await;
+    Ok(Json(correction))
+}
+
+/// Approves or rejects a requested correction
+#[put("/time/corrections/<id>", data = "<decision>")]
+pub async fn review_time_correction(
+    auth: Jwt,
+    id: i64,
+    decision: Json<Review>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Json<Correction>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let Review { status, comment } = decision.into_inner();
+    if matches!(
+        status,
+        ApprovalStatus::Submitted | ApprovalStatus::Cancelled
+    ) {
+        return Err(Status::BadRequest);
+    }
+    let user = db
+        .run(move |d| get_correction(d, id))
+        .await
+        .map_err(CorrectionError::from)?
+        .ok_or(Status::NotFound)?
+        .user;
+    require_reviewer(&auth.name, &user, &db).await?;
+    let reviewer = auth.name.clone();
+    let correction = db
+        .run(move |d| review_correction(d, &reviewer, id, status, comment.as_deref()))
+        .await?;
+    let mut content = format!(
+        "{} {} your correction {}",
+        auth.name,
+        correction.status.as_str(),
+        correction.id
+    );
+    if let Some(comment) = &correction.review_comment {
+        content += &format!(": {comment}");
+    }
+    notify(&db, user_db, vec![correction.user.clone()], content).await;
+    // The correction might have closed or reopened the entry they're clocked in to
+    if correction.status == ApprovalStatus::Approved {
+        clock_changed(&db, user_db, &correction.user).await;
+    }
+    Ok(Json(correction))
+}
+
+/// Every change made to the time entries of `user` (defaults to the authenticated user),
+/// optionally just those of one `entry`
+#[get("/time/audit?<user>&<entry>")]
+pub async fn time_audit(
+    auth: Jwt,
+    user: Option<String>,
+    entry: Option<i64>,
+    db: SqliteDB,
+) -> Result<Json<Vec<AuditEntry>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let user = user.map_or_else(|| auth.name.clone(), UserID);
+    require_can_view(&auth, &user, &db).await?;
+    db.run(move |d| audit_log(d, &user, entry))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!("Failed to get time entry audit log: {e}");
+            Status::InternalServerError
+        })
+}
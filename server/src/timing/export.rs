@@ -0,0 +1,457 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use rocket::{
+    http::{ContentType, Header, Status},
+    State,
+};
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use serde::Serialize;
+
+use super::{
+    leave::{leave_days, with_company_holidays},
+    overtime::PaySplit,
+    projects::project_names,
+    report::{build_report, TimeReport},
+    require_can_view,
+    rounding::rounded_entries,
+    time_entries, NaiveDateForm, TimeConfig, TimeRange,
+};
+use crate::{
+    auth::{tokens::Scope, Jwt},
+    profiles::user_timezone,
+    types::UserID,
+    SqliteDB,
+};
+
+const ENTRIES_SHEET: &str = "Time entries";
+
+/// Start, end and hours are rounded, the punches are as they were made
+const HEADERS: [&str; 12] = [
+    "User",
+    "Entry",
+    "Date",
+    "Start",
+    "End",
+    "Hours",
+    "Start note",
+    "End note",
+    "Project",
+    "Task",
+    "Punched in",
+    "Punched out",
+];
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Xlsx,
+    Json,
+}
+
+/// Everything exported for a single user
+#[derive(Serialize)]
+pub struct TimesheetExport {
+    summary: TimeReport,
+    /// Entries started between the export's `start` and `end`, as punched
+    entries: Vec<TimeRange>,
+    /// `entries` rounded like in `summary`
+    #[serde(skip)]
+    rounded: Vec<TimeRange>,
+    #[serde(skip)]
+    tz: Tz,
+    /// Names of the projects entries are tagged with
+    #[serde(skip)]
+    projects: HashMap<i64, String>,
+}
+
+impl TimesheetExport {
+    /// Start and end of an entry in the user's timezone
+    fn local(&self, entry: &TimeRange) -> (NaiveDateTime, Option<NaiveDateTime>) {
+        let local = |time: &chrono::DateTime<Utc>| time.with_timezone(&self.tz).naive_local();
+        (
+            local(&entry.start.time),
+            entry.end.as_ref().map(|end| local(&end.time)),
+        )
+    }
+
+    fn project(&self, entry: &TimeRange) -> String {
+        entry
+            .project
+            .map(|id| self.projects.get(&id).cloned().unwrap_or(id.to_string()))
+            .unwrap_or_default()
+    }
+}
+
+/// Stops spreadsheet apps from running text a user typed, like a note, as a formula
+fn csv_text(text: &str) -> String {
+    if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{text}")
+    } else {
+        text.to_owned()
+    }
+}
+
+pub(crate) fn to_csv(sheets: &[TimesheetExport]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(HEADERS)?;
+    for sheet in sheets {
+        for (punched, entry) in sheet.entries.iter().zip(&sheet.rounded) {
+            let (start, end) = sheet.local(entry);
+            let (punched_in, punched_out) = sheet.local(punched);
+            let time = |t: NaiveDateTime| t.format("%Y-%m-%d %H:%M").to_string();
+            let end_note = entry.end.as_ref().and_then(|end| end.note.as_deref());
+            writer.write_record([
+                csv_text(&sheet.summary.user.0),
+                entry.id.to_string(),
+                start.date().to_string(),
+                time(start),
+                end.map(time).unwrap_or_default(),
+                format!("{:.2}", entry.hours()),
+                csv_text(entry.start.note.as_deref().unwrap_or_default()),
+                csv_text(end_note.unwrap_or_default()),
+                csv_text(&sheet.project(entry)),
+                csv_text(entry.task.as_deref().unwrap_or_default()),
+                time(punched_in),
+                punched_out.map(time).unwrap_or_default(),
+            ])?;
+        }
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Names of the summary sheets of `users`. Sheet names can't contain `[]:*?/\`, start or end
+/// with `'`, or be longer than 31 characters, and have to be unique ignoring case, so names
+/// that clash get numbered.
+fn sheet_names<'a>(users: impl IntoIterator<Item = &'a UserID>) -> Vec<String> {
+    let mut taken = HashSet::from([ENTRIES_SHEET.to_lowercase()]);
+    users
+        .into_iter()
+        .map(|user| {
+            let mut base: Vec<char> = user
+                .0
+                .chars()
+                .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+                .collect();
+            for i in [0, base.len().saturating_sub(1)] {
+                if base.get(i) == Some(&'\'') {
+                    base[i] = '_';
+                }
+            }
+            let mut name: String = base.iter().take(31).collect();
+            let mut n = 1;
+            while !taken.insert(name.to_lowercase()) {
+                n += 1;
+                let suffix = format!(" ({n})");
+                name = base.iter().take(31 - suffix.len()).collect::<String>() + &suffix;
+            }
+            name
+        })
+        .collect()
+}
+
+/// Regular, overtime and double time hours from `(row, col)`, down a column or along the row
+fn write_pay(
+    sheet: &mut Worksheet,
+    row: u32,
+    col: u16,
+    pay: &PaySplit,
+    format: &Format,
+    down: bool,
+) -> Result<(), XlsxError> {
+    for (i, hours) in [pay.regular, pay.overtime, pay.double_time]
+        .into_iter()
+        .enumerate()
+    {
+        let (row, col) = if down {
+            (row + i as u32, col)
+        } else {
+            (row, col + i as u16)
+        };
+        sheet.write_number_with_format(row, col, hours, format)?;
+    }
+    Ok(())
+}
+
+fn write_summary(sheet: &mut Worksheet, report: &TimeReport) -> Result<(), XlsxError> {
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    let hours = Format::new().set_num_format("0.00");
+    sheet.write_column_with_format(
+        0,
+        0,
+        [
+            "User",
+            "Timezone",
+            "From",
+            "To",
+            "Total hours",
+            "Regular",
+            "Overtime",
+            "Double time",
+            "Paid leave",
+        ],
+        &bold,
+    )?;
+    sheet.write_string(0, 1, &report.user.0)?;
+    sheet.write_string(1, 1, &report.timezone)?;
+    sheet.write_date_with_format(2, 1, report.start, &date)?;
+    sheet.write_date_with_format(3, 1, report.end, &date)?;
+    sheet.write_number_with_format(4, 1, report.total, &hours)?;
+    write_pay(sheet, 5, 1, &report.pay, &hours, true)?;
+    sheet.write_number_with_format(8, 1, report.paid_leave, &hours)?;
+
+    let mut row = 10;
+    sheet.write_row_with_format(
+        row,
+        0,
+        [
+            "Pay period start",
+            "Pay period end",
+            "Hours",
+            "Regular",
+            "Overtime",
+            "Double time",
+        ],
+        &bold,
+    )?;
+    for period in &report.pay_periods {
+        row += 1;
+        sheet.write_date_with_format(row, 0, period.start, &date)?;
+        sheet.write_date_with_format(row, 1, period.end, &date)?;
+        sheet.write_number_with_format(row, 2, period.hours, &hours)?;
+        write_pay(sheet, row, 3, &period.pay, &hours, false)?;
+    }
+    row += 2;
+    sheet.write_row_with_format(
+        row,
+        0,
+        [
+            "ISO year",
+            "Week",
+            "Hours",
+            "Regular",
+            "Overtime",
+            "Double time",
+        ],
+        &bold,
+    )?;
+    for week in &report.weeks {
+        row += 1;
+        sheet.write_number(row, 0, week.year)?;
+        sheet.write_number(row, 1, week.week)?;
+        sheet.write_number_with_format(row, 2, week.hours, &hours)?;
+        write_pay(sheet, row, 3, &week.pay, &hours, false)?;
+    }
+    row += 2;
+    sheet.write_row_with_format(row, 0, ["Project", "Hours"], &bold)?;
+    for project in &report.projects {
+        row += 1;
+        let name = match (&project.name, project.project) {
+            (Some(name), _) => name.clone(),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => "None".into(),
+        };
+        sheet.write_string(row, 0, name)?;
+        sheet.write_number_with_format(row, 1, project.hours, &hours)?;
+    }
+    if !report.leave.is_empty() {
+        row += 2;
+        sheet.write_row_with_format(row, 0, ["Leave date", "Kind", "Hours", "Paid"], &bold)?;
+        for day in &report.leave {
+            row += 1;
+            sheet.write_date_with_format(row, 0, day.date, &date)?;
+            sheet.write_string(row, 1, &day.kind)?;
+            sheet.write_number_with_format(row, 2, day.hours, &hours)?;
+            sheet.write_boolean(row, 3, day.paid)?;
+        }
+    }
+    row += 2;
+    sheet.write_row_with_format(
+        row,
+        0,
+        ["Date", "Hours", "Regular", "Overtime", "Double time"],
+        &bold,
+    )?;
+    for day in &report.days {
+        row += 1;
+        sheet.write_date_with_format(row, 0, day.date, &date)?;
+        sheet.write_number_with_format(row, 1, day.hours, &hours)?;
+        write_pay(sheet, row, 2, &day.pay, &hours, false)?;
+    }
+    sheet.autofit();
+    Ok(())
+}
+
+/// A sheet with every entry, then a summary sheet per user
+pub(crate) fn to_xlsx(sheets: &[TimesheetExport]) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let bold = Format::new().set_bold();
+    let datetime = Format::new().set_num_format("yyyy-mm-dd hh:mm");
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    let hours = Format::new().set_num_format("0.00");
+
+    let entries = workbook.add_worksheet().set_name(ENTRIES_SHEET)?;
+    entries.write_row_with_format(0, 0, HEADERS, &bold)?;
+    let mut row = 0;
+    for sheet in sheets {
+        for (punched, entry) in sheet.entries.iter().zip(&sheet.rounded) {
+            row += 1;
+            let (start, end) = sheet.local(entry);
+            let (punched_in, punched_out) = sheet.local(punched);
+            entries.write_string(row, 0, &sheet.summary.user.0)?;
+            entries.write_number(row, 1, entry.id as f64)?;
+            entries.write_date_with_format(row, 2, start.date(), &date)?;
+            entries.write_datetime_with_format(row, 3, start, &datetime)?;
+            if let Some(end) = end {
+                entries.write_datetime_with_format(row, 4, end, &datetime)?;
+            }
+            entries.write_number_with_format(row, 5, entry.hours(), &hours)?;
+            if let Some(note) = &entry.start.note {
+                entries.write_string(row, 6, note)?;
+            }
+            if let Some(note) = entry.end.as_ref().and_then(|end| end.note.as_ref()) {
+                entries.write_string(row, 7, note)?;
+            }
+            entries.write_string(row, 8, sheet.project(entry))?;
+            if let Some(task) = &entry.task {
+                entries.write_string(row, 9, task)?;
+            }
+            entries.write_datetime_with_format(row, 10, punched_in, &datetime)?;
+            if let Some(end) = punched_out {
+                entries.write_datetime_with_format(row, 11, end, &datetime)?;
+            }
+        }
+    }
+    entries.autofit();
+
+    let names = sheet_names(sheets.iter().map(|sheet| &sheet.summary.user));
+    for (sheet, name) in sheets.iter().zip(names) {
+        let summary = workbook.add_worksheet().set_name(name)?;
+        write_summary(summary, &sheet.summary)?;
+    }
+    workbook.save_to_buffer()
+}
+
+/// The export of `user` for entries punched from `start` to `end`
+pub(crate) fn timesheet_export(
+    d: &rusqlite::Connection,
+    user: UserID,
+    config: &TimeConfig,
+    (start, end): (NaiveDate, NaiveDate),
+    now: DateTime<Utc>,
+) -> rusqlite::Result<TimesheetExport> {
+    let tz = user_timezone(d, &user)?.unwrap_or(Tz::UTC);
+    let entries = time_entries(d, &user)?;
+    let rounded = rounded_entries(d, &user, &config.rounding, &entries, tz)?;
+    let projects = project_names(d)?;
+    let leave = leave_days(d, &user, &config.work_days, start, end)?;
+    let mut summary = build_report(user, &rounded, tz, config, start, end, now);
+    summary.name_projects(&projects);
+    summary.add_leave(leave);
+    // Filtered by when they were punched, keeping both lists in step
+    let (entries, rounded) = entries
+        .into_iter()
+        .zip(rounded)
+        .filter(|(entry, _)| {
+            let date = entry.start.time.with_timezone(&tz).date_naive();
+            (start..=end).contains(&date)
+        })
+        .unzip();
+    Ok(TimesheetExport {
+        summary,
+        entries,
+        rounded,
+        tz,
+        projects,
+    })
+}
+
+#[derive(Responder)]
+pub struct Export(Vec<u8>, ContentType, Header<'static>);
+
+/// Time entries of `users` (a comma separated list, defaults to the authenticated user)
+/// started between `start` and `end`, which default to the current pay period
+#[get("/time/export?<format>&<start>&<end>&<users>")]
+pub async fn export_time(
+    auth: Jwt,
+    format: ExportFormat,
+    start: Option<NaiveDateForm>,
+    end: Option<NaiveDateForm>,
+    users: Option<String>,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<Export, Status> {
+    auth.require(Scope::TimeRead)?;
+    let users: Vec<UserID> = match users {
+        Some(users) => users
+            .split(',')
+            .map(str::trim)
+            .filter(|user| !user.is_empty())
+            .map(|user| UserID(user.into()))
+            .collect(),
+        None => vec![auth.name.clone()],
+    };
+    if users.is_empty() {
+        return Err(Status::BadRequest);
+    }
+    for user in &users {
+        require_can_view(&auth, user, &db).await?;
+    }
+    let now = Utc::now();
+    let (period_start, period_end) = config.pay_period(config.today(now));
+    let start = start.map_or(period_start, |date| *date);
+    let end = end.map_or(period_end, |date| *date);
+    if start > end {
+        return Err(Status::BadRequest);
+    }
+    let config = config.inner().clone();
+    let sheets = db
+        .run(move |d| {
+            let config = TimeConfig {
+                overtime: with_company_holidays(d, &config.overtime)?,
+                ..config
+            };
+            users
+                .into_iter()
+                .map(|user| timesheet_export(d, user, &config, (start, end), now))
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get time entries for export: {e}");
+            Status::InternalServerError
+        })?;
+
+    let (data, content_type, extension) = match format {
+        ExportFormat::Csv => (
+            to_csv(&sheets).map_err(|e| e.to_string()),
+            ContentType::CSV,
+            "csv",
+        ),
+        ExportFormat::Xlsx => (
+            to_xlsx(&sheets).map_err(|e| e.to_string()),
+            ContentType::new(
+                "application",
+                "vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            ),
+            "xlsx",
+        ),
+        ExportFormat::Json => (
+            serde_json::to_vec_pretty(&sheets).map_err(|e| e.to_string()),
+            ContentType::JSON,
+            "json",
+        ),
+    };
+    let data = data.map_err(|e| {
+        log::error!("Failed to write time export: {e}");
+        Status::InternalServerError
+    })?;
+    let disposition = format!("attachment; filename=\"timesheets-{start}-{end}.{extension}\"");
+    Ok(Export(
+        data,
+        content_type,
+        Header::new("Content-Disposition", disposition),
+    ))
+}
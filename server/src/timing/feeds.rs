@@ -0,0 +1,176 @@
+use chrono::{DateTime, Days, Utc, Weekday};
+use rocket::{
+    http::{ContentType, Status},
+    serde::json::Json,
+    State,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use super::{
+    ical::{calendar, Event, When},
+    leave::{approved_leave, get_leave_type, leave_dates},
+    projects::project_names,
+    schedule::{published_shifts, shift_event},
+    time_entries, TimeConfig,
+};
+use crate::{
+    auth::{
+        tokens::{hash_token, new_secret, Scope},
+        Jwt,
+    },
+    types::UserID,
+    SqliteDB,
+};
+
+/// A calendar feed that was just made, the only time its secret URL is shown
+#[derive(Serialize)]
+pub struct CreatedFeed {
+    /// Path of the feed, relative to the server
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Worked time, approved leave on `work_days` and published shifts of `user` as calendar
+/// events
+pub fn feed_events(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    work_days: &[Weekday],
+) -> rusqlite::Result<Vec<Event>> {
+    let projects = project_names(d)?;
+    let mut events: Vec<Event> = time_entries(d, user)?
+        .into_iter()
+        .filter_map(|entry| {
+            let end = entry.end.as_ref()?;
+            let project = entry.project.and_then(|id| projects.get(&id));
+            let notes: Vec<_> = [&entry.start.note, &end.note, &entry.task]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect();
+            Some(Event {
+                uid: format!("entry-{}@melangerie", entry.id),
+                when: When::Timed(entry.start.time, end.time),
+                summary: match project {
+                    Some(project) => format!("Worked: {project}"),
+                    None => "Worked".into(),
+                },
+                description: (!notes.is_empty()).then(|| notes.join("\n")),
+            })
+        })
+        .collect();
+    for leave in approved_leave(d, user)? {
+        let name = get_leave_type(d, &leave.leave_type)?.map_or(leave.leave_type, |t| t.name);
+        // One event per run of days off, skipping weekends and holidays in between
+        let mut runs: Vec<(_, _)> = Vec::new();
+        for date in leave_dates(d, work_days, leave.start, leave.end)? {
+            match runs.last_mut() {
+                Some((_, last)) if *last + Days::new(1) == date => *last = date,
+                _ => runs.push((date, date)),
+            }
+        }
+        events.extend(runs.into_iter().map(|(first, last)| Event {
+            uid: format!("leave-{}-{first}@melangerie", leave.id),
+            when: When::AllDay(first, last),
+            summary: name.clone(),
+            description: leave.reason.clone(),
+        }));
+    }
+    events.extend(published_shifts(d, user)?.iter().map(shift_event));
+    Ok(events)
+}
+
+/// Makes a secret calendar URL for the authenticated user. Any URL they had before stops
+/// working.
+#[post("/time/calendar")]
+pub async fn create_feed(auth: Jwt, db: SqliteDB) -> Result<Json<CreatedFeed>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let secret = new_secret();
+    let (hash, user, created_at) = (hash_token(&secret), auth.name.clone(), Utc::now());
+    db.run(move |d| {
+        d.execute(
+            "INSERT INTO calendar_feeds (user_id, token_hash, created_at) VALUES (?1, ?2, ?3) \
+            ON CONFLICT (user_id) DO UPDATE SET token_hash = ?2, created_at = ?3, \
+            last_used_at = NULL",
+            params![user.0, hash, created_at],
+        )
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to create calendar feed: {e}");
+        Status::InternalServerError
+    })?;
+    Ok(Json(CreatedFeed {
+        url: format!("/time/calendar/{secret}.ics"),
+        created_at,
+    }))
+}
+
+/// Turns off the authenticated user's calendar URL
+#[delete("/time/calendar")]
+pub async fn delete_feed(auth: Jwt, db: SqliteDB) -> Status {
+    if auth.require(Scope::TimeRead).is_err() {
+        return Status::Forbidden;
+    }
+    let user = auth.name.clone();
+    match db
+        .run(move |d| {
+            d.execute(
+                "DELETE FROM calendar_feeds WHERE user_id = ?",
+                params![user.0],
+            )
+        })
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            log::error!("Failed to delete calendar feed: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+/// The calendar behind a secret URL. Calendar apps can't send tokens, so the URL is all
+/// it takes.
+#[get("/time/calendar/<feed>")]
+pub async fn calendar_feed(
+    feed: &str,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<(ContentType, String), Status> {
+    let secret = feed.strip_suffix(".ics").ok_or(Status::NotFound)?;
+    let hash = hash_token(secret);
+    let work_days = config.work_days.clone();
+    let found = db
+        .run(move |d| {
+            let user: Option<UserID> = d
+                .query_row(
+                    "SELECT f.user_id FROM calendar_feeds f \
+                    INNER JOIN users u ON u.user_id = f.user_id \
+                    WHERE f.token_hash = ? AND u.active = 1",
+                    params![hash],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            let Some(user) = user else {
+                return Ok(None);
+            };
+            d.execute(
+                "UPDATE calendar_feeds SET last_used_at = ? WHERE user_id = ?",
+                params![Utc::now(), user.0],
+            )?;
+            Ok::<_, rusqlite::Error>(Some((feed_events(d, &user, &work_days)?, user)))
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get calendar feed: {e}");
+            Status::InternalServerError
+        })?;
+    let (events, user) = found.ok_or(Status::NotFound)?;
+    Ok((
+        ContentType::Calendar,
+        calendar(&format!("Time of {user}"), &events),
+    ))
+}
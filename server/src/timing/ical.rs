@@ -0,0 +1,90 @@
+use chrono::{DateTime, Days, NaiveDate, Utc};
+
+/// When an event takes place
+pub enum When {
+    Timed(DateTime<Utc>, DateTime<Utc>),
+    /// From the first to the last day, both included
+    AllDay(NaiveDate, NaiveDate),
+}
+
+/// Something that shows up in a calendar app
+pub struct Event {
+    /// Has to stay the same across downloads for apps to update the event
+    pub uid: String,
+    pub when: When,
+    pub summary: String,
+    pub description: Option<String>,
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes text values as RFC 5545 wants
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes a content line, folded every 75 bytes
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// An iCalendar (.ics) file named `name` with `events` in it
+pub fn calendar(name: &str, events: &[Event]) -> String {
+    let mut out = String::new();
+    line(&mut out, "BEGIN:VCALENDAR");
+    line(&mut out, "VERSION:2.0");
+    line(&mut out, "PRODID:-//Melangerie//Time tracking//EN");
+    line(&mut out, "CALSCALE:GREGORIAN");
+    line(&mut out, &format!("X-WR-CALNAME:{}", escape(name)));
+    let now = timestamp(Utc::now());
+    for event in events {
+        line(&mut out, "BEGIN:VEVENT");
+        line(&mut out, &format!("UID:{}", escape(&event.uid)));
+        line(&mut out, &format!("DTSTAMP:{now}"));
+        match event.when {
+            When::Timed(start, end) => {
+                line(&mut out, &format!("DTSTART:{}", timestamp(start)));
+                line(&mut out, &format!("DTEND:{}", timestamp(end)));
+            }
+            When::AllDay(first, last) => {
+                // All day events end the day after
+                let day = |date: NaiveDate| date.format("%Y%m%d").to_string();
+                line(&mut out, &format!("DTSTART;VALUE=DATE:{}", day(first)));
+                line(
+                    &mut out,
+                    &format!("DTEND;VALUE=DATE:{}", day(last + Days::new(1))),
+                );
+            }
+        }
+        line(&mut out, &format!("SUMMARY:{}", escape(&event.summary)));
+        if let Some(description) = &event.description {
+            line(&mut out, &format!("DESCRIPTION:{}", escape(description)));
+        }
+        line(&mut out, "END:VEVENT");
+    }
+    line(&mut out, "END:VCALENDAR");
+    out
+}
@@ -0,0 +1,586 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+    Request, State,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{breaks::is_on_break, is_clocked_in, projects::ProjectTag, PunchError};
+use crate::{
+    auth::{
+        require_self_or_admin,
+        tokens::{hash_token, new_secret, Scope},
+        Admin, Jwt, HASH_COST,
+    },
+    events::{send_status, ClockType},
+    timing::board::clock_changed,
+    types::{UserDB, UserID},
+    SqliteDB,
+};
+
+/// Prefix that tells kiosk tokens apart from API tokens and session JWTs
+pub const KIOSK_PREFIX: &str = "mlk_";
+/// Punches a kiosk can attempt per minute
+const KIOSK_REQUESTS_PER_MINUTE: usize = 30;
+/// Wrong PINs in a row that lock a user out of every kiosk for `LOCKOUT_MINUTES`
+const MAX_WRONG_PINS: usize = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+/// Checked against when the user has no PIN, so unknown users take as long as wrong PINs
+static DUMMY_PIN_HASH: LazyLock<String> =
+    LazyLock::new(|| bcrypt::hash("", HASH_COST).expect("Failed to hash the dummy PIN"));
+
+/// Counts hits per key over a sliding window
+pub struct RateLimiter {
+    limit: usize,
+    window: TimeDelta,
+    hits: Mutex<HashMap<String, Vec<DateTime<Utc>>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: TimeDelta) -> Self {
+        Self {
+            limit,
+            window,
+            hits: Mutex::default(),
+        }
+    }
+
+    /// Counts a hit for `key` at `now` if it has any left, dropping the ones that fell out of
+    /// the window for every key
+    pub fn try_hit(&self, key: &str, now: DateTime<Utc>) -> bool {
+        let mut hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        hits.retain(|_, times| {
+            times.retain(|time| *time > now - self.window);
+            !times.is_empty()
+        });
+        let times = hits.entry(key.to_owned()).or_default();
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push(now);
+        true
+    }
+
+    pub fn clear(&self, key: &str) {
+        let mut hits = self.hits.lock().unwrap_or_else(|e| e.into_inner());
+        hits.remove(key);
+    }
+}
+
+/// How often kiosks get to try punching
+pub struct KioskLimits {
+    /// Per kiosk
+    pub requests: RateLimiter,
+    /// Wrong PINs per user
+    pub wrong_pins: RateLimiter,
+}
+
+impl Default for KioskLimits {
+    fn default() -> Self {
+        Self {
+            requests: RateLimiter::new(KIOSK_REQUESTS_PER_MINUTE, TimeDelta::minutes(1)),
+            wrong_pins: RateLimiter::new(MAX_WRONG_PINS, TimeDelta::minutes(LOCKOUT_MINUTES)),
+        }
+    }
+}
+
+/// A shared terminal authenticated with a kiosk token. Kiosks can only punch time, they
+/// never count as a user.
+pub struct Kiosk {
+    pub id: i64,
+    pub name: String,
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Kiosk {
+    type Error = &'static str;
+    async fn from_request(r: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(token) = r.headers().get_one("authorization") else {
+            return Outcome::Error((Status::Unauthorized, "No Authorization Header"));
+        };
+        let token = token.trim();
+        let token = token.strip_prefix("Bearer ").unwrap_or(token);
+        if !token.starts_with(KIOSK_PREFIX) {
+            return Outcome::Error((Status::Unauthorized, "Not a kiosk token"));
+        }
+        let Some(db) = SqliteDB::get_one(r.rocket()).await else {
+            return Outcome::Error((Status::InternalServerError, "Database unavailable"));
+        };
+        let hash = hash_token(token);
+        let kiosk = db
+            .run(move |d| {
+                let kiosk = d
+                    .query_row(
+                        "SELECT kiosk_id, name FROM kiosks \
+                        WHERE token_hash = ? AND revoked_at IS NULL",
+                        params![hash],
+                        |r| {
+                            Ok(Kiosk {
+                                id: r.get(0)?,
+                                name: r.get(1)?,
+                            })
+                        },
+                    )
+                    .optional()?;
+                if let Some(kiosk) = &kiosk {
+                    d.execute(
+                        "UPDATE kiosks SET last_used_at = ? WHERE kiosk_id = ?",
+                        params![Utc::now(), kiosk.id],
+                    )?;
+                }
+                Ok::<_, rusqlite::Error>(kiosk)
+            })
+            .await;
+        match kiosk {
+            Ok(Some(kiosk)) => Outcome::Success(kiosk),
+            Ok(None) => Outcome::Error((Status::Unauthorized, "Invalid kiosk token")),
+            Err(e) => {
+                log::error!("Failed to look up kiosk: {e}");
+                Outcome::Error((Status::InternalServerError, "Database unavailable"))
+            }
+        }
+    }
+}
+
+/// A kiosk as listed to admins, never including its token
+#[derive(Serialize)]
+pub struct KioskDevice {
+    id: i64,
+    name: String,
+    created_by: UserID,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct NewKiosk {
+    name: String,
+}
+
+/// A freshly created kiosk, the only time its token is ever shown
+#[derive(Serialize)]
+pub struct CreatedKiosk {
+    token: String,
+    #[serde(flatten)]
+    info: KioskDevice,
+}
+
+#[post("/time/kiosks", data = "<new>")]
+pub async fn create_kiosk(
+    new: Json<NewKiosk>,
+    admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<CreatedKiosk>, Status> {
+    let name = new.into_inner().name;
+    if name.trim().is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let token = format!("{KIOSK_PREFIX}{}", new_secret());
+    let hash = hash_token(&token);
+    let created_by = admin.0.name;
+    let info = db
+        .run(move |d| {
+            let created_at = Utc::now();
+            d.execute(
+                "INSERT INTO kiosks (name, token_hash, created_by, created_at) \
+                VALUES (?, ?, ?, ?)",
+                params![name, hash, created_by.0, created_at],
+            )?;
+            Ok::<_, rusqlite::Error>(KioskDevice {
+                id: d.last_insert_rowid(),
+                name,
+                created_by,
+                created_at,
+                last_used_at: None,
+                revoked_at: None,
+            })
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to create kiosk: {e}");
+            Status::InternalServerError
+        })?;
+    Ok(Json(CreatedKiosk { token, info }))
+}
+
+#[get("/time/kiosks")]
+pub async fn list_kiosks(_admin: Admin, db: SqliteDB) -> Result<Json<Vec<KioskDevice>>, Status> {
+    db.run(move |d| {
+        d.prepare(
+            "SELECT kiosk_id, name, created_by, created_at, last_used_at, revoked_at \
+            FROM kiosks ORDER BY created_at",
+        )?
+        .query_map([], |r| {
+            Ok(KioskDevice {
+                id: r.get(0)?,
+                name: r.get(1)?,
+                created_by: r.get(2)?,
+                created_at: r.get(3)?,
+                last_used_at: r.get(4)?,
+                revoked_at: r.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list kiosks: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[delete("/time/kiosks/<id>")]
+pub async fn revoke_kiosk(id: i64, _admin: Admin, db: SqliteDB) -> Status {
+    match db
+        .run(move |d| {
+            d.execute(
+                "UPDATE kiosks SET revoked_at = ? WHERE kiosk_id = ? AND revoked_at IS NULL",
+                params![Utc::now(), id],
+            )
+        })
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::Ok,
+        Err(e) => {
+            log::error!("Failed to revoke kiosk: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+/// PINs are 4 to 8 digits
+pub fn valid_pin(pin: &str) -> bool {
+    (4..=8).contains(&pin.len()) && pin.bytes().all(|b| b.is_ascii_digit())
+}
+
+pub fn set_pin(d: &rusqlite::Connection, user: &UserID, pin_hash: &str) -> rusqlite::Result<()> {
+    d.execute(
+        "INSERT INTO kiosk_pins (user_id, pin_hash, updated_at) VALUES (?1, ?2, ?3) \
+        ON CONFLICT (user_id) DO UPDATE SET pin_hash = ?2, updated_at = ?3",
+        params![user.0, pin_hash, Utc::now()],
+    )?;
+    Ok(())
+}
+
+/// The PIN hash of an active user who can punch on kiosks
+pub fn pin_hash(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<Option<String>> {
+    d.query_row(
+        "SELECT p.pin_hash FROM kiosk_pins p INNER JOIN users u ON u.user_id = p.user_id \
+        WHERE p.user_id = ? AND u.active = 1 AND u.service_account = 0",
+        params![user.0],
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+#[derive(Deserialize)]
+pub struct NewPin {
+    pin: String,
+}
+
+/// Sets the PIN `id` clocks in with on kiosks
+#[put("/<id>/pin", data = "<new>")]
+pub async fn put_pin(
+    id: &str,
+    new: Json<NewPin>,
+    auth: Jwt,
+    db: SqliteDB,
+) -> Result<Status, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let id = UserID(id.into());
+    require_self_or_admin(&auth, &id, &db).await?;
+    let pin = new.into_inner().pin;
+    if !valid_pin(&pin) {
+        return Err(Status::BadRequest);
+    }
+    let hash = bcrypt::hash(pin, HASH_COST).map_err(|_| Status::InternalServerError)?;
+    db.run(move |d| set_pin(d, &id, &hash)).await.map_err(|e| {
+        log::error!("Failed to set PIN: {e}");
+        Status::InternalServerError
+    })?;
+    Ok(Status::NoContent)
+}
+
+/// Stops `id` from clocking in on kiosks
+#[delete("/<id>/pin")]
+pub async fn delete_pin(id: &str, auth: Jwt, db: SqliteDB) -> Result<Status, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let id = UserID(id.into());
+    require_self_or_admin(&auth, &id, &db).await?;
+    match db
+        .run(move |d| d.execute("DELETE FROM kiosk_pins WHERE user_id = ?", params![id.0]))
+        .await
+    {
+        Ok(0) => Err(Status::NotFound),
+        Ok(_) => Ok(Status::NoContent),
+        Err(e) => {
+            log::error!("Failed to delete PIN: {e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// How a punch on a kiosk went
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum KioskOutcome {
+    Punched,
+    WrongPin,
+    /// Too many wrong PINs, the PIN wasn't checked
+    LockedOut,
+    /// The PIN was right but the punch wasn't, e.g. clocking in twice
+    Refused,
+}
+
+impl KioskOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            KioskOutcome::Punched => "punched",
+            KioskOutcome::WrongPin => "wrong_pin",
+            KioskOutcome::LockedOut => "locked_out",
+            KioskOutcome::Refused => "refused",
+        }
+    }
+
+    fn parse(outcome: &str) -> Self {
+        match outcome {
+            "punched" => KioskOutcome::Punched,
+            "wrong_pin" => KioskOutcome::WrongPin,
+            "locked_out" => KioskOutcome::LockedOut,
+            _ => KioskOutcome::Refused,
+        }
+    }
+}
+
+/// A punch attempted on a kiosk
+#[derive(Serialize, Debug)]
+pub struct KioskPunch {
+    pub id: i64,
+    pub kiosk: i64,
+    /// As typed on the kiosk, which might not be a user
+    pub user: String,
+    pub action: ClockType,
+    pub outcome: KioskOutcome,
+    /// The time entry or break punched, or why the punch was refused
+    pub detail: Option<String>,
+    pub attempted_at: DateTime<Utc>,
+}
+
+pub fn record_kiosk_punch(
+    d: &rusqlite::Connection,
+    kiosk: i64,
+    user: &str,
+    action: ClockType,
+    outcome: KioskOutcome,
+    detail: Option<&str>,
+) -> rusqlite::Result<()> {
+    let action = serde_json::to_value(action)
+        .ok()
+        .and_then(|a| a.as_str().map(str::to_owned))
+        .unwrap_or_default();
+    d.execute(
+        "INSERT INTO kiosk_punches (kiosk_id, user_id, action, outcome, detail, attempted_at) \
+        VALUES (?, ?, ?, ?, ?, ?)",
+        params![kiosk, user, action, outcome.as_str(), detail, Utc::now()],
+    )?;
+    Ok(())
+}
+
+/// Punches attempted on `kiosk`, or every kiosk, optionally just by `user`
+pub fn kiosk_punches(
+    d: &rusqlite::Connection,
+    kiosk: Option<i64>,
+    user: Option<&str>,
+) -> rusqlite::Result<Vec<KioskPunch>> {
+    d.prepare(
+        "SELECT punch_id, kiosk_id, user_id, action, outcome, detail, attempted_at \
+        FROM kiosk_punches WHERE (?1 IS NULL OR kiosk_id = ?1) AND (?2 IS NULL OR user_id = ?2) \
+        ORDER BY punch_id",
+    )?
+    .query_map(params![kiosk, user], |r| {
+        let action: String = r.get(3)?;
+        let outcome: String = r.get(4)?;
+        Ok(KioskPunch {
+            id: r.get(0)?,
+            kiosk: r.get(1)?,
+            user: r.get(2)?,
+            action: serde_json::from_value(serde_json::Value::String(action))
+                .unwrap_or(ClockType::TimeIn),
+            outcome: KioskOutcome::parse(&outcome),
+            detail: r.get(5)?,
+            attempted_at: r.get(6)?,
+        })
+    })?
+    .collect()
+}
+
+#[get("/time/kiosks/punches?<kiosk>&<user>")]
+pub async fn list_kiosk_punches(
+    kiosk: Option<i64>,
+    user: Option<String>,
+    _admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<Vec<KioskPunch>>, Status> {
+    db.run(move |d| kiosk_punches(d, kiosk, user.as_deref()))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!("Failed to list kiosk punches: {e}");
+            Status::InternalServerError
+        })
+}
+
+#[derive(Deserialize)]
+pub struct KioskPunchRequest {
+    user: UserID,
+    pin: String,
+    action: ClockType,
+    note: Option<String>,
+    /// Whether a `BreakStart` starts a paid break
+    #[serde(default)]
+    paid: bool,
+    #[serde(flatten)]
+    tag: ProjectTag,
+}
+
+/// Where the user stands after punching on a kiosk
+#[derive(Serialize)]
+pub struct KioskStatus {
+    user: UserID,
+    clocked_in: bool,
+    on_break: bool,
+    /// Why the punch was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Punches the time of the user whose PIN was entered on a kiosk
+#[post("/time/kiosk/punch", data = "<punch>")]
+pub async fn kiosk_punch(
+    punch: Json<KioskPunchRequest>,
+    kiosk: Kiosk,
+    limits: &State<KioskLimits>,
+    user_db: &State<UserDB>,
+    db: SqliteDB,
+) -> Result<(Status, Json<KioskStatus>), Status> {
+    let KioskPunchRequest {
+        user,
+        pin,
+        action,
+        note,
+        paid,
+        tag,
+    } = punch.into_inner();
+    let now = Utc::now();
+    let kiosk_key = kiosk.id.to_string();
+    if !limits.requests.try_hit(&kiosk_key, now) {
+        return Err(Status::TooManyRequests);
+    }
+    let record = |outcome: KioskOutcome, detail: Option<String>| {
+        let user = user.0.clone();
+        log::info!(
+            "Kiosk {} ({}) punch by {user}: {}",
+            kiosk.id,
+            kiosk.name,
+            outcome.as_str()
+        );
+        db.run(move |d| record_kiosk_punch(d, kiosk.id, &user, action, outcome, detail.as_deref()))
+    };
+    // Counts as a wrong PIN until it checks out, so guesses in parallel can't skip the lockout
+    if !limits.wrong_pins.try_hit(&user.0, now) {
+        record(KioskOutcome::LockedOut, None).await.map_err(|e| {
+            log::error!("Failed to record kiosk punch: {e}");
+            Status::InternalServerError
+        })?;
+        return Err(Status::TooManyRequests);
+    }
+    let id = user.clone();
+    let hash = db.run(move |d| pin_hash(d, &id)).await.map_err(|e| {
+        log::error!("Failed to get PIN: {e}");
+        Status::InternalServerError
+    })?;
+    let verified = match hash {
+        Some(hash) => bcrypt::verify(&pin, &hash).unwrap_or(false),
+        None => {
+            let _ = bcrypt::verify(&pin, &DUMMY_PIN_HASH);
+            false
+        }
+    };
+    if !verified {
+        record(KioskOutcome::WrongPin, None).await.map_err(|e| {
+            log::error!("Failed to record kiosk punch: {e}");
+            Status::InternalServerError
+        })?;
+        return Err(Status::Unauthorized);
+    }
+    limits.wrong_pins.clear(&user.0);
+
+    let (id, kiosk_id) = (user.clone(), kiosk.id);
+    let res = db
+        .run(move |d| {
+            let error = match action.punch(d, &id, note.as_deref(), paid, &tag) {
+                Ok(punched) => {
+                    let detail = punched.to_string();
+                    record_kiosk_punch(
+                        d,
+                        kiosk_id,
+                        &id.0,
+                        action,
+                        KioskOutcome::Punched,
+                        Some(&detail),
+                    )?;
+                    None
+                }
+                Err(PunchError::Db(e)) => return Err(e),
+                Err(e) => {
+                    let detail = e.to_string();
+                    record_kiosk_punch(
+                        d,
+                        kiosk_id,
+                        &id.0,
+                        action,
+                        KioskOutcome::Refused,
+                        Some(&detail),
+                    )?;
+                    Some(detail)
+                }
+            };
+            Ok((error, is_clocked_in(d, &id)?, is_on_break(d, &id)?))
+        })
+        .await;
+    let (error, clocked_in, on_break) = match res {
+        Ok(state) => state,
+        Err(e) => {
+            log::error!("Failed to punch time for {user} on a kiosk: {e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    let status = if error.is_some() {
+        Status::Conflict
+    } else {
+        clock_changed(&db, user_db, &user).await;
+        Status::Ok
+    };
+    // Keeps any other device they're logged in on in sync
+    send_status(&user, &db, user_db).await;
+    Ok((
+        status,
+        Json(KioskStatus {
+            user,
+            clocked_in,
+            on_break,
+            error,
+        }),
+    ))
+}
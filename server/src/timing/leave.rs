@@ -0,0 +1,910 @@
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
+use rocket::{http::Status, serde::json::Json, State};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    approvals::{managers_of, period_locked, require_reviewer, ApprovalStatus, Review},
+    notify,
+    overtime::OvertimePolicy,
+    require_can_view, TimeConfig,
+};
+use crate::{
+    auth::{is_admin, tokens::Scope, Admin, Jwt},
+    profiles::user_timezone,
+    types::{UserDB, UserID},
+    SqliteDB,
+};
+
+/// A kind of time off, like vacation or sick leave
+#[derive(Serialize, Debug, Clone)]
+pub struct LeaveType {
+    pub id: String,
+    pub name: String,
+    /// Paid leave counts towards paid hours in reports
+    pub paid: bool,
+    /// Hours earned every pay period, `None` if there's no balance to keep
+    pub accrual_hours: Option<f64>,
+    /// Balances don't accrue past this
+    pub max_balance: Option<f64>,
+}
+
+const SELECT_LEAVE_TYPES: &str =
+    "SELECT leave_type, name, paid, accrual_hours, max_balance FROM leave_types";
+
+fn leave_type_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<LeaveType> {
+    Ok(LeaveType {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        paid: row.get(2)?,
+        accrual_hours: row.get(3)?,
+        max_balance: row.get(4)?,
+    })
+}
+
+pub fn get_leave_type(d: &rusqlite::Connection, id: &str) -> rusqlite::Result<Option<LeaveType>> {
+    d.query_row(
+        &format!("{SELECT_LEAVE_TYPES} WHERE leave_type = ?"),
+        params![id],
+        leave_type_from_row,
+    )
+    .optional()
+}
+
+/// A day off for everyone, paid for `hours`
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Holiday {
+    pub date: NaiveDate,
+    pub name: String,
+    pub hours: f64,
+}
+
+/// Company holidays from `start` to `end`
+pub fn holidays_between(
+    d: &rusqlite::Connection,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> rusqlite::Result<Vec<Holiday>> {
+    d.prepare("SELECT date, name, hours FROM holidays WHERE date >= ? AND date <= ? ORDER BY date")?
+        .query_map(params![start, end], |row| {
+            Ok(Holiday {
+                date: row.get(0)?,
+                name: row.get(1)?,
+                hours: row.get(2)?,
+            })
+        })?
+        .collect()
+}
+
+/// `policy` with every company holiday paid at its holiday rate too
+pub fn with_company_holidays(
+    d: &rusqlite::Connection,
+    policy: &OvertimePolicy,
+) -> rusqlite::Result<OvertimePolicy> {
+    let mut policy = policy.clone();
+    policy.holidays.extend(
+        holidays_between(d, NaiveDate::MIN, NaiveDate::MAX)?
+            .into_iter()
+            .map(|holiday| holiday.date),
+    );
+    Ok(policy)
+}
+
+/// Days from `start` to `end` leave gets taken on: `work_days` that aren't holidays
+pub fn leave_dates(
+    d: &rusqlite::Connection,
+    work_days: &[Weekday],
+    start: NaiveDate,
+    end: NaiveDate,
+) -> rusqlite::Result<Vec<NaiveDate>> {
+    let holidays = holidays_between(d, start, end)?;
+    Ok(start
+        .iter_days()
+        .take_while(|date| *date <= end)
+        .filter(|date| work_days.contains(&date.weekday()))
+        .filter(|date| !holidays.iter().any(|holiday| holiday.date == *date))
+        .collect())
+}
+
+/// Hours of one leave type someone has left
+#[derive(Serialize, Debug, Clone)]
+pub struct Balance {
+    pub leave_type: String,
+    pub hours: f64,
+    /// Last day of the last pay period that was accrued
+    pub accrued_through: NaiveDate,
+}
+
+/// Adds what `user` earned of `leave` for every pay period that ended since the last time,
+/// returning their balance. New balances start at 0 with the pay period `today` falls in.
+/// Leave types without accrual have no balance.
+pub fn accrue(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    leave: &LeaveType,
+    config: &TimeConfig,
+    today: NaiveDate,
+) -> rusqlite::Result<Option<Balance>> {
+    let Some(rate) = leave.accrual_hours else {
+        return Ok(None);
+    };
+    let last_ended = config.pay_period(today).0 - Days::new(1);
+    let found: Option<(f64, NaiveDate)> = d
+        .query_row(
+            "SELECT hours, accrued_through FROM leave_balances \
+            WHERE user_id = ? AND leave_type = ?",
+            params![user.0, leave.id],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    let (mut hours, mut through) = found.unwrap_or((0.0, last_ended));
+    while through < last_ended {
+        through = config.pay_period(through + Days::new(1)).1;
+        // Balances set above the cap by hand stay where they are
+        let cap = leave
+            .max_balance
+            .map_or(f64::INFINITY, |max| max.max(hours));
+        hours = (hours + rate).min(cap);
+    }
+    d.execute(
+        "INSERT INTO leave_balances (user_id, leave_type, hours, accrued_through) \
+        VALUES (?1, ?2, ?3, ?4) ON CONFLICT (user_id, leave_type) \
+        DO UPDATE SET hours = ?3, accrued_through = ?4",
+        params![user.0, leave.id, hours, through],
+    )?;
+    Ok(Some(Balance {
+        leave_type: leave.id.clone(),
+        hours,
+        accrued_through: through,
+    }))
+}
+
+/// Time off someone asked for and what became of it
+#[derive(Serialize, Debug, Clone)]
+pub struct LeaveRequest {
+    pub id: i64,
+    pub user: UserID,
+    pub leave_type: String,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub hours_per_day: f64,
+    /// `hours_per_day` for every work day in the range that isn't a holiday
+    pub hours: f64,
+    pub reason: Option<String>,
+    pub status: ApprovalStatus,
+    pub requested_at: DateTime<Utc>,
+    pub reviewed_by: Option<UserID>,
+    pub reviewed_at: Option<DateTime<Utc>>,
+    pub review_comment: Option<String>,
+    pub cancelled_by: Option<UserID>,
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+const SELECT_LEAVE: &str = "SELECT request_id, user_id, leave_type, start_date, end_date, \
+    hours_per_day, hours, reason, status, requested_at, reviewed_by, reviewed_at, \
+    review_comment, cancelled_by, cancelled_at FROM leave_requests";
+
+fn leave_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<LeaveRequest> {
+    Ok(LeaveRequest {
+        id: row.get(0)?,
+        user: row.get(1)?,
+        leave_type: row.get(2)?,
+        start: row.get(3)?,
+        end: row.get(4)?,
+        hours_per_day: row.get(5)?,
+        hours: row.get(6)?,
+        reason: row.get(7)?,
+        status: ApprovalStatus::parse(&row.get::<_, String>(8)?),
+        requested_at: row.get(9)?,
+        reviewed_by: row.get(10)?,
+        reviewed_at: row.get(11)?,
+        review_comment: row.get(12)?,
+        cancelled_by: row.get(13)?,
+        cancelled_at: row.get(14)?,
+    })
+}
+
+fn get_leave(d: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<LeaveRequest>> {
+    d.query_row(
+        &format!("{SELECT_LEAVE} WHERE request_id = ?"),
+        params![id],
+        leave_from_row,
+    )
+    .optional()
+}
+
+/// Approved leave of `user`, oldest first
+pub fn approved_leave(
+    d: &rusqlite::Connection,
+    user: &UserID,
+) -> rusqlite::Result<Vec<LeaveRequest>> {
+    d.prepare(&format!(
+        "{SELECT_LEAVE} WHERE user_id = ? AND status = 'approved' ORDER BY start_date"
+    ))?
+    .query_map(params![user.0], leave_from_row)?
+    .collect()
+}
+
+pub enum LeaveError {
+    /// The leave type or request doesn't exist
+    NotFound,
+    /// The range ends before it starts or has no working days in it
+    Invalid,
+    /// Part of the range falls in a locked pay period
+    Locked,
+    /// Not enough hours left of that leave type
+    InsufficientBalance,
+    /// The request was already reviewed
+    Reviewed,
+    /// The request was already cancelled
+    Cancelled,
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for LeaveError {
+    fn from(e: rusqlite::Error) -> Self {
+        LeaveError::Db(e)
+    }
+}
+
+impl std::fmt::Display for LeaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeaveError::NotFound => f.write_str("That leave doesn't exist"),
+            LeaveError::Invalid => f.write_str("There are no working days in that range"),
+            LeaveError::Locked => f.write_str("This pay period is locked"),
+            LeaveError::InsufficientBalance => f.write_str("Not enough leave left"),
+            LeaveError::Reviewed => f.write_str("That leave was already reviewed"),
+            LeaveError::Cancelled => f.write_str("That leave was already cancelled"),
+            LeaveError::Db(_) => f.write_str("Failed to update leave"),
+        }
+    }
+}
+
+impl From<LeaveError> for Status {
+    fn from(e: LeaveError) -> Self {
+        match e {
+            LeaveError::NotFound => Status::NotFound,
+            LeaveError::Invalid => Status::BadRequest,
+            LeaveError::InsufficientBalance | LeaveError::Reviewed | LeaveError::Cancelled => {
+                Status::Conflict
+            }
+            LeaveError::Locked => Status::Locked,
+            LeaveError::Db(e) => {
+                log::error!("Failed to update leave: {e}");
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+/// Whether `user` can take `hours` of `leave` on `dates`, returning their balance if it's
+/// kept
+fn check_leave(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    leave: &LeaveType,
+    dates: &[NaiveDate],
+    hours: f64,
+    config: &TimeConfig,
+    today: NaiveDate,
+) -> Result<Option<Balance>, LeaveError> {
+    if dates.is_empty() || hours <= 0.0 {
+        return Err(LeaveError::Invalid);
+    }
+    for date in dates {
+        if period_locked(d, user, *date)? {
+            return Err(LeaveError::Locked);
+        }
+    }
+    let balance = accrue(d, user, leave, config, today)?;
+    if balance
+        .as_ref()
+        .is_some_and(|balance| balance.hours < hours)
+    {
+        return Err(LeaveError::InsufficientBalance);
+    }
+    Ok(balance)
+}
+
+/// Asks for `hours_per_day` of `leave_type` on every working day from `start` to `end`
+#[allow(clippy::too_many_arguments)]
+pub fn request_leave(
+    d: &mut rusqlite::Connection,
+    user: &UserID,
+    leave_type: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+    hours_per_day: f64,
+    reason: Option<&str>,
+    config: &TimeConfig,
+    today: NaiveDate,
+) -> Result<LeaveRequest, LeaveError> {
+    if start > end || hours_per_day <= 0.0 || hours_per_day > 24.0 {
+        return Err(LeaveError::Invalid);
+    }
+    let tx = d.transaction()?;
+    let leave = get_leave_type(&tx, leave_type)?.ok_or(LeaveError::NotFound)?;
+    let dates = leave_dates(&tx, &config.work_days, start, end)?;
+    let hours = dates.len() as f64 * hours_per_day;
+    check_leave(&tx, user, &leave, &dates, hours, config, today)?;
+    tx.execute(
+        "INSERT INTO leave_requests (user_id, leave_type, start_date, end_date, \
+        hours_per_day, hours, reason, status, requested_at) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, 'submitted', ?)",
+        params![
+            user.0,
+            leave.id,
+            start,
+            end,
+            hours_per_day,
+            hours,
+            reason,
+            Utc::now()
+        ],
+    )?;
+    let request = get_leave(&tx, tx.last_insert_rowid())?.ok_or(LeaveError::NotFound)?;
+    tx.commit()?;
+    Ok(request)
+}
+
+/// Approves or rejects requested leave, taking it off the user's balance if it's approved.
+/// Permissions have to be checked beforehand.
+pub fn review_leave(
+    d: &mut rusqlite::Connection,
+    reviewer: &UserID,
+    id: i64,
+    status: ApprovalStatus,
+    comment: Option<&str>,
+    config: &TimeConfig,
+    today: NaiveDate,
+) -> Result<LeaveRequest, LeaveError> {
+    let tx = d.transaction()?;
+    let Some(request) = get_leave(&tx, id)? else {
+        return Err(LeaveError::NotFound);
+    };
+    match request.status {
+        ApprovalStatus::Submitted => {}
+        ApprovalStatus::Cancelled => return Err(LeaveError::Cancelled),
+        _ => return Err(LeaveError::Reviewed),
+    }
+    if status == ApprovalStatus::Approved {
+        let leave = get_leave_type(&tx, &request.leave_type)?.ok_or(LeaveError::NotFound)?;
+        let dates = leave_dates(&tx, &config.work_days, request.start, request.end)?;
+        let user = &request.user;
+        if check_leave(&tx, user, &leave, &dates, request.hours, config, today)?.is_some() {
+            tx.execute(
+                "UPDATE leave_balances SET hours = hours - ? WHERE user_id = ? AND leave_type = ?",
+                params![request.hours, user.0, leave.id],
+            )?;
+        }
+    }
+    tx.execute(
+        "UPDATE leave_requests SET status = ?, reviewed_by = ?, reviewed_at = ?, \
+        review_comment = ? WHERE request_id = ?",
+        params![status.as_str(), reviewer.0, Utc::now(), comment, id],
+    )?;
+    let request = get_leave(&tx, id)?.ok_or(LeaveError::NotFound)?;
+    tx.commit()?;
+    Ok(request)
+}
+
+/// Cancels requested or approved leave, giving approved hours back to the user's balance.
+/// Permissions have to be checked beforehand.
+pub fn cancel_leave(
+    d: &mut rusqlite::Connection,
+    by: &UserID,
+    id: i64,
+) -> Result<LeaveRequest, LeaveError> {
+    let tx = d.transaction()?;
+    let Some(request) = get_leave(&tx, id)? else {
+        return Err(LeaveError::NotFound);
+    };
+    match request.status {
+        ApprovalStatus::Submitted => {}
+        ApprovalStatus::Approved => {
+            // Leave in a locked pay period was already paid out
+            for date in request
+                .start
+                .iter_days()
+                .take_while(|date| *date <= request.end)
+            {
+                if period_locked(&tx, &request.user, date)? {
+                    return Err(LeaveError::Locked);
+                }
+            }
+            tx.execute(
+                "UPDATE leave_balances SET hours = hours + ? WHERE user_id = ? AND leave_type = ?",
+                params![request.hours, request.user.0, request.leave_type],
+            )?;
+        }
+        ApprovalStatus::Rejected => return Err(LeaveError::Reviewed),
+        ApprovalStatus::Cancelled => return Err(LeaveError::Cancelled),
+    }
+    tx.execute(
+        "UPDATE leave_requests SET status = ?, cancelled_by = ?, cancelled_at = ? \
+        WHERE request_id = ?",
+        params![ApprovalStatus::Cancelled.as_str(), by.0, Utc::now(), id],
+    )?;
+    let request = get_leave(&tx, id)?.ok_or(LeaveError::NotFound)?;
+    tx.commit()?;
+    Ok(request)
+}
+
+/// Hours paid on a day without being worked
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct LeaveDay {
+    pub date: NaiveDate,
+    /// The leave type, or `holiday` for company holidays
+    pub kind: String,
+    pub hours: f64,
+    pub paid: bool,
+}
+
+/// Approved leave of `user` on `work_days` and company holidays from `start` to `end`
+pub fn leave_days(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    work_days: &[Weekday],
+    start: NaiveDate,
+    end: NaiveDate,
+) -> rusqlite::Result<Vec<LeaveDay>> {
+    let mut days: Vec<_> = holidays_between(d, start, end)?
+        .into_iter()
+        .map(|holiday| LeaveDay {
+            date: holiday.date,
+            kind: "holiday".into(),
+            hours: holiday.hours,
+            paid: true,
+        })
+        .collect();
+    let approved: Vec<(String, bool, NaiveDate, NaiveDate, f64)> = d
+        .prepare(
+            "SELECT lr.leave_type, lt.paid, lr.start_date, lr.end_date, lr.hours_per_day \
+            FROM leave_requests lr INNER JOIN leave_types lt ON lt.leave_type = lr.leave_type \
+            WHERE lr.user_id = ?1 AND lr.status = 'approved' \
+            AND lr.start_date <= ?3 AND lr.end_date >= ?2",
+        )?
+        .query_map(params![user.0, start, end], |r| {
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    for (kind, paid, from, to, hours) in approved {
+        for date in leave_dates(d, work_days, from.max(start), to.min(end))? {
+            days.push(LeaveDay {
+                date,
+                kind: kind.clone(),
+                hours,
+                paid,
+            });
+        }
+    }
+    days.sort_by_key(|day| day.date);
+    Ok(days)
+}
+
+/// Every leave type
+#[get("/time/leave/types")]
+pub async fn list_leave_types(auth: Jwt, db: SqliteDB) -> Result<Json<Vec<LeaveType>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    db.run(|d| {
+        d.prepare(&format!("{SELECT_LEAVE_TYPES} ORDER BY name"))?
+            .query_map([], leave_type_from_row)?
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list leave types: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize)]
+pub struct LeaveTypeUpdate {
+    name: String,
+    #[serde(default = "default_paid")]
+    paid: bool,
+    accrual_hours: Option<f64>,
+    max_balance: Option<f64>,
+}
+
+fn default_paid() -> bool {
+    true
+}
+
+/// Creates or replaces the leave type `id`
+#[put("/time/leave/types/<id>", data = "<update>")]
+pub async fn put_leave_type(
+    _admin: Admin,
+    id: &str,
+    update: Json<LeaveTypeUpdate>,
+    db: SqliteDB,
+) -> Result<Json<LeaveType>, Status> {
+    let LeaveTypeUpdate {
+        name,
+        paid,
+        accrual_hours,
+        max_balance,
+    } = update.into_inner();
+    let name = name.trim().to_string();
+    let valid_id = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if name.is_empty() || !valid_id || accrual_hours.is_some_and(|hours| hours < 0.0) {
+        return Err(Status::BadRequest);
+    }
+    let id = id.to_string();
+    db.run(move |d| {
+        d.execute(
+            "INSERT INTO leave_types (leave_type, name, paid, accrual_hours, max_balance) \
+            VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT (leave_type) \
+            DO UPDATE SET name = ?2, paid = ?3, accrual_hours = ?4, max_balance = ?5",
+            params![id, name, paid, accrual_hours, max_balance],
+        )?;
+        get_leave_type(d, &id)
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to save leave type: {e}");
+        Status::InternalServerError
+    })?
+    .map(Json)
+    .ok_or(Status::InternalServerError)
+}
+
+/// Company holidays, of `year` if given
+#[get("/time/holidays?<year>")]
+pub async fn list_holidays(
+    auth: Jwt,
+    year: Option<i32>,
+    db: SqliteDB,
+) -> Result<Json<Vec<Holiday>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let (start, end) = match year {
+        Some(year) => (
+            NaiveDate::from_ymd_opt(year, 1, 1).ok_or(Status::BadRequest)?,
+            NaiveDate::from_ymd_opt(year, 12, 31).ok_or(Status::BadRequest)?,
+        ),
+        None => (NaiveDate::MIN, NaiveDate::MAX),
+    };
+    db.run(move |d| holidays_between(d, start, end))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!("Failed to list holidays: {e}");
+            Status::InternalServerError
+        })
+}
+
+#[derive(Deserialize)]
+pub struct HolidayUpdate {
+    name: String,
+    hours: f64,
+}
+
+/// Makes `date` a company holiday
+#[put("/time/holidays/<date>", data = "<update>")]
+pub async fn put_holiday(
+    _admin: Admin,
+    date: &str,
+    update: Json<HolidayUpdate>,
+    db: SqliteDB,
+) -> Result<Json<Holiday>, Status> {
+    let date: NaiveDate = date.parse().map_err(|_| Status::BadRequest)?;
+    let HolidayUpdate { name, hours } = update.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() || !(0.0..=24.0).contains(&hours) {
+        return Err(Status::BadRequest);
+    }
+    let holiday = Holiday { date, name, hours };
+    let saved = holiday.clone();
+    db.run(move |d| {
+        d.execute(
+            "INSERT INTO holidays (date, name, hours) VALUES (?1, ?2, ?3) \
+            ON CONFLICT (date) DO UPDATE SET name = ?2, hours = ?3",
+            params![saved.date, saved.name, saved.hours],
+        )
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to save holiday: {e}");
+        Status::InternalServerError
+    })?;
+    Ok(Json(holiday))
+}
+
+#[delete("/time/holidays/<date>")]
+pub async fn delete_holiday(_admin: Admin, date: &str, db: SqliteDB) -> Status {
+    let Ok(date) = date.parse::<NaiveDate>() else {
+        return Status::BadRequest;
+    };
+    match db
+        .run(move |d| d.execute("DELETE FROM holidays WHERE date = ?", params![date]))
+        .await
+    {
+        Ok(0) => Status::NotFound,
+        Ok(_) => Status::NoContent,
+        Err(e) => {
+            log::error!("Failed to delete holiday: {e}");
+            Status::InternalServerError
+        }
+    }
+}
+
+/// The day it is for `user`
+fn user_today(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<NaiveDate> {
+    let tz = user_timezone(d, user)?.unwrap_or(Tz::UTC);
+    Ok(Utc::now().with_timezone(&tz).date_naive())
+}
+
+/// Leave balances of `user`, defaults to the authenticated user
+#[get("/time/leave/balances?<user>")]
+pub async fn leave_balances(
+    auth: Jwt,
+    user: Option<String>,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<Json<Vec<Balance>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let user = user.map_or_else(|| auth.name.clone(), UserID);
+    require_can_view(&auth, &user, &db).await?;
+    let config = config.inner().clone();
+    db.run(move |d| {
+        let tx = d.transaction()?;
+        let today = user_today(&tx, &user)?;
+        let types = tx
+            .prepare(&format!("{SELECT_LEAVE_TYPES} ORDER BY name"))?
+            .query_map([], leave_type_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut balances = Vec::new();
+        for leave in &types {
+            balances.extend(accrue(&tx, &user, leave, &config, today)?);
+        }
+        tx.commit()?;
+        Ok::<_, rusqlite::Error>(balances)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to get leave balances: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize)]
+pub struct BalanceUpdate {
+    hours: f64,
+}
+
+/// Sets how many hours of `leave_type` `user` has left, for carry-overs and adjustments
+#[put("/time/leave/balances/<user>/<leave_type>", data = "<update>")]
+pub async fn set_leave_balance(
+    _admin: Admin,
+    user: UserID,
+    leave_type: &str,
+    update: Json<BalanceUpdate>,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<Json<Balance>, Status> {
+    let hours = update.hours;
+    let (leave_type, config) = (leave_type.to_string(), config.inner().clone());
+    db.run(move |d| {
+        let tx = d.transaction()?;
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM users WHERE user_id = ?)",
+            params![user.0],
+            |r| r.get(0),
+        )?;
+        let Some(leave) = get_leave_type(&tx, &leave_type)?.filter(|_| exists) else {
+            return Err(LeaveError::NotFound);
+        };
+        let today = user_today(&tx, &user)?;
+        let Some(mut balance) = accrue(&tx, &user, &leave, &config, today)? else {
+            return Err(LeaveError::Invalid);
+        };
+        tx.execute(
+            "UPDATE leave_balances SET hours = ? WHERE user_id = ? AND leave_type = ?",
+            params![hours, user.0, leave.id],
+        )?;
+        tx.commit()?;
+        balance.hours = hours;
+        Ok(balance)
+    })
+    .await
+    .map(Json)
+    .map_err(Status::from)
+}
+
+/// Leave asked for by `user`, or without one your own plus that of everyone you manage
+/// (everyone for admins)
+#[get("/time/leave?<user>&<status>")]
+pub async fn list_leave(
+    auth: Jwt,
+    user: Option<String>,
+    status: Option<ApprovalStatus>,
+    db: SqliteDB,
+) -> Result<Json<Vec<LeaveRequest>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let status = status.map(ApprovalStatus::as_str);
+    let requests = match user.map(UserID) {
+        Some(user) => {
+            require_can_view(&auth, &user, &db).await?;
+            db.run(move |d| {
+                d.prepare(&format!(
+                    "{SELECT_LEAVE} WHERE user_id = ?1 AND (?2 IS NULL OR status = ?2) \
+                    ORDER BY start_date DESC"
+                ))?
+                .query_map(params![user.0, status], leave_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+        }
+        None => {
+            let admin = is_admin(&db, &auth.name).await;
+            let viewer = auth.name.clone();
+            db.run(move |d| {
+                d.prepare(&format!(
+                    "{SELECT_LEAVE} WHERE (?1 OR user_id = ?2 OR user_id IN \
+                      (SELECT user_id FROM user_managers WHERE manager_id = ?2)) \
+                    AND (?3 IS NULL OR status = ?3) ORDER BY start_date DESC"
+                ))?
+                .query_map(params![admin, viewer.0, status], leave_from_row)?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+        }
+    };
+    requests.map(Json).map_err(|e| {
+        log::error!("Failed to list leave requests: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize)]
+pub struct NewLeave {
+    leave_type: String,
+    start: NaiveDate,
+    end: NaiveDate,
+    /// Defaults to `workday_hours`
+    hours_per_day: Option<f64>,
+    reason: Option<String>,
+}
+
+/// Asks the authenticated user's managers for time off
+#[post("/time/leave", data = "<request>")]
+pub async fn request_time_off(
+    auth: Jwt,
+    request: Json<NewLeave>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    config: &State<TimeConfig>,
+) -> Result<Json<LeaveRequest>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let NewLeave {
+        leave_type,
+        start,
+        end,
+        hours_per_day,
+        reason,
+    } = request.into_inner();
+    let hours_per_day = hours_per_day.unwrap_or(config.workday_hours);
+    let reason = reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    let (user, config) = (auth.name.clone(), config.inner().clone());
+    let (request, managers) = db
+        .run(move |d| {
+            let today = user_today(d, &user)?;
+            let request = request_leave(
+                d,
+                &user,
+                &leave_type,
+                start,
+                end,
+                hours_per_day,
+                reason.as_deref(),
+                &config,
+                today,
+            )?;
+            Ok::<_, LeaveError>((request, managers_of(d, &user)?))
+        })
+        .await?;
+    let mut content = format!(
+        "{} asked for {} hours of {} from {} to {}",
+        request.user, request.hours, request.leave_type, request.start, request.end
+    );
+    if let Some(reason) = &request.reason {
+        content += &format!(": {reason}");
+    }
+    notify(&db, user_db, managers, content).await;
+    Ok(Json(request))
+}
+
+/// Approves or rejects requested leave
+#[put("/time/leave/<id>", data = "<decision>")]
+pub async fn review_time_off(
+    auth: Jwt,
+    id: i64,
+    decision: Json<Review>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    config: &State<TimeConfig>,
+) -> Result<Json<LeaveRequest>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let Review { status, comment } = decision.into_inner();
+    if matches!(
+        status,
+        ApprovalStatus::Submitted | ApprovalStatus::Cancelled
+    ) {
+        return Err(Status::BadRequest);
+    }
+    let user = db
+        .run(move |d| get_leave(d, id))
+        .await
+        .map_err(LeaveError::from)?
+        .ok_or(Status::NotFound)?
+        .user;
+    require_reviewer(&auth.name, &user, &db).await?;
+    let (reviewer, config) = (auth.name.clone(), config.inner().clone());
+    let request = db
+        .run(move |d| {
+            let today = user_today(d, &user)?;
+            review_leave(d, &reviewer, id, status, comment.as_deref(), &config, today)
+        })
+        .await?;
+    let mut content = format!(
+        "{} {} your leave from {} to {}",
+        auth.name,
+        request.status.as_str(),
+        request.start,
+        request.end
+    );
+    if let Some(comment) = &request.review_comment {
+        content += &format!(": {comment}");
+    }
+    notify(&db, user_db, vec![request.user.clone()], content).await;
+    Ok(Json(request))
+}
+
+/// Cancels leave, giving back the hours if it was approved. Open to whoever asked for it and
+/// whoever can review it.
+#[delete("/time/leave/<id>")]
+pub async fn cancel_time_off(
+    auth: Jwt,
+    id: i64,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Json<LeaveRequest>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let user = db
+        .run(move |d| get_leave(d, id))
+        .await
+        .map_err(LeaveError::from)?
+        .ok_or(Status::NotFound)?
+        .user;
+    if user != auth.name {
+        require_reviewer(&auth.name, &user, &db).await?;
+    }
+    let by = auth.name.clone();
+    let (request, managers) = db
+        .run(move |d| {
+            let request = cancel_leave(d, &by, id)?;
+            let managers = managers_of(d, &request.user)?;
+            Ok::<_, LeaveError>((request, managers))
+        })
+        .await?;
+    let (to, whose) = if request.user == auth.name {
+        (managers, "their")
+    } else {
+        (vec![request.user.clone()], "your")
+    };
+    let content = format!(
+        "{} cancelled {whose} leave from {} to {}",
+        auth.name, request.start, request.end
+    );
+    notify(&db, user_db, to, content).await;
+    Ok(Json(request))
+}
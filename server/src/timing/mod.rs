@@ -0,0 +1,771 @@
+pub mod approvals;
+pub mod board;
+pub mod breaks;
+pub mod corrections;
+pub mod export;
+pub mod feeds;
+pub mod ical;
+pub mod kiosk;
+pub mod leave;
+pub mod overtime;
+pub mod periods;
+pub mod projects;
+pub mod reminders;
+pub mod report;
+pub mod rounding;
+pub mod schedule;
+
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
+use rocket::{form::FromFormField, http::Status, serde::json::Json, tokio::sync::RwLock};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{atomic::AtomicI64, Arc},
+};
+
+use chrono::NaiveDateTime;
+use chrono::NaiveTime;
+
+// type AllowedTimeDB = HashMap<UserID, HashSet<UserID>>;
+
+// https://stackoverflow.com/questions/25413201/how-do-i-implement-a-trait-i-dont-own-for-a-type-i-dont-own
+// https://github.com/SergioBenitez/Rocket/issues/602#issuecomment-380497269
+pub struct NaiveDateForm(NaiveDate);
+pub struct NaiveTimeForm(NaiveTime);
+pub struct NaiveDateTimeForm(NaiveDateTime);
+
+impl Deref for NaiveDateForm {
+    type Target = NaiveDate;
+    fn deref(&self) -> &NaiveDate {
+        &self.0
+    }
+}
+
+impl Deref for NaiveTimeForm {
+    type Target = NaiveTime;
+    fn deref(&self) -> &NaiveTime {
+        &self.0
+    }
+}
+
+impl Deref for NaiveDateTimeForm {
+    type Target = NaiveDateTime;
+    fn deref(&self) -> &NaiveDateTime {
+        &self.0
+    }
+}
+
+impl<'v> FromFormField<'v> for NaiveDateForm {
+    fn from_value(field: rocket::form::ValueField<'v>) -> rocket::form::Result<'v, Self> {
+        let date = NaiveDate::parse_from_str(field.value, "%Y-%m-%d")
+            .map_err(|_| rocket::form::Error::validation("Invalid Date, format: %Y-%m-%d"))?;
+        Ok(NaiveDateForm(date))
+    }
+}
+
+use crate::{
+    auth::{require_self_or_admin, tokens::Scope, Jwt},
+    notifications::deliveries,
+    profiles::user_timezone,
+    types::{ChatMessage, ChatRoomID, UserDB, UserID},
+    users::SYSTEM_USER,
+    SqliteDB,
+};
+use approvals::{manages, period_locked};
+use breaks::{attach_breaks, breaks_of, end_open_breaks, Break};
+use corrections::{record_audit, AuditAction};
+use overtime::OvertimePolicy;
+use projects::{project_active, ProjectTag};
+use rounding::RoundingPolicy;
+
+/// Room time tracking notifications show up in. Only clients know about it, nothing is stored.
+pub const TIMESHEET_ROOM: &str = "timesheets";
+
+/// Sends a time tracking notification to whichever of `users` are connected
+pub async fn notify(db: &SqliteDB, user_db: &UserDB, users: Vec<UserID>, content: String) {
+    let message = ChatMessage {
+        sender: SYSTEM_USER.into(),
+        room: ChatRoomID(TIMESHEET_ROOM.into()),
+        content,
+        timestamp: jsonwebtoken::get_current_timestamp() as f64,
+        notify: false,
+        flagged: false,
+    };
+    let msg = message.clone();
+    match db.run(move |d| deliveries(d, &msg, users)).await {
+        Ok(deliveries) => user_db.write_to(message, &deliveries).await,
+        Err(e) => log::error!("Failed to send time tracking notification: {e}"),
+    }
+}
+
+/// Only the user themself, their managers and admins get to see someone's time
+pub async fn require_can_view(auth: &Jwt, user: &UserID, db: &SqliteDB) -> Result<(), Status> {
+    let (viewer, uid) = (auth.name.clone(), user.clone());
+    if db
+        .run(move |d| manages(d, &viewer, &uid))
+        .await
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+    require_self_or_admin(auth, user, db).await
+}
+
+/// How often people get paid
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PayPeriod {
+    Weekly,
+    #[default]
+    Biweekly,
+    /// The 1st to the 15th, then the 16th to the end of the month
+    Semimonthly,
+    Monthly,
+}
+
+/// The `time` table of `Rocket.toml`
+#[derive(Deserialize, Clone, Debug)]
+pub struct TimeConfig {
+    #[serde(default)]
+    pub pay_period: PayPeriod,
+    /// IANA name of the company timezone, which decides what day it is for things that
+    /// aren't about a single user, like the current pay period of an export
+    #[serde(
+        default = "TimeConfig::default_timezone",
+        deserialize_with = "parse_timezone"
+    )]
+    pub timezone: Tz,
+    /// First day of any weekly or biweekly pay period
+    #[serde(default = "TimeConfig::default_anchor")]
+    pub pay_period_start: NaiveDate,
+    /// Hours after which people still clocked in get reminded, 0 turns reminders off
+    #[serde(default = "TimeConfig::default_remind_after")]
+    pub remind_after_hours: u32,
+    /// Hours after which entries get closed for people, 0 turns it off
+    #[serde(default = "TimeConfig::default_auto_clock_out")]
+    pub auto_clock_out_hours: u32,
+    /// Entries longer than this many hours without a break get pointed out in reports, 0
+    /// turns it off
+    #[serde(default)]
+    pub break_required_after_hours: u32,
+    /// Minutes people can be late to or leave early from a shift without getting flagged
+    #[serde(default = "TimeConfig::default_late_grace")]
+    pub late_grace_minutes: u32,
+    /// Hours a day of leave counts for unless asked otherwise
+    #[serde(default = "TimeConfig::default_workday")]
+    pub workday_hours: f64,
+    /// Days of the week people work, the only ones leave gets taken on
+    #[serde(default = "TimeConfig::default_work_days")]
+    pub work_days: Vec<Weekday>,
+    #[serde(default)]
+    pub overtime: OvertimePolicy,
+    #[serde(default)]
+    pub rounding: RoundingPolicy,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        TimeConfig {
+            pay_period: PayPeriod::default(),
+            timezone: Self::default_timezone(),
+            pay_period_start: Self::default_anchor(),
+            remind_after_hours: Self::default_remind_after(),
+            auto_clock_out_hours: Self::default_auto_clock_out(),
+            break_required_after_hours: 0,
+            late_grace_minutes: Self::default_late_grace(),
+            workday_hours: Self::default_workday(),
+            work_days: Self::default_work_days(),
+            overtime: OvertimePolicy::default(),
+            rounding: RoundingPolicy::default(),
+        }
+    }
+}
+
+fn parse_timezone<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Tz, D::Error> {
+    String::deserialize(d)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+impl TimeConfig {
+    fn default_timezone() -> Tz {
+        Tz::UTC
+    }
+
+    /// The date it is at `now` in the company timezone
+    pub fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+        now.with_timezone(&self.timezone).date_naive()
+    }
+
+    fn default_anchor() -> NaiveDate {
+        // A Monday
+        NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+    }
+
+    fn default_remind_after() -> u32 {
+        10
+    }
+
+    fn default_auto_clock_out() -> u32 {
+        16
+    }
+
+    fn default_late_grace() -> u32 {
+        5
+    }
+
+    fn default_workday() -> f64 {
+        8.0
+    }
+
+    fn default_work_days() -> Vec<Weekday> {
+        vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+        ]
+    }
+
+    /// First and last day of the pay period `date` falls in
+    pub fn pay_period(&self, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let first_of_month = date.with_day(1).unwrap();
+        match self.pay_period {
+            PayPeriod::Weekly | PayPeriod::Biweekly => {
+                let length = if self.pay_period == PayPeriod::Weekly {
+                    7
+                } else {
+                    14
+                };
+                let offset = (date - self.pay_period_start).num_days().rem_euclid(length);
+                let start = date - Days::new(offset as u64);
+                (start, start + Days::new(length as u64 - 1))
+            }
+            PayPeriod::Semimonthly if date.day() <= 15 => {
+                (first_of_month, date.with_day(15).unwrap())
+            }
+            PayPeriod::Semimonthly => (
+                date.with_day(16).unwrap(),
+                first_of_month + Months::new(1) - Days::new(1),
+            ),
+            PayPeriod::Monthly => (
+                first_of_month,
+                first_of_month + Months::new(1) - Days::new(1),
+            ),
+        }
+    }
+}
+
+/// Time entries of the authenticated user, or of `user` for admins
+#[get("/time?<start>&<end>&<user>")]
+pub async fn get_time(
+    auth: Jwt,
+    start: Option<NaiveDateForm>,
+    end: Option<NaiveDateForm>,
+    user: Option<String>,
+    db: SqliteDB,
+) -> Result<Json<Vec<TimeRange>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let user = user.map_or_else(|| auth.name.clone(), UserID);
+    require_can_view(&auth, &user, &db).await?;
+    // let ts = server_state.time.data.read().await;
+    // let user_timesheet = ts.get(&user.name)?;
+    let (start, end) = (start.map(|date| date.0), end.map(|date| date.0));
+    let time_range = db
+        .run(move |d| time_entries_between(d, &user, start, end))
+        .await
+        .map_err(|e| {
+            log::error!("Error getting times: {e}");
+            Status::InternalServerError
+        })?;
+
+    Ok(Json(time_range))
+}
+
+/// Entries of `user` started between `start` and `end` in their timezone, missing bounds
+/// being today there. Without either bound, every entry
+pub fn time_entries_between(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+) -> rusqlite::Result<Vec<TimeRange>> {
+    let mut time_range = time_entries(d, user)?;
+    if start.is_none() && end.is_none() {
+        return Ok(time_range);
+    }
+
+    let tz = user_timezone(d, user)?.unwrap_or(Tz::UTC);
+    let today = Utc::now().with_timezone(&tz).date_naive();
+    let (start, end) = (start.unwrap_or(today), end.unwrap_or(today));
+    time_range.retain(|t| (start..=end).contains(&t.start.time.with_timezone(&tz).date_naive()));
+    Ok(time_range)
+}
+
+const SELECT_ENTRIES: &str = "SELECT te.time_entry_id, te.start_time, te.start_note, \
+    te.end_time, te.end_note, ts.user_id, te.flagged, te.project_id, te.task FROM timesheets ts \
+    INNER JOIN time_entries te ON ts.timesheet_id = te.timesheet_id";
+
+fn entry_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<TimeRange> {
+    let end: Option<DateTime<Utc>> = row.get(3)?;
+    let end_note = row.get(4)?;
+    Ok(TimeRange {
+        id: row.get(0)?,
+        start: Timestamp {
+            time: row.get(1)?,
+            note: row.get(2)?,
+        },
+        end: end.map(|time| Timestamp {
+            time,
+            note: end_note,
+        }),
+        flagged: row.get(6)?,
+        breaks: Vec::new(),
+        project: row.get(7)?,
+        task: row.get(8)?,
+    })
+}
+
+/// All time entries recorded for `user`, oldest first
+pub fn time_entries(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<Vec<TimeRange>> {
+    let mut entries = d
+        .prepare(&format!(
+            "{SELECT_ENTRIES} WHERE ts.user_id = ? ORDER BY te.start_time"
+        ))?
+        .query_map(params![user.0], entry_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    attach_breaks(d, user, &mut entries)?;
+    Ok(entries)
+}
+
+/// A single time entry and who it belongs to
+pub fn get_entry(d: &rusqlite::Connection, id: i64) -> rusqlite::Result<(UserID, TimeRange)> {
+    let (user, mut entry): (UserID, TimeRange) = d.query_row(
+        &format!("{SELECT_ENTRIES} WHERE te.time_entry_id = ?"),
+        params![id],
+        |row| Ok((row.get(5)?, entry_from_row(row)?)),
+    )?;
+    entry.breaks = breaks_of(d, id)?;
+    Ok((user, entry))
+}
+
+pub enum PunchError {
+    AlreadyClockedIn,
+    NotClockedIn,
+    OnBreak,
+    NotOnBreak,
+    /// The project doesn't exist or was closed
+    InactiveProject,
+    /// The pay period was approved
+    Locked,
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for PunchError {
+    fn from(e: rusqlite::Error) -> Self {
+        PunchError::Db(e)
+    }
+}
+
+impl std::fmt::Display for PunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PunchError::AlreadyClockedIn => f.write_str("You are already clocked in"),
+            PunchError::NotClockedIn => f.write_str("You are not clocked in"),
+            PunchError::OnBreak => f.write_str("You are already on a break"),
+            PunchError::NotOnBreak => f.write_str("You are not on a break"),
+            PunchError::InactiveProject => f.write_str("That project doesn't exist or is closed"),
+            PunchError::Locked => f.write_str("This pay period is locked"),
+            PunchError::Db(_) => f.write_str("Failed to record your time"),
+        }
+    }
+}
+
+/// The user's timesheet and the entry they're clocked in to, if any. Creates the timesheet if
+/// it's missing, and clears `clocked_in` if it doesn't point at an open entry so a timesheet
+/// can never get stuck clocked in.
+fn current_entry(
+    tx: &rusqlite::Transaction<'_>,
+    user: &UserID,
+) -> rusqlite::Result<(i64, Option<i64>)> {
+    tx.execute(
+        "INSERT INTO timesheets (user_id, clocked_in, current_id) SELECT ?1, 0, NULL \
+        WHERE NOT EXISTS (SELECT 1 FROM timesheets WHERE user_id = ?1)",
+        params![user.0],
+    )?;
+    let (sheet, clocked_in, current): (i64, bool, Option<i64>) = tx.query_row(
+        "SELECT ts.timesheet_id, ts.clocked_in, te.time_entry_id FROM timesheets ts \
+        LEFT JOIN time_entries te ON te.time_entry_id = ts.current_id \
+          AND te.timesheet_id = ts.timesheet_id AND te.end_time IS NULL \
+        WHERE ts.user_id = ? ORDER BY ts.timesheet_id LIMIT 1",
+        params![user.0],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )?;
+    if clocked_in != current.is_some() {
+        log::warn!("Timesheet {sheet} of {user} was inconsistent, marking it clocked out");
+        tx.execute(
+            "UPDATE timesheets SET clocked_in = 0, current_id = NULL WHERE timesheet_id = ?",
+            params![sheet],
+        )?;
+        return Ok((sheet, None));
+    }
+    Ok((sheet, current))
+}
+
+/// Refuses new time in a locked pay period. Periods can't get locked while someone is clocked
+/// in to them, so clocking out is always allowed.
+fn check_unlocked(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    time: DateTime<Utc>,
+) -> Result<(), PunchError> {
+    if locked_at(d, user, time)? {
+        return Err(PunchError::Locked);
+    }
+    Ok(())
+}
+
+/// Whether any of `user`'s time entries start or end in one of their locked pay periods
+pub fn has_locked_time(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<bool> {
+    for entry in time_entries(d, user)? {
+        let end = entry.end.as_ref().map(|end| end.time);
+        for time in [Some(entry.start.time), end].into_iter().flatten() {
+            if locked_at(d, user, time)? {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `time` falls in a locked pay period of `user`, going by their timezone
+pub fn locked_at(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    time: DateTime<Utc>,
+) -> rusqlite::Result<bool> {
+    let tz = user_timezone(d, user)?.unwrap_or(Tz::UTC);
+    period_locked(d, user, time.with_timezone(&tz).date_naive())
+}
+
+/// Opens an entry on `sheet` at `now` and makes it the current one
+fn punch_in(
+    tx: &rusqlite::Transaction<'_>,
+    user: &UserID,
+    sheet: i64,
+    note: Option<&str>,
+    tag: &ProjectTag,
+    now: DateTime<Utc>,
+) -> Result<i64, PunchError> {
+    check_unlocked(tx, user, now)?;
+    if let Some(project) = tag.project {
+        if !project_active(tx, project)? {
+            return Err(PunchError::InactiveProject);
+        }
+    }
+    tx.execute(
+        "INSERT INTO time_entries (timesheet_id, start_time, start_note, project_id, task) \
+        VALUES (?, ?, ?, ?, ?)",
+        params![sheet, now, note, tag.project, tag.task],
+    )?;
+    let entry = tx.last_insert_rowid();
+    tx.execute(
+        "UPDATE timesheets SET clocked_in = 1, current_id = ? WHERE timesheet_id = ?",
+        params![entry, sheet],
+    )?;
+    let (_, after) = get_entry(tx, entry)?;
+    record_audit(
+        tx,
+        user,
+        AuditAction::ClockIn,
+        user,
+        None,
+        None,
+        Some(&after),
+    )?;
+    Ok(entry)
+}
+
+/// Closes the current entry `entry` on `sheet` at `now`, along with any break still going
+fn punch_out(
+    tx: &rusqlite::Transaction<'_>,
+    user: &UserID,
+    sheet: i64,
+    entry: i64,
+    note: Option<&str>,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    let (_, before) = get_entry(tx, entry)?;
+    end_open_breaks(tx, entry, now)?;
+    tx.execute(
+        "UPDATE time_entries SET end_time = ?, end_note = ? WHERE time_entry_id = ?",
+        params![now, note, entry],
+    )?;
+    tx.execute(
+        "UPDATE timesheets SET clocked_in = 0, current_id = NULL WHERE timesheet_id = ?",
+        params![sheet],
+    )?;
+    let (_, after) = get_entry(tx, entry)?;
+    record_audit(
+        tx,
+        user,
+        AuditAction::ClockOut,
+        user,
+        None,
+        Some(&before),
+        Some(&after),
+    )
+}
+
+/// Starts a new time entry for `user` on the project in `tag`, returning its id
+pub fn clock_in_to(
+    d: &mut rusqlite::Connection,
+    user: &UserID,
+    note: Option<&str>,
+    tag: &ProjectTag,
+) -> Result<i64, PunchError> {
+    let tx = d.transaction()?;
+    let (sheet, None) = current_entry(&tx, user)? else {
+        return Err(PunchError::AlreadyClockedIn);
+    };
+    let entry = punch_in(&tx, user, sheet, note, tag, Utc::now())?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+/// Ends the time entry `user` is clocked in to, returning its id
+pub fn clock_out(
+    d: &mut rusqlite::Connection,
+    user: &UserID,
+    note: Option<&str>,
+) -> Result<i64, PunchError> {
+    let tx = d.transaction()?;
+    let (sheet, current) = current_entry(&tx, user)?;
+    let Some(entry) = current else {
+        // Keep any repair `current_entry` made
+        tx.commit()?;
+        return Err(PunchError::NotClockedIn);
+    };
+    punch_out(&tx, user, sheet, entry, note, Utc::now())?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+/// Ends the entry `user` is clocked in to and starts one tagged with `tag` at the same
+/// instant, returning the new entry's id. `note` goes on both.
+pub fn switch_project(
+    d: &mut rusqlite::Connection,
+    user: &UserID,
+    note: Option<&str>,
+    tag: &ProjectTag,
+) -> Result<i64, PunchError> {
+    let tx = d.transaction()?;
+    let (sheet, current) = current_entry(&tx, user)?;
+    let Some(entry) = current else {
+        tx.commit()?;
+        return Err(PunchError::NotClockedIn);
+    };
+    let now = Utc::now();
+    punch_out(&tx, user, sheet, entry, note, now)?;
+    let entry = punch_in(&tx, user, sheet, note, tag, now)?;
+    tx.commit()?;
+    Ok(entry)
+}
+
+/// Whether `user` is clocked in to an open time entry
+pub fn is_clocked_in(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<bool> {
+    Ok(d.query_row(
+        "SELECT 1 FROM timesheets ts \
+            INNER JOIN time_entries te ON te.time_entry_id = ts.current_id \
+            WHERE ts.user_id = ? AND ts.clocked_in = 1 AND te.end_time IS NULL",
+        params![user.0],
+        |_| Ok(()),
+    )
+    .optional()?
+    .is_some())
+}
+
+#[derive(Default, Clone)]
+pub struct TimeState {
+    pub data: Arc<RwLock<HashMap<UserID, TimeSheet>>>,
+    id_counter: Arc<AtomicI64>,
+}
+
+impl TimeState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn from_data(data: HashMap<UserID, TimeSheet>) -> Self {
+        let max_id = data
+            .values()
+            .flat_map(|sheet| sheet.completed.iter())
+            .map(|range| range.id)
+            .max()
+            .unwrap_or(0);
+        TimeState {
+            data: Arc::new(RwLock::new(data)),
+            id_counter: Arc::new(AtomicI64::new(max_id)),
+        }
+    }
+
+    pub async fn start(&self, user: UserID, note: Option<String>) {
+        let mut data = self.data.write().await;
+        let sheet = data.entry(user).or_insert_with(|| TimeSheet {
+            completed: Vec::new(),
+            current: None,
+        });
+        if sheet.current.is_none() {
+            sheet.current = Some(Timestamp {
+                time: Utc::now(),
+                note,
+            });
+        }
+    }
+
+    pub async fn stop(&self, user: &UserID, note: Option<String>) -> Option<i64> {
+        let mut data = self.data.write().await;
+        let sheet = data.get_mut(user)?;
+        let Some(start) = sheet.current.take() else {
+            return None;
+        };
+        let id = self
+            .id_counter
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        sheet.completed.push(TimeRange {
+            start,
+            end: Some(Timestamp {
+                time: Utc::now(),
+                note,
+            }),
+            id,
+            flagged: false,
+            breaks: Vec::new(),
+            project: None,
+            task: None,
+        });
+        Some(id)
+    }
+
+    pub async fn is_active(&self, user: &UserID) -> Option<bool> {
+        self.data
+            .read()
+            .await
+            .get(user)
+            .map(|sheet| sheet.current.is_some())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TimeSheet {
+    completed: Vec<TimeRange>,
+    current: Option<Timestamp>,
+}
+
+impl TimeSheet {
+    fn get(&self, id: i64) -> Option<&TimeRange> {
+        self.completed.iter().find(|range| range.id == id)
+    }
+
+    fn total_hours(&self) -> f64 {
+        let mut total = self.completed.iter().map(TimeRange::hours).sum();
+        if let Some(current) = &self.current {
+            total += (Utc::now() - current.time).num_seconds() as f64 / 3600.0;
+        }
+        total
+    }
+
+    fn hours_for_day(&self, day: NaiveDate) -> f64 {
+        self.completed
+            .iter()
+            .filter(|range| range.start.time.date_naive() == day)
+            .map(TimeRange::hours)
+            .sum()
+    }
+
+    pub fn find_in_range(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> impl Iterator<Item = &TimeRange> {
+        self.completed.iter().filter(move |range| {
+            let date = range.start.time.date_naive();
+            date >= start && date <= end
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TimeRange {
+    id: i64,
+    start: Timestamp,
+    /// `None` while still clocked in
+    end: Option<Timestamp>,
+    /// Closed automatically, waiting for a manager to look at it
+    #[serde(default)]
+    flagged: bool,
+    #[serde(default)]
+    breaks: Vec<Break>,
+    #[serde(default)]
+    project: Option<i64>,
+    #[serde(default)]
+    task: Option<String>,
+}
+
+impl From<DateTime<Utc>> for Timestamp {
+    fn from(time: DateTime<Utc>) -> Self {
+        Timestamp { time, note: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Timestamp {
+    time: DateTime<Utc>,
+    note: Option<String>,
+}
+
+impl TimeRange {
+    /// When the entry ends, or `now` if it's still open
+    fn end_or(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        self.end.as_ref().map_or(now, |end| end.time)
+    }
+
+    /// Stretches of the entry spent working, leaving out unpaid breaks. Open entries and
+    /// breaks run up to `now`.
+    fn worked(&self, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let (start, end) = (self.start.time, self.end_or(now));
+        let mut unpaid: Vec<_> = self
+            .breaks
+            .iter()
+            .filter(|pause| !pause.paid)
+            .map(|pause| (pause.start.max(start), pause.end.unwrap_or(now).min(end)))
+            .filter(|(from, to)| from < to)
+            .collect();
+        unpaid.sort();
+        let mut worked = Vec::new();
+        let mut from = start;
+        for (pause_start, pause_end) in unpaid {
+            if from < pause_start {
+                worked.push((from, pause_start));
+            }
+            from = from.max(pause_end);
+        }
+        if from < end {
+            worked.push((from, end));
+        }
+        worked
+    }
+
+    fn hours(&self) -> f64 {
+        let seconds: i64 = self
+            .worked(Utc::now())
+            .iter()
+            .map(|(from, to)| (*to - *from).num_seconds())
+            .sum();
+        seconds as f64 / 3600.0
+    }
+}
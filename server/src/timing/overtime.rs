@@ -0,0 +1,181 @@
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+/// What hours worked on a holiday get paid as
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HolidayRate {
+    /// The same rules as any other day
+    Regular,
+    #[default]
+    Overtime,
+    DoubleTime,
+}
+
+/// The `time.overtime` table of `Rocket.toml`. A threshold of 0 turns that rule off.
+#[derive(Deserialize, Clone, Debug)]
+pub struct OvertimePolicy {
+    /// Hours a day after which time is overtime
+    #[serde(default = "OvertimePolicy::default_daily")]
+    pub daily_hours: f64,
+    /// Regular hours an ISO week (Monday to Sunday) after which time is overtime
+    #[serde(default = "OvertimePolicy::default_weekly")]
+    pub weekly_hours: f64,
+    /// Hours a day after which time is double time
+    #[serde(default = "OvertimePolicy::default_double_time")]
+    pub double_time_hours: f64,
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+    #[serde(default)]
+    pub holiday_rate: HolidayRate,
+    /// How long before reaching the daily or weekly threshold people get warned
+    #[serde(default = "OvertimePolicy::default_warn_before")]
+    pub warn_before_hours: f64,
+}
+
+impl Default for OvertimePolicy {
+    fn default() -> Self {
+        OvertimePolicy {
+            daily_hours: Self::default_daily(),
+            weekly_hours: Self::default_weekly(),
+            double_time_hours: Self::default_double_time(),
+            holidays: Vec::new(),
+            holiday_rate: HolidayRate::default(),
+            warn_before_hours: Self::default_warn_before(),
+        }
+    }
+}
+
+/// Hours worked split by how they're paid
+#[derive(Serialize, Default, Clone, Copy, PartialEq, Debug)]
+pub struct PaySplit {
+    pub regular: f64,
+    pub overtime: f64,
+    pub double_time: f64,
+}
+
+impl AddAssign for PaySplit {
+    fn add_assign(&mut self, other: Self) {
+        self.regular += other.regular;
+        self.overtime += other.overtime;
+        self.double_time += other.double_time;
+    }
+}
+
+/// Thresholds someone can be about to cross
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Threshold {
+    Daily,
+    Weekly,
+}
+
+fn seconds(hours: f64) -> i64 {
+    (hours * 3600.0).round() as i64
+}
+
+fn hours(seconds: i64) -> f64 {
+    seconds as f64 / 3600.0
+}
+
+impl OvertimePolicy {
+    fn default_daily() -> f64 {
+        8.0
+    }
+
+    fn default_weekly() -> f64 {
+        40.0
+    }
+
+    fn default_double_time() -> f64 {
+        12.0
+    }
+
+    fn default_warn_before() -> f64 {
+        1.0
+    }
+
+    /// Splits the seconds worked on each day. `days` has to include every earlier day of the
+    /// first week for weekly overtime to add up.
+    pub fn split(&self, days: &BTreeMap<NaiveDate, i64>) -> BTreeMap<NaiveDate, PaySplit> {
+        let (daily, weekly, double) = (
+            seconds(self.daily_hours),
+            seconds(self.weekly_hours),
+            seconds(self.double_time_hours),
+        );
+        let mut splits = BTreeMap::new();
+        let mut week = None;
+        let mut week_regular = 0;
+        for (&date, &worked) in days {
+            let iso = date.iso_week();
+            if week != Some((iso.year(), iso.week())) {
+                week = Some((iso.year(), iso.week()));
+                week_regular = 0;
+            }
+            let (mut regular, mut overtime, mut double_time) = (worked, 0, 0);
+            if self.holidays.contains(&date) && self.holiday_rate != HolidayRate::Regular {
+                regular = 0;
+                match self.holiday_rate {
+                    HolidayRate::DoubleTime => double_time = worked,
+                    _ => overtime = worked,
+                }
+            } else {
+                if double > 0 {
+                    double_time = (regular - double).max(0);
+                    regular -= double_time;
+                }
+                if daily > 0 {
+                    overtime = (regular - daily).max(0);
+                    regular -= overtime;
+                }
+                if weekly > 0 {
+                    let excess = (week_regular + regular - weekly).max(0);
+                    regular -= excess;
+                    overtime += excess;
+                }
+                week_regular += regular;
+            }
+            splits.insert(
+                date,
+                PaySplit {
+                    regular: hours(regular),
+                    overtime: hours(overtime),
+                    double_time: hours(double_time),
+                },
+            );
+        }
+        splits
+    }
+
+    /// The threshold someone who worked `days` is within `warn_before_hours` of on `today`,
+    /// if any
+    pub fn approaching(
+        &self,
+        days: &BTreeMap<NaiveDate, i64>,
+        today: NaiveDate,
+    ) -> Option<Threshold> {
+        let warn = seconds(self.warn_before_hours);
+        if warn <= 0 || self.holidays.contains(&today) {
+            return None;
+        }
+        let near = |worked: i64, threshold: i64| {
+            threshold > 0 && worked < threshold && worked >= threshold - warn
+        };
+        if near(
+            days.get(&today).copied().unwrap_or(0),
+            seconds(self.daily_hours),
+        ) {
+            return Some(Threshold::Daily);
+        }
+        let week = today.iso_week();
+        let regular = seconds(
+            self.split(days)
+                .iter()
+                .filter(|(date, _)| date.iso_week() == week)
+                .map(|(_, split)| split.regular)
+                .sum(),
+        );
+        near(regular, seconds(self.weekly_hours)).then_some(Threshold::Weekly)
+    }
+}
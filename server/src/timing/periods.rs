@@ -0,0 +1,333 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use rocket::{http::Status, serde::json::Json, State};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::TimeConfig;
+use crate::{
+    auth::{tokens::Scope, Admin, Jwt},
+    profiles::user_timezone,
+    types::UserID,
+    SqliteDB,
+};
+
+/// A pay period closed after payroll ran, which nobody's time can change in
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct ClosedPeriod {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub closed_by: UserID,
+    pub closed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PeriodAction {
+    Close,
+    Reopen,
+}
+
+impl PeriodAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            PeriodAction::Close => "close",
+            PeriodAction::Reopen => "reopen",
+        }
+    }
+
+    fn parse(action: &str) -> Self {
+        match action {
+            "reopen" => PeriodAction::Reopen,
+            _ => PeriodAction::Close,
+        }
+    }
+}
+
+/// An admin closing or reopening a pay period
+#[derive(Serialize, Debug)]
+pub struct PeriodChange {
+    pub id: i64,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub action: PeriodAction,
+    pub changed_by: UserID,
+    pub changed_at: DateTime<Utc>,
+    pub reason: Option<String>,
+}
+
+pub enum PeriodError {
+    /// The pay period hasn't ended yet
+    NotOver,
+    /// Part of the pay period is already closed
+    Closed,
+    /// The pay period isn't closed
+    NotClosed,
+    /// These people are still clocked in to entries in the pay period
+    ClockedIn(Vec<UserID>),
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for PeriodError {
+    fn from(e: rusqlite::Error) -> Self {
+        PeriodError::Db(e)
+    }
+}
+
+impl std::fmt::Display for PeriodError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeriodError::NotOver => f.write_str("That pay period hasn't ended yet"),
+            PeriodError::Closed => f.write_str("That pay period is already closed"),
+            PeriodError::NotClosed => f.write_str("That pay period isn't closed"),
+            PeriodError::ClockedIn(users) => {
+                let users: Vec<_> = users.iter().map(|user| user.0.as_str()).collect();
+                write!(f, "Still clocked in: {}", users.join(", "))
+            }
+            PeriodError::Db(_) => f.write_str("Failed to change the pay period"),
+        }
+    }
+}
+
+impl From<PeriodError> for Status {
+    fn from(e: PeriodError) -> Self {
+        match e {
+            PeriodError::NotOver => Status::BadRequest,
+            PeriodError::NotClosed => Status::NotFound,
+            PeriodError::Closed | PeriodError::ClockedIn(_) => Status::Conflict,
+            PeriodError::Db(e) => {
+                log::error!("Failed to change pay period: {e}");
+                Status::InternalServerError
+            }
+        }
+    }
+}
+
+fn log_change(
+    d: &rusqlite::Connection,
+    (start, end): (NaiveDate, NaiveDate),
+    action: PeriodAction,
+    admin: &UserID,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    d.execute(
+        "INSERT INTO pay_period_log (period_start, period_end, action, changed_by, changed_at, \
+        reason) VALUES (?, ?, ?, ?, ?, ?)",
+        params![start, end, action.as_str(), admin.0, Utc::now(), reason],
+    )?;
+    Ok(())
+}
+
+/// People clocked in to an entry that started on or before `end` in their timezone
+fn clocked_in_until(d: &rusqlite::Connection, end: NaiveDate) -> rusqlite::Result<Vec<UserID>> {
+    let open = d
+        .prepare(
+            "SELECT ts.user_id, te.start_time FROM timesheets ts \
+            INNER JOIN time_entries te ON te.time_entry_id = ts.current_id \
+            WHERE ts.clocked_in = 1 AND te.end_time IS NULL ORDER BY ts.user_id",
+        )?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<Vec<(UserID, DateTime<Utc>)>, _>>()?;
+    let mut users = Vec::new();
+    for (user, start) in open {
+        let tz = user_timezone(d, &user)?.unwrap_or(Tz::UTC);
+        if start.with_timezone(&tz).date_naive() <= end {
+            users.push(user);
+        }
+    }
+    Ok(users)
+}
+
+/// Closes the pay period from `start` to `end` for everyone, as long as it's over and
+/// nobody's still clocked in to it
+pub fn close_period(
+    d: &mut rusqlite::Connection,
+    admin: &UserID,
+    (start, end): (NaiveDate, NaiveDate),
+    today: NaiveDate,
+    reason: Option<&str>,
+) -> Result<ClosedPeriod, PeriodError> {
+    if end >= today {
+        return Err(PeriodError::NotOver);
+    }
+    let tx = d.transaction()?;
+    let overlaps: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM closed_periods \
+        WHERE period_start <= ? AND period_end >= ?)",
+        params![end, start],
+        |r| r.get(0),
+    )?;
+    if overlaps {
+        return Err(PeriodError::Closed);
+    }
+    let clocked_in = clocked_in_until(&tx, end)?;
+    if !clocked_in.is_empty() {
+        return Err(PeriodError::ClockedIn(clocked_in));
+    }
+    let closed = ClosedPeriod {
+        start,
+        end,
+        closed_by: admin.clone(),
+        closed_at: Utc::now(),
+    };
+    tx.execute(
+        "INSERT INTO closed_periods (period_start, period_end, closed_by, closed_at) \
+        VALUES (?, ?, ?, ?)",
+        params![start, end, admin.0, closed.closed_at],
+    )?;
+    log_change(&tx, (start, end), PeriodAction::Close, admin, reason)?;
+    tx.commit()?;
+    Ok(closed)
+}
+
+/// Reopens the closed pay period `date` falls in
+pub fn reopen_period(
+    d: &mut rusqlite::Connection,
+    admin: &UserID,
+    date: NaiveDate,
+    reason: &str,
+) -> Result<ClosedPeriod, PeriodError> {
+    let tx = d.transaction()?;
+    let closed = tx
+        .query_row(
+            "SELECT period_start, period_end, closed_by, closed_at FROM closed_periods \
+            WHERE period_start <= ?1 AND period_end >= ?1",
+            params![date],
+            period_from_row,
+        )
+        .optional()?
+        .ok_or(PeriodError::NotClosed)?;
+    tx.execute(
+        "DELETE FROM closed_periods WHERE period_start = ?",
+        params![closed.start],
+    )?;
+    log_change(
+        &tx,
+        (closed.start, closed.end),
+        PeriodAction::Reopen,
+        admin,
+        Some(reason),
+    )?;
+    tx.commit()?;
+    Ok(closed)
+}
+
+fn period_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ClosedPeriod> {
+    Ok(ClosedPeriod {
+        start: row.get(0)?,
+        end: row.get(1)?,
+        closed_by: row.get(2)?,
+        closed_at: row.get(3)?,
+    })
+}
+
+pub fn closed_periods(d: &mut rusqlite::Connection) -> rusqlite::Result<Vec<ClosedPeriod>> {
+    d.prepare(
+        "SELECT period_start, period_end, closed_by, closed_at FROM closed_periods \
+        ORDER BY period_start DESC",
+    )?
+    .query_map([], period_from_row)?
+    .collect()
+}
+
+/// Every time a pay period was closed or reopened, oldest first
+pub fn period_log(d: &mut rusqlite::Connection) -> rusqlite::Result<Vec<PeriodChange>> {
+    d.prepare(
+        "SELECT log_id, period_start, period_end, action, changed_by, changed_at, reason \
+        FROM pay_period_log ORDER BY log_id",
+    )?
+    .query_map([], |r| {
+        Ok(PeriodChange {
+            id: r.get(0)?,
+            start: r.get(1)?,
+            end: r.get(2)?,
+            action: PeriodAction::parse(&r.get::<_, String>(3)?),
+            changed_by: r.get(4)?,
+            changed_at: r.get(5)?,
+            reason: r.get(6)?,
+        })
+    })?
+    .collect()
+}
+
+#[get("/time/periods")]
+pub async fn list_closed_periods(
+    auth: Jwt,
+    db: SqliteDB,
+) -> Result<Json<Vec<ClosedPeriod>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    db.run(closed_periods).await.map(Json).map_err(|e| {
+        log::error!("Failed to list closed pay periods: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[get("/time/periods/log")]
+pub async fn pay_period_log(
+    _admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<Vec<PeriodChange>>, Status> {
+    db.run(period_log).await.map(Json).map_err(|e| {
+        log::error!("Failed to get the pay period log: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize, Default)]
+pub struct PeriodNote {
+    reason: Option<String>,
+}
+
+/// Closes the pay period containing `date` for everyone
+#[post("/time/periods/<date>/close", data = "<note>")]
+pub async fn close_pay_period(
+    date: &str,
+    note: Option<Json<PeriodNote>>,
+    admin: Admin,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<Json<ClosedPeriod>, Status> {
+    let date: NaiveDate = date.parse().map_err(|_| Status::BadRequest)?;
+    let reason = note
+        .map(|note| note.into_inner())
+        .unwrap_or_default()
+        .reason;
+    let period = config.pay_period(date);
+    let today = config.today(Utc::now());
+    let name = admin.0.name;
+    let closed = db
+        .run(move |d| close_period(d, &name, period, today, reason.as_deref()))
+        .await?;
+    log::info!(
+        "{} closed the pay period {} to {}",
+        closed.closed_by,
+        closed.start,
+        closed.end
+    );
+    Ok(Json(closed))
+}
+
+/// Reopens the closed pay period containing `date`, which takes a `reason`
+#[post("/time/periods/<date>/reopen", data = "<note>")]
+pub async fn reopen_pay_period(
+    date: &str,
+    note: Json<PeriodNote>,
+    admin: Admin,
+    db: SqliteDB,
+) -> Result<Json<ClosedPeriod>, Status> {
+    let date: NaiveDate = date.parse().map_err(|_| Status::BadRequest)?;
+    let Some(reason) = note.into_inner().reason.filter(|r| !r.trim().is_empty()) else {
+        return Err(Status::BadRequest);
+    };
+    let name = admin.0.name.clone();
+    let reopened = db
+        .run(move |d| reopen_period(d, &name, date, &reason))
+        .await?;
+    log::warn!(
+        "{} reopened the pay period {} to {}",
+        admin.0.name,
+        reopened.start,
+        reopened.end
+    );
+    Ok(Json(reopened))
+}
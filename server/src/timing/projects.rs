@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{tokens::Scope, Admin, Jwt},
+    profiles::nullable,
+    SqliteDB,
+};
+
+/// What a time entry was spent on
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ProjectTag {
+    pub project: Option<i64>,
+    pub task: Option<String>,
+}
+
+/// Something time gets billed to
+#[derive(Serialize, Debug, Clone)]
+pub struct Project {
+    pub id: i64,
+    pub name: String,
+    pub client: Option<String>,
+    pub cost_center: Option<String>,
+    /// Closed projects can't get new time
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+const SELECT_PROJECTS: &str =
+    "SELECT project_id, name, client, cost_center, active, created_at FROM projects";
+
+fn project_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Project> {
+    Ok(Project {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        client: row.get(2)?,
+        cost_center: row.get(3)?,
+        active: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+fn get_project(d: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<Project>> {
+    d.query_row(
+        &format!("{SELECT_PROJECTS} WHERE project_id = ?"),
+        params![id],
+        project_from_row,
+    )
+    .optional()
+}
+
+/// Whether time can be tagged with `project`
+pub fn project_active(d: &rusqlite::Connection, project: i64) -> rusqlite::Result<bool> {
+    d.query_row(
+        "SELECT EXISTS (SELECT 1 FROM projects WHERE project_id = ? AND active = 1)",
+        params![project],
+        |r| r.get(0),
+    )
+}
+
+/// Names of every project, closed ones included
+pub fn project_names(d: &rusqlite::Connection) -> rusqlite::Result<HashMap<i64, String>> {
+    d.prepare("SELECT project_id, name FROM projects")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect()
+}
+
+/// Active projects, or every project with `all`
+#[get("/time/projects?<all>")]
+pub async fn list_projects(
+    auth: Jwt,
+    all: Option<bool>,
+    db: SqliteDB,
+) -> Result<Json<Vec<Project>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let all = all.unwrap_or(false);
+    db.run(move |d| {
+        d.prepare(&format!(
+            "{SELECT_PROJECTS} WHERE ? OR active = 1 ORDER BY name"
+        ))?
+        .query_map(params![all], project_from_row)?
+        .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list projects: {e}");
+        Status::InternalServerError
+    })
+}
+
+#[derive(Deserialize)]
+pub struct NewProject {
+    name: String,
+    client: Option<String>,
+    cost_center: Option<String>,
+}
+
+#[post("/time/projects", data = "<project>")]
+pub async fn create_project(
+    _admin: Admin,
+    project: Json<NewProject>,
+    db: SqliteDB,
+) -> Result<Json<Project>, Status> {
+    let NewProject {
+        name,
+        client,
+        cost_center,
+    } = project.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Status::BadRequest);
+    }
+    db.run(move |d| {
+        d.execute(
+            "INSERT INTO projects (name, client, cost_center, active, created_at) \
+            VALUES (?, ?, ?, 1, ?)",
+            params![name, client, cost_center, Utc::now()],
+        )?;
+        get_project(d, d.last_insert_rowid())
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to create project: {e}");
+        Status::InternalServerError
+    })?
+    .map(Json)
+    .ok_or(Status::InternalServerError)
+}
+
+#[derive(Deserialize)]
+pub struct ProjectUpdate {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    client: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    cost_center: Option<Option<String>>,
+    active: Option<bool>,
+}
+
+/// Renames, reassigns or closes a project. Fields left out are kept.
+#[patch("/time/projects/<id>", data = "<update>")]
+pub async fn update_project(
+    _admin: Admin,
+    id: i64,
+    update: Json<ProjectUpdate>,
+    db: SqliteDB,
+) -> Result<Json<Project>, Status> {
+    let ProjectUpdate {
+        name,
+        client,
+        cost_center,
+        active,
+    } = update.into_inner();
+    let name = name.map(|name| name.trim().to_string());
+    if name.as_ref().is_some_and(String::is_empty) {
+        return Err(Status::BadRequest);
+    }
+    db.run(move |d| {
+        let tx = d.transaction()?;
+        if get_project(&tx, id)?.is_none() {
+            return Ok(None);
+        }
+        let set = |column: &str, value: &dyn rusqlite::ToSql| {
+            tx.execute(
+                &format!("UPDATE projects SET {column} = ? WHERE project_id = ?"),
+                params![value, id],
+            )
+        };
+        if let Some(name) = name {
+            set("name", &name)?;
+        }
+        if let Some(client) = client {
+            set("client", &client)?;
+        }
+        if let Some(cost_center) = cost_center {
+            set("cost_center", &cost_center)?;
+        }
+        if let Some(active) = active {
+            set("active", &active)?;
+        }
+        let project = get_project(&tx, id)?;
+        tx.commit()?;
+        Ok::<_, rusqlite::Error>(project)
+    })
+    .await
+    .map_err(|e| {
+        log::error!("Failed to update project {id}: {e}");
+        Status::InternalServerError
+    })?
+    .map(Json)
+    .ok_or(Status::NotFound)
+}
@@ -0,0 +1,261 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Days, NaiveDate, TimeDelta, Utc, Weekday};
+use chrono_tz::Tz;
+use rocket::tokio;
+use rusqlite::params;
+
+use super::{
+    approvals::managers_of,
+    board::clock_changed,
+    breaks::end_open_breaks,
+    corrections::{record_audit, AuditAction},
+    get_entry,
+    leave::with_company_holidays,
+    notify,
+    overtime::{OvertimePolicy, Threshold},
+    report::seconds_per_day,
+    time_entries, TimeConfig, TimeRange,
+};
+use crate::{
+    profiles::user_timezone,
+    types::{ServerAction, UserDB, UserID},
+    users::SYSTEM_USER,
+    SqliteDB,
+};
+
+/// How often open entries get checked on
+const CHECK_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(60);
+
+/// Note put on entries closed by `auto_clock_out`
+pub const AUTO_CLOCK_OUT_NOTE: &str = "auto clock-out";
+
+/// Open entries started more than `after` ago that nobody got reminded of yet. Marks them as
+/// reminded.
+pub fn due_reminders(
+    d: &mut rusqlite::Connection,
+    after: TimeDelta,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Vec<(UserID, TimeRange)>> {
+    let tx = d.transaction()?;
+    let ids: Vec<i64> = tx
+        .prepare(
+            "SELECT time_entry_id FROM time_entries \
+            WHERE end_time IS NULL AND reminded_at IS NULL AND start_time <= ?",
+        )?
+        .query_map(params![now - after], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut due = Vec::with_capacity(ids.len());
+    for id in ids {
+        tx.execute(
+            "UPDATE time_entries SET reminded_at = ? WHERE time_entry_id = ?",
+            params![now, id],
+        )?;
+        due.push(get_entry(&tx, id)?);
+    }
+    tx.commit()?;
+    Ok(due)
+}
+
+/// Closes entries left open for longer than `after`, ending them `after` past their start and
+/// flagging them for review
+pub fn auto_clock_out(
+    d: &mut rusqlite::Connection,
+    after: TimeDelta,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Vec<(UserID, TimeRange)>> {
+    let tx = d.transaction()?;
+    let ids: Vec<i64> = tx
+        .prepare(
+            "SELECT time_entry_id FROM time_entries WHERE end_time IS NULL AND start_time <= ?",
+        )?
+        .query_map(params![now - after], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut closed = Vec::with_capacity(ids.len());
+    for id in ids {
+        let (user, before) = get_entry(&tx, id)?;
+        let end = before.start.time + after;
+        end_open_breaks(&tx, id, end)?;
+        tx.execute(
+            "UPDATE time_entries SET end_time = ?, end_note = ?, flagged = 1 \
+            WHERE time_entry_id = ?",
+            params![end, AUTO_CLOCK_OUT_NOTE, id],
+        )?;
+        tx.execute(
+            "UPDATE timesheets SET clocked_in = 0, current_id = NULL WHERE current_id = ?",
+            params![id],
+        )?;
+        let (_, entry) = get_entry(&tx, id)?;
+        record_audit(
+            &tx,
+            &user,
+            AuditAction::AutoClockOut,
+            &SYSTEM_USER.into(),
+            None,
+            Some(&before),
+            Some(&entry),
+        )?;
+        closed.push((user, entry));
+    }
+    tx.commit()?;
+    Ok(closed)
+}
+
+async fn check_punches(db: &SqliteDB, user_db: &UserDB, config: &TimeConfig) {
+    let now = Utc::now();
+    let (remind_after, close_after) = (
+        TimeDelta::hours(config.remind_after_hours.into()),
+        TimeDelta::hours(config.auto_clock_out_hours.into()),
+    );
+    let (reminders, closed) = match db
+        .run(move |d| {
+            let closed = if close_after > TimeDelta::zero() {
+                auto_clock_out(d, close_after, now)?
+            } else {
+                Vec::new()
+            };
+            let reminders = if remind_after > TimeDelta::zero() {
+                due_reminders(d, remind_after, now)?
+            } else {
+                Vec::new()
+            };
+            let closed = closed
+                .into_iter()
+                .map(|(user, entry)| Ok((managers_of(d, &user)?, user, entry)))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok::<_, rusqlite::Error>((reminders, closed))
+        })
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => {
+            log::error!("Failed to check open time entries: {e}");
+            return;
+        }
+    };
+    for (user, entry) in reminders {
+        let hours = (now - entry.start.time).num_hours();
+        let mut content =
+            format!("You've been clocked in for {hours} hours, did you forget to clock out?");
+        if config.auto_clock_out_hours > 0 {
+            content += &format!(
+                " Entries still open after {} hours get closed for you.",
+                config.auto_clock_out_hours
+            );
+        }
+        notify(db, user_db, vec![user], content).await;
+    }
+    for (managers, user, entry) in closed {
+        notify(
+            db,
+            user_db,
+            vec![user.clone()],
+            format!(
+                "You were clocked out after {} hours. Request a correction if that's not when \
+                you stopped working.",
+                config.auto_clock_out_hours
+            ),
+        )
+        .await;
+        user_db
+            .send_to(ServerAction::TimedIn(false), std::slice::from_ref(&user))
+            .await;
+        user_db
+            .send_to(ServerAction::OnBreak(false), std::slice::from_ref(&user))
+            .await;
+        clock_changed(db, user_db, &user).await;
+        let content = format!(
+            "{user} was clocked out automatically, time entry {} needs a review",
+            entry.id
+        );
+        notify(db, user_db, managers, content).await;
+    }
+}
+
+/// People clocked in who are about to go into overtime, with the day or the first day of the
+/// week they'd go over on
+pub fn nearing_overtime(
+    d: &rusqlite::Connection,
+    policy: &OvertimePolicy,
+    now: DateTime<Utc>,
+) -> rusqlite::Result<Vec<(UserID, Threshold, NaiveDate)>> {
+    let users: Vec<UserID> = d
+        .prepare("SELECT user_id FROM timesheets WHERE clocked_in = 1")?
+        .query_map([], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let policy = &with_company_holidays(d, policy)?;
+    let mut nearing = Vec::new();
+    for user in users {
+        let tz = user_timezone(d, &user)?.unwrap_or(Tz::UTC);
+        let today = now.with_timezone(&tz).date_naive();
+        let entries = time_entries(d, &user)?;
+        // Only this week matters, and entries can run at most a day into it
+        let recent = entries
+            .iter()
+            .filter(|entry| entry.end_or(now) > now - TimeDelta::days(8));
+        let days = seconds_per_day(recent, tz, now);
+        match policy.approaching(&days, today) {
+            Some(Threshold::Daily) => nearing.push((user, Threshold::Daily, today)),
+            Some(Threshold::Weekly) => {
+                let monday = today.week(Weekday::Mon).first_day();
+                nearing.push((user, Threshold::Weekly, monday));
+            }
+            None => {}
+        }
+    }
+    Ok(nearing)
+}
+
+/// Warns people about to go into overtime and their managers, once a day or week.
+/// `warned` keeps track of who was already warned.
+async fn warn_overtime(
+    db: &SqliteDB,
+    user_db: &UserDB,
+    policy: &OvertimePolicy,
+    warned: &mut HashSet<(UserID, Threshold, NaiveDate)>,
+) {
+    let now = Utc::now();
+    let cutoff = now.date_naive() - Days::new(8);
+    warned.retain(|(_, _, date)| *date > cutoff);
+    let rules = policy.clone();
+    let nearing = match db
+        .run(move |d| {
+            nearing_overtime(d, &rules, now)?
+                .into_iter()
+                .map(|near| Ok((managers_of(d, &near.0)?, near)))
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await
+    {
+        Ok(nearing) => nearing,
+        Err(e) => {
+            log::error!("Failed to check for overtime: {e}");
+            return;
+        }
+    };
+    for (managers, near) in nearing {
+        if !warned.insert(near.clone()) {
+            continue;
+        }
+        let (user, threshold, _) = near;
+        let (limit, period) = match threshold {
+            Threshold::Daily => (policy.daily_hours, "today"),
+            Threshold::Weekly => (policy.weekly_hours, "this week"),
+        };
+        let content = format!("You're about to go over {limit} hours {period}");
+        notify(db, user_db, vec![user.clone()], content).await;
+        let content = format!("{user} is about to go over {limit} hours {period}");
+        notify(db, user_db, managers, content).await;
+    }
+}
+
+/// Reminds people who forgot to clock out, then clocks them out, and warns about overtime
+/// every `CHECK_INTERVAL`
+pub async fn watch_punches(db: SqliteDB, user_db: UserDB, config: TimeConfig) {
+    let mut warned = HashSet::new();
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        check_punches(&db, &user_db, &config).await;
+        warn_overtime(&db, &user_db, &config.overtime, &mut warned).await;
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use rocket::{http::Status, serde::json::Json, State};
+use serde::Serialize;
+
+use super::{
+    leave::{leave_days, with_company_holidays, LeaveDay},
+    overtime::PaySplit,
+    projects::project_names,
+    require_can_view,
+    rounding::rounded_entries,
+    time_entries, NaiveDateForm, TimeConfig, TimeRange,
+};
+use crate::{
+    auth::{tokens::Scope, Jwt},
+    profiles::user_timezone,
+    types::UserID,
+    SqliteDB,
+};
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct DayTotal {
+    pub date: NaiveDate,
+    pub hours: f64,
+    #[serde(flatten)]
+    pub pay: PaySplit,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct WeekTotal {
+    /// ISO week-numbering year, which can differ from the calendar year around new year
+    pub year: i32,
+    pub week: u32,
+    pub hours: f64,
+    #[serde(flatten)]
+    pub pay: PaySplit,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct PeriodTotal {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub hours: f64,
+    #[serde(flatten)]
+    pub pay: PaySplit,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct ProjectTotal {
+    /// `None` for time not tagged with a project
+    pub project: Option<i64>,
+    pub name: Option<String>,
+    pub hours: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TimeReport {
+    pub user: UserID,
+    /// Timezone days are counted in
+    pub timezone: String,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub total: f64,
+    /// `total` split into regular, overtime and double time hours
+    #[serde(flatten)]
+    pub pay: PaySplit,
+    /// Only days with time on them
+    pub days: Vec<DayTotal>,
+    pub weeks: Vec<WeekTotal>,
+    /// Periods cut off by `start` or `end` only count the days inside the report
+    pub pay_periods: Vec<PeriodTotal>,
+    pub projects: Vec<ProjectTotal>,
+    /// Entries started in the report that ran past `break_required_after_hours` without a
+    /// break
+    pub missing_breaks: Vec<i64>,
+    /// Approved leave and company holidays, which aren't part of `total`
+    pub leave: Vec<LeaveDay>,
+    /// Hours of paid leave and holidays
+    pub paid_leave: f64,
+}
+
+/// When `date` starts in `tz`, or an hour later where DST skips midnight
+pub(super) fn local_midnight(tz: Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_time(NaiveTime::MIN);
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(midnight + TimeDelta::hours(1)))
+                .earliest()
+        })
+        .map_or_else(|| midnight.and_utc(), |t| t.to_utc())
+}
+
+/// Seconds worked on each day in `tz`. Entries get split at midnight, open ones count up to
+/// `now` and unpaid breaks don't count.
+pub(super) fn seconds_per_day<'a>(
+    entries: impl IntoIterator<Item = &'a TimeRange>,
+    tz: Tz,
+    now: DateTime<Utc>,
+) -> BTreeMap<NaiveDate, i64> {
+    let mut days = BTreeMap::new();
+    for (mut from, end) in entries.into_iter().flat_map(|entry| entry.worked(now)) {
+        while from < end {
+            let date = from.with_timezone(&tz).date_naive();
+            let next = local_midnight(tz, date + Days::new(1)).min(end);
+            *days.entry(date).or_insert(0) += (next - from).num_seconds();
+            from = next;
+        }
+    }
+    days
+}
+
+pub fn build_report(
+    user: UserID,
+    entries: &[TimeRange],
+    tz: Tz,
+    config: &TimeConfig,
+    start: NaiveDate,
+    end: NaiveDate,
+    now: DateTime<Utc>,
+) -> TimeReport {
+    let worked = seconds_per_day(entries, tz, now);
+    // Split every day so weekly overtime counts the days before `start`
+    let splits = config.overtime.split(&worked);
+    let days: Vec<_> = worked
+        .range(start..=end)
+        .map(|(&date, &seconds)| DayTotal {
+            date,
+            hours: seconds as f64 / 3600.0,
+            pay: splits[&date],
+        })
+        .collect();
+    let mut pay = PaySplit::default();
+    let mut weeks: Vec<WeekTotal> = Vec::new();
+    let mut pay_periods: Vec<PeriodTotal> = Vec::new();
+    for day in &days {
+        let week = day.date.iso_week();
+        match weeks.last_mut() {
+            Some(last) if last.year == week.year() && last.week == week.week() => {
+                last.hours += day.hours;
+                last.pay += day.pay;
+            }
+            _ => weeks.push(WeekTotal {
+                year: week.year(),
+                week: week.week(),
+                hours: day.hours,
+                pay: day.pay,
+            }),
+        }
+        let (period_start, period_end) = config.pay_period(day.date);
+        match pay_periods.last_mut() {
+            Some(last) if last.start == period_start => {
+                last.hours += day.hours;
+                last.pay += day.pay;
+            }
+            _ => pay_periods.push(PeriodTotal {
+                start: period_start,
+                end: period_end,
+                hours: day.hours,
+                pay: day.pay,
+            }),
+        }
+        pay += day.pay;
+    }
+    let projects = entries
+        .iter()
+        .map(|entry| entry.project)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|project| {
+            let entries = entries.iter().filter(|entry| entry.project == project);
+            let seconds: i64 = seconds_per_day(entries, tz, now)
+                .range(start..=end)
+                .map(|(_, seconds)| seconds)
+                .sum();
+            (seconds > 0).then(|| ProjectTotal {
+                project,
+                name: None,
+                hours: seconds as f64 / 3600.0,
+            })
+        })
+        .collect();
+    let required = TimeDelta::hours(config.break_required_after_hours.into());
+    let missing_breaks = entries
+        .iter()
+        .filter(|entry| {
+            let date = entry.start.time.with_timezone(&tz).date_naive();
+            required > TimeDelta::zero()
+                && (start..=end).contains(&date)
+                && entry.breaks.is_empty()
+                && entry.end_or(now) - entry.start.time > required
+        })
+        .map(|entry| entry.id)
+        .collect();
+    TimeReport {
+        user,
+        timezone: tz.name().into(),
+        start,
+        end,
+        total: days.iter().fold(0.0, |total, day| total + day.hours),
+        pay,
+        days,
+        weeks,
+        pay_periods,
+        projects,
+        missing_breaks,
+        leave: Vec::new(),
+        paid_leave: 0.0,
+    }
+}
+
+impl TimeReport {
+    /// Fills in the names of the report's projects
+    pub fn name_projects(&mut self, names: &HashMap<i64, String>) {
+        for total in &mut self.projects {
+            total.name = total.project.and_then(|id| names.get(&id).cloned());
+        }
+    }
+
+    /// Adds leave and holidays from `leave_days` to the report
+    pub fn add_leave(&mut self, days: Vec<LeaveDay>) {
+        self.paid_leave = days
+            .iter()
+            .filter(|day| day.paid)
+            .fold(0.0, |total, day| total + day.hours);
+        self.leave = days;
+    }
+}
+
+/// Hours worked per day, ISO week and pay period in the user's timezone. Defaults to the
+/// current pay period so far.
+#[get("/time/report?<start>&<end>&<user>")]
+pub async fn time_report(
+    auth: Jwt,
+    start: Option<NaiveDateForm>,
+    end: Option<NaiveDateForm>,
+    user: Option<String>,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<Json<TimeReport>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let user = user.map_or_else(|| auth.name.clone(), UserID);
+    require_can_view(&auth, &user, &db).await?;
+    let (uid, overtime, rounding) = (
+        user.clone(),
+        config.overtime.clone(),
+        config.rounding.clone(),
+    );
+    let (entries, tz, names, overtime) = db
+        .run(move |d| {
+            let tz = user_timezone(d, &uid)?.unwrap_or(Tz::UTC);
+            let overtime = with_company_holidays(d, &overtime)?;
+            let entries = rounded_entries(d, &uid, &rounding, &time_entries(d, &uid)?, tz)?;
+            Ok::<_, rusqlite::Error>((entries, tz, project_names(d)?, overtime))
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get time entries for report: {e}");
+            Status::InternalServerError
+        })?;
+    let now = Utc::now();
+    let end = end.map_or_else(|| now.with_timezone(&tz).date_naive(), |date| *date);
+    let start = start.map_or_else(|| config.pay_period(end).0, |date| *date);
+    if start > end {
+        return Err(Status::BadRequest);
+    }
+    let config = TimeConfig {
+        overtime,
+        ..config.inner().clone()
+    };
+    let mut report = build_report(user.clone(), &entries, tz, &config, start, end, now);
+    report.name_projects(&names);
+    let work_days = config.work_days;
+    let leave = db
+        .run(move |d| leave_days(d, &user, &work_days, start, end))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get leave for report: {e}");
+            Status::InternalServerError
+        })?;
+    report.add_leave(leave);
+    Ok(Json(report))
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Offset, TimeDelta, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use super::{schedule::published_shifts, TimeRange};
+use crate::{types::UserID, users::department_of};
+
+/// How punches get rounded in reports and exports. Time entries always keep the real punches.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+pub struct RoundingRule {
+    /// Minutes punches get rounded to, 0 leaves them alone
+    #[serde(default)]
+    pub minutes: u32,
+    /// Punches up to this many minutes past a mark round down to it, later ones round up.
+    /// Defaults to rounding to the nearest mark, which is 7 for 15 minutes.
+    #[serde(default)]
+    pub round_down_minutes: Option<u32>,
+    /// Clock-ins up to this many minutes before a published shift count from its start
+    #[serde(default)]
+    pub early_clock_in_minutes: u32,
+}
+
+impl RoundingRule {
+    /// Rounds `time` to a mark counted from midnight in `tz`
+    pub fn round(&self, time: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        if self.minutes == 0 {
+            return time;
+        }
+        let step = i64::from(self.minutes) * 60;
+        let round_down = self.round_down_minutes.unwrap_or((self.minutes - 1) / 2);
+        let offset = i64::from(time.with_timezone(&tz).offset().fix().local_minus_utc());
+        let local = time.timestamp() + offset;
+        let past = local.rem_euclid(step);
+        let mark = if past < (i64::from(round_down) + 1) * 60 {
+            local - past
+        } else {
+            local - past + step
+        };
+        DateTime::from_timestamp(mark - offset, 0).unwrap_or(time)
+    }
+
+    /// Where a clock-in at `start` counts from: the start of a shift it's early to, otherwise
+    /// the rounded punch
+    fn round_start(&self, start: DateTime<Utc>, shifts: &[DateTime<Utc>], tz: Tz) -> DateTime<Utc> {
+        let grace = TimeDelta::minutes(self.early_clock_in_minutes.into());
+        shifts
+            .iter()
+            .find(|shift| start < **shift && **shift - start <= grace)
+            .copied()
+            .unwrap_or_else(|| self.round(start, tz))
+    }
+
+    /// `entries` with their punches and breaks rounded. Entries that are still open keep
+    /// running up to now. `shifts` are the starts of the user's published shifts.
+    pub fn apply(&self, entries: &[TimeRange], shifts: &[DateTime<Utc>], tz: Tz) -> Vec<TimeRange> {
+        if self.minutes == 0 && self.early_clock_in_minutes == 0 {
+            return entries.to_vec();
+        }
+        entries
+            .iter()
+            .map(|entry| {
+                let mut entry = entry.clone();
+                entry.start.time = self.round_start(entry.start.time, shifts, tz);
+                if let Some(end) = &mut entry.end {
+                    end.time = self.round(end.time, tz).max(entry.start.time);
+                }
+                for pause in &mut entry.breaks {
+                    pause.start = self.round(pause.start, tz);
+                    pause.end = pause.end.map(|end| self.round(end, tz).max(pause.start));
+                }
+                entry
+            })
+            .collect()
+    }
+}
+
+/// The company-wide rounding rule, and departments that round differently
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+pub struct RoundingPolicy {
+    #[serde(flatten)]
+    pub rule: RoundingRule,
+    /// Rules replacing the company-wide one for everyone in a department
+    #[serde(default)]
+    pub departments: HashMap<String, RoundingRule>,
+}
+
+impl RoundingPolicy {
+    pub fn rule_for(&self, department: Option<&str>) -> &RoundingRule {
+        department
+            .and_then(|department| self.departments.get(department))
+            .unwrap_or(&self.rule)
+    }
+}
+
+/// `entries` of `user` rounded by the rule of their department, for reports and exports
+pub fn rounded_entries(
+    d: &rusqlite::Connection,
+    user: &UserID,
+    policy: &RoundingPolicy,
+    entries: &[TimeRange],
+    tz: Tz,
+) -> rusqlite::Result<Vec<TimeRange>> {
+    let department = department_of(d, user)?;
+    let rule = policy.rule_for(department.as_deref());
+    let shifts: Vec<_> = if rule.early_clock_in_minutes > 0 {
+        published_shifts(d, user)?
+            .into_iter()
+            .map(|shift| shift.start)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    Ok(rule.apply(entries, &shifts, tz))
+}
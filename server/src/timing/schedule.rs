@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Days, TimeDelta, Utc};
+use chrono_tz::Tz;
+use rocket::{
+    http::{ContentType, Status},
+    serde::json::Json,
+    State,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    approvals::manages,
+    ical::{calendar, Event, When},
+    notify,
+    report::local_midnight,
+    require_can_view, time_entries, NaiveDateForm, TimeConfig, TimeRange,
+};
+use crate::{
+    auth::{is_admin, tokens::Scope, Jwt},
+    profiles::user_timezone,
+    types::{UserDB, UserID},
+    SqliteDB,
+};
+
+/// How someone's punches lined up with a shift that started
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct Attendance {
+    /// First punch in during the shift, `None` for no-shows and people who didn't show up
+    /// yet
+    pub clocked_in: Option<DateTime<Utc>>,
+    /// Last punch out, `None` while still clocked in
+    pub clocked_out: Option<DateTime<Utc>>,
+    /// Minutes past the start of the shift, 0 within `late_grace_minutes`
+    pub late_minutes: i64,
+    /// Minutes before the end of the shift, 0 within `late_grace_minutes`
+    pub left_early_minutes: i64,
+    pub no_show: bool,
+}
+
+/// Compares `entries` to a shift from `start` to `end`. `None` until the shift is
+/// `grace` past its start without a punch, and a no-show once it ends without one.
+pub fn attendance(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    entries: &[TimeRange],
+    grace: TimeDelta,
+    now: DateTime<Utc>,
+) -> Option<Attendance> {
+    let worked: Vec<_> = entries
+        .iter()
+        .filter(|entry| entry.start.time < end && entry.end_or(now) > start)
+        .collect();
+    let minutes = |late: TimeDelta| if late > grace { late.num_minutes() } else { 0 };
+    let Some(clocked_in) = worked.iter().map(|entry| entry.start.time).min() else {
+        // Late until the shift is over, then a no-show
+        return (now >= start + grace).then(|| Attendance {
+            clocked_in: None,
+            clocked_out: None,
+            late_minutes: if now < end { minutes(now - start) } else { 0 },
+            left_early_minutes: 0,
+            no_show: now >= end,
+        });
+    };
+    let clocked_out = worked
+        .iter()
+        .map(|entry| entry.end.as_ref().map(|end| end.time))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|ends| ends.into_iter().max());
+    Some(Attendance {
+        clocked_in: Some(clocked_in),
+        clocked_out,
+        late_minutes: minutes(clocked_in - start),
+        left_early_minutes: clocked_out.map_or(0, |out| minutes(end - out)),
+        no_show: false,
+    })
+}
+
+/// Time someone is scheduled to work
+#[derive(Serialize, Debug, Clone)]
+pub struct Shift {
+    pub id: i64,
+    pub user: UserID,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// What they'll be doing, like `cashier`
+    pub role: Option<String>,
+    pub note: Option<String>,
+    pub created_by: UserID,
+    /// Only published shifts are shown to the people working them
+    pub published_at: Option<DateTime<Utc>>,
+    /// Filled in for shifts that started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attendance: Option<Attendance>,
+}
+
+const SELECT_SHIFTS: &str = "SELECT shift_id, user_id, start_time, end_time, role, note, \
+    created_by, published_at FROM shifts";
+
+fn shift_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Shift> {
+    Ok(Shift {
+        id: row.get(0)?,
+        user: row.get(1)?,
+        start: row.get(2)?,
+        end: row.get(3)?,
+        role: row.get(4)?,
+        note: row.get(5)?,
+        created_by: row.get(6)?,
+        published_at: row.get(7)?,
+        attendance: None,
+    })
+}
+
+fn get_shift(d: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<Shift>> {
+    d.query_row(
+        &format!("{SELECT_SHIFTS} WHERE shift_id = ?"),
+        params![id],
+        shift_from_row,
+    )
+    .optional()
+}
+
+/// Published shifts of `user`, oldest first
+pub fn published_shifts(d: &rusqlite::Connection, user: &UserID) -> rusqlite::Result<Vec<Shift>> {
+    d.prepare(&format!(
+        "{SELECT_SHIFTS} WHERE user_id = ? AND published_at IS NOT NULL ORDER BY start_time"
+    ))?
+    .query_map(params![user.0], shift_from_row)?
+    .collect()
+}
+
+/// The shift as a calendar event
+pub fn shift_event(shift: &Shift) -> Event {
+    Event {
+        uid: format!("shift-{}@melangerie", shift.id),
+        when: When::Timed(shift.start, shift.end),
+        summary: shift.role.clone().unwrap_or_else(|| "Shift".into()),
+        description: shift.note.clone(),
+    }
+}
+
+/// Managers schedule the people they manage, admins anyone
+async fn can_schedule(scheduler: &UserID, user: &UserID, db: &SqliteDB) -> Result<bool, Status> {
+    let (id, uid) = (scheduler.clone(), user.clone());
+    let manager = db.run(move |d| manages(d, &id, &uid)).await.map_err(|e| {
+        log::error!("Failed to check managers: {e}");
+        Status::InternalServerError
+    })?;
+    Ok(manager || is_admin(db, scheduler).await)
+}
+
+/// Shifts of `user` (defaults to the authenticated user) starting from `start` to `end` in
+/// their timezone, which default to the current pay period. People see their published
+/// shifts, managers and admins their drafts too.
+#[get("/time/shifts?<user>&<start>&<end>")]
+pub async fn list_shifts(
+    auth: Jwt,
+    user: Option<String>,
+    start: Option<NaiveDateForm>,
+    end: Option<NaiveDateForm>,
+    db: SqliteDB,
+    config: &State<TimeConfig>,
+) -> Result<Json<Vec<Shift>>, Status> {
+    auth.require(Scope::TimeRead)?;
+    let user = user.map_or_else(|| auth.name.clone(), UserID);
+    require_can_view(&auth, &user, &db).await?;
+    let drafts = can_schedule(&auth.name, &user, &db).await?;
+    let (config, now) = (config.inner().clone(), Utc::now());
+    db.run(move |d| {
+        let tz = user_timezone(d, &user)?.unwrap_or(Tz::UTC);
+        let (period_start, period_end) = config.pay_period(now.with_timezone(&tz).date_naive());
+        let from = local_midnight(tz, start.map_or(period_start, |date| *date));
+        let to = local_midnight(tz, end.map_or(period_end, |date| *date) + Days::new(1));
+        let mut shifts = d
+            .prepare(&format!(
+                "{SELECT_SHIFTS} WHERE user_id = ?1 AND start_time >= ?2 AND start_time < ?3 \
+                AND (?4 OR published_at IS NOT NULL) ORDER BY start_time"
+            ))?
+            .query_map(params![user.0, from, to, drafts], shift_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let entries = time_entries(d, &user)?;
+        let grace = TimeDelta::minutes(config.late_grace_minutes.into());
+        for shift in &mut shifts {
+            shift.attendance = attendance(shift.start, shift.end, &entries, grace, now);
+        }
+        Ok::<_, rusqlite::Error>(shifts)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        log::error!("Failed to list shifts: {e}");
+        Status::InternalServerError
+    })
+}
+
+/// Published shifts of `user` (defaults to the authenticated user) as an iCalendar file
+#[get("/time/shifts.ics?<user>")]
+pub async fn shifts_ics(
+    auth: Jwt,
+    user: Option<String>,
+    db: SqliteDB,
+) -> Result<(ContentType, String), Status> {
+    auth.require(Scope::TimeRead)?;
+    let user = user.map_or_else(|| auth.name.clone(), UserID);
+    require_can_view(&auth, &user, &db).await?;
+    let uid = user.clone();
+    let shifts = db
+        .run(move |d| published_shifts(d, &uid))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to get shifts for calendar: {e}");
+            Status::InternalServerError
+        })?;
+    let events: Vec<_> = shifts.iter().map(shift_event).collect();
+    Ok((
+        ContentType::Calendar,
+        calendar(&format!("Shifts of {user}"), &events),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct NewShift {
+    user: UserID,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    role: Option<String>,
+    note: Option<String>,
+}
+
+/// Schedules a shift, which stays a draft until it's published
+#[post("/time/shifts", data = "<shift>")]
+pub async fn create_shift(
+    auth: Jwt,
+    shift: Json<NewShift>,
+    db: SqliteDB,
+) -> Result<Json<Shift>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let NewShift {
+        user,
+        start,
+        end,
+        role,
+        note,
+    } = shift.into_inner();
+    if start >= end || end - start > TimeDelta::days(1) {
+        return Err(Status::BadRequest);
+    }
+    if !can_schedule(&auth.name, &user, &db).await? {
+        return Err(Status::Forbidden);
+    }
+    let role = role
+        .map(|role| role.trim().to_string())
+        .filter(|role| !role.is_empty());
+    let scheduler = auth.name.clone();
+    db.run(move |d| {
+        let exists: bool = d.query_row(
+            "SELECT EXISTS (SELECT 1 FROM users WHERE user_id = ?)",
+            params![user.0],
+            |r| r.get(0),
+        )?;
+        if !exists {
+            return Ok(Err(Status::NotFound));
+        }
+        let overlaps: bool = d.query_row(
+            "SELECT EXISTS (SELECT 1 FROM shifts \
+            WHERE user_id = ? AND start_time < ? AND end_time > ?)",
+            params![user.0, end, start],
+            |r| r.get(0),
+        )?;
+        if overlaps {
+            return Ok(Err(Status::Conflict));
+        }
+        d.execute(
+            "INSERT INTO shifts (user_id, start_time, end_time, role, note, created_by) \
+            VALUES (?, ?, ?, ?, ?, ?)",
+            params![user.0, start, end, role, note, scheduler.0],
+        )?;
+        Ok(get_shift(d, d.last_insert_rowid())?.ok_or(Status::InternalServerError))
+    })
+    .await
+    .map_err(|e: rusqlite::Error| {
+        log::error!("Failed to create shift: {e}");
+        Status::InternalServerError
+    })?
+    .map(Json)
+}
+
+/// Cancels a shift, letting its user know if it was published
+#[delete("/time/shifts/<id>")]
+pub async fn delete_shift(
+    auth: Jwt,
+    id: i64,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Status, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let log_err = |e: rusqlite::Error| {
+        log::error!("Failed to delete shift {id}: {e}");
+        Status::InternalServerError
+    };
+    let shift = db
+        .run(move |d| get_shift(d, id))
+        .await
+        .map_err(log_err)?
+        .ok_or(Status::NotFound)?;
+    if !can_schedule(&auth.name, &shift.user, &db).await? {
+        return Err(Status::Forbidden);
+    }
+    let user = shift.user.clone();
+    let tz = db
+        .run(move |d| {
+            d.execute("DELETE FROM shifts WHERE shift_id = ?", params![id])?;
+            user_timezone(d, &user)
+        })
+        .await
+        .map_err(log_err)?
+        .unwrap_or(Tz::UTC);
+    if shift.published_at.is_some() {
+        let start = shift.start.with_timezone(&tz).format("%Y-%m-%d %H:%M");
+        let content = format!("Your shift on {start} was cancelled");
+        notify(&db, user_db, vec![shift.user.clone()], content).await;
+    }
+    Ok(Status::NoContent)
+}
+
+#[derive(Deserialize)]
+pub struct Publication {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+/// Publishes the drafts starting from `from` to `to` of everyone the authenticated user can
+/// schedule, notifying each of them. Returns the published shifts.
+#[post("/time/shifts/publish", data = "<publication>")]
+pub async fn publish_shifts(
+    auth: Jwt,
+    publication: Json<Publication>,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+) -> Result<Json<Vec<Shift>>, Status> {
+    auth.require(Scope::TimeWrite)?;
+    let Publication { from, to } = publication.into_inner();
+    if from >= to {
+        return Err(Status::BadRequest);
+    }
+    let admin = is_admin(&db, &auth.name).await;
+    let publisher = auth.name.clone();
+    let (shifts, zones) = db
+        .run(move |d| {
+            let tx = d.transaction()?;
+            let now = Utc::now();
+            let shifts = tx
+                .prepare(&format!(
+                    "{SELECT_SHIFTS} WHERE published_at IS NULL \
+                    AND start_time >= ?1 AND start_time < ?2 AND (?3 OR user_id IN \
+                      (SELECT user_id FROM user_managers WHERE manager_id = ?4)) \
+                    ORDER BY start_time"
+                ))?
+                .query_map(params![from, to, admin, publisher.0], shift_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut zones = BTreeMap::new();
+            for shift in &shifts {
+                tx.execute(
+                    "UPDATE shifts SET published_at = ? WHERE shift_id = ?",
+                    params![now, shift.id],
+                )?;
+                if !zones.contains_key(&shift.user.0) {
+                    let tz = user_timezone(&tx, &shift.user)?.unwrap_or(Tz::UTC);
+                    zones.insert(shift.user.0.clone(), tz);
+                }
+            }
+            let shifts = shifts
+                .into_iter()
+                .map(|shift| Shift {
+                    published_at: Some(now),
+                    ..shift
+                })
+                .collect::<Vec<_>>();
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>((shifts, zones))
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to publish shifts: {e}");
+            Status::InternalServerError
+        })?;
+    for (user, tz) in zones {
+        let times: Vec<_> = shifts
+            .iter()
+            .filter(|shift| shift.user.0 == user)
+            .map(|shift| {
+                let (start, end) = (shift.start.with_timezone(&tz), shift.end.with_timezone(&tz));
+                let mut time = format!(
+                    "{} to {}",
+                    start.format("%a %Y-%m-%d %H:%M"),
+                    end.format("%H:%M")
+                );
+                if let Some(role) = &shift.role {
+                    time += &format!(" ({role})");
+                }
+                time
+            })
+            .collect();
+        let content = format!("Your schedule was published: {}", times.join(", "));
+        notify(&db, user_db, vec![UserID(user)], content).await;
+    }
+    Ok(Json(shifts))
+}
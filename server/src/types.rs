@@ -8,7 +8,9 @@ use rocket::{
 use rusqlite::types::FromSql;
 use serde::{Deserialize, Serialize};
 
-use crate::SqliteDB;
+use crate::{
+    notifications::Delivery, profiles::ProfileSummary, timing::board::OnTheClock, SqliteDB,
+};
 
 #[derive(Default, Clone)]
 pub struct UserDB(Arc<RwLock<HashMap<UserID, User>>>);
@@ -26,11 +28,21 @@ impl UserDB {
     pub async fn add_user(&self, user: User) {
         self.write().await.insert(user.name.clone(), user);
     }
-    pub async fn write_to(&self, message: ChatMessage, users: &[UserID]) {
+    pub async fn write_to(&self, message: ChatMessage, deliveries: &[Delivery]) {
         let udb = self.read().await;
-        for user in users {
+        for Delivery {
+            user,
+            notify,
+            flagged,
+        } in deliveries
+        {
             if let Some(UserStatus::Active(sender)) = udb.get(user).map(|u| &u.status) {
-                if let Err(e) = sender.send(ServerAction::Message(message.clone())) {
+                let message = ChatMessage {
+                    notify: *notify && !flagged,
+                    flagged: *flagged,
+                    ..message.clone()
+                };
+                if let Err(e) = sender.send(ServerAction::Message(message)) {
                     log::error!("Failed to send message to user: {e:?}");
                 };
             } else {
@@ -38,15 +50,37 @@ impl UserDB {
             }
         }
     }
+    /// Sends `action` to whichever of `users` are connected
+    pub async fn send_to(&self, action: ServerAction, users: &[UserID]) {
+        let udb = self.read().await;
+        for user in users {
+            if let Some(UserStatus::Active(sender)) = udb.get(user).map(|u| &u.status) {
+                if let Err(e) = sender.send(action.clone()) {
+                    log::error!("Failed to send action to user: {e:?}");
+                };
+            }
+        }
+    }
+    /// Connected users watching the board of who's on the clock
+    pub async fn board_watchers(&self) -> Vec<UserID> {
+        self.read()
+            .await
+            .values()
+            .filter(|user| user.watching_board && matches!(user.status, UserStatus::Active(_)))
+            .map(|user| user.name.clone())
+            .collect()
+    }
+    /// Drops the user entirely, which also drops the sender for their live socket
+    pub async fn remove_user(&self, id: &UserID) -> Option<User> {
+        self.write().await.remove(id)
+    }
     pub async fn close_user(&self, id: &UserID) {
         if let Some(user) = self.write().await.get_mut(id) {
             user.status = UserStatus::Inactive;
+            user.watching_board = false;
             log::info!("User disconnected: {:?}", id);
         }
     }
-    pub async fn all_users(&self) -> Vec<UserID> {
-        self.read().await.keys().cloned().collect()
-    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -55,6 +89,9 @@ pub struct User {
     pub status: UserStatus,
     // Hashed password
     pub password: String,
+    /// Gets live updates of who's on the clock, until they disconnect
+    #[serde(skip)]
+    pub watching_board: bool,
 }
 
 fn user_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<User> {
@@ -62,6 +99,7 @@ fn user_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<User> {
         name: row.get(0)?,
         status: UserStatus::Inactive,
         password: row.get(1)?,
+        watching_board: false,
     })
 }
 
@@ -174,6 +212,12 @@ pub struct ChatMessage {
     pub room: ChatRoomID,
     pub content: String,
     pub timestamp: f64,
+    /// Whether the recipient's client should alert them, set per recipient by the server
+    #[serde(default)]
+    pub notify: bool,
+    /// The recipient has blocked the sender, so clients can hide the message
+    #[serde(default)]
+    pub flagged: bool,
 }
 
 pub fn message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ChatMessage> {
@@ -183,6 +227,8 @@ pub fn message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ChatMessage
         room: row.get(1)?,
         content: row.get(2)?,
         timestamp: row.get(3)?,
+        notify: false,
+        flagged: false,
     })
 }
 
@@ -209,8 +255,18 @@ pub enum ServerAction {
         adder: Option<UserID>,
         added: UserID,
     },
-    List(Vec<UserID>),
+    List(Vec<ProfileSummary>),
+    /// Someone sharing a room changed their profile
+    Profile(ProfileSummary),
     TimedIn(bool),
+    OnBreak(bool),
+    /// Everyone on the clock a manager can see, sent when they start watching the board
+    Board(Vec<OnTheClock>),
+    /// Someone on the board punched, `entry` is `None` once they clocked out
+    BoardUpdate {
+        user: UserID,
+        entry: Option<OnTheClock>,
+    },
     Leave((ChatRoomID, UserID)),
     Error(String),
 }
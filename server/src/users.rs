@@ -0,0 +1,496 @@
+use crate::{
+    auth::{is_admin, tokens::Scope, Admin, Jwt},
+    logger::Log,
+    profiles::{get_profile, Profile},
+    timing::{has_locked_time, time_entries, TimeRange},
+    types::{
+        message_from_row, ChatMessage, ChatRoomID, ServerAction, User, UserDB, UserID, UserStatus,
+    },
+    SqliteDB,
+};
+use chrono::{DateTime, Utc};
+use rocket::{
+    http::{Header, Status},
+    serde::json::Json,
+    State,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// Author that anonymized messages get reassigned to
+const DELETED_USER: &str = "deleted";
+
+/// Reserved actor of automated changes, can never log in
+pub(crate) const SYSTEM_USER: &str = "system";
+
+/// What happens to a user's messages when their account is deactivated or deleted
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum MessageHandling {
+    Keep,
+    Anonymize,
+    Delete,
+}
+
+fn handle_messages(
+    d: &rusqlite::Connection,
+    id: &UserID,
+    messages: MessageHandling,
+) -> rusqlite::Result<usize> {
+    match messages {
+        MessageHandling::Keep => Ok(0),
+        MessageHandling::Anonymize => d.execute(
+            "UPDATE messages SET user_id = ? WHERE user_id = ?",
+            params![DELETED_USER, id.0],
+        ),
+        MessageHandling::Delete => {
+            d.execute("DELETE FROM messages WHERE user_id = ?", params![id.0])
+        }
+    }
+}
+
+/// Removes the user from the user db, which closes their socket if they are connected
+async fn disconnect(user_db: &UserDB, id: &UserID, reason: &str) {
+    let Some(user) = user_db.remove_user(id).await else {
+        return;
+    };
+    if let UserStatus::Active(tx) = user.status {
+        let _ = tx.send(ServerAction::Error(reason.into()));
+    }
+}
+
+/// Keeps `id` from logging in or using their tokens, returning whether they were active
+pub(crate) fn deactivate_account(
+    d: &mut rusqlite::Connection,
+    id: &UserID,
+    messages: MessageHandling,
+) -> rusqlite::Result<bool> {
+    let tx = d.transaction()?;
+    let updated = tx.execute(
+        "UPDATE users SET active = 0 WHERE user_id = ? AND active = 1",
+        params![id.0],
+    )? == 1;
+    if updated {
+        handle_messages(&tx, id, messages)?;
+    }
+    tx.commit()?;
+    Ok(updated)
+}
+
+#[post("/<id>/deactivate?<messages>")]
+pub async fn deactivate_user(
+    id: UserID,
+    messages: Option<MessageHandling>,
+    admin: Admin,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    log: &State<Log>,
+) -> Status {
+    // Admins can't lock themselves out
+    if id == admin.0.name {
+        return Status::BadRequest;
+    }
+    let messages = messages.unwrap_or(MessageHandling::Keep);
+    let uid = id.clone();
+    match db.run(move |d| deactivate_account(d, &uid, messages)).await {
+        Ok(false) => return Status::NotFound,
+        Ok(true) => {}
+        Err(e) => {
+            log::error!("Failed to deactivate {id}: {e}");
+            return Status::InternalServerError;
+        }
+    }
+    disconnect(user_db, &id, "Your account has been deactivated").await;
+    let _ = log
+        .write(format!(
+            "{} deactivated {id} (messages: {messages:?})",
+            admin.0.name
+        ))
+        .await;
+    Status::Ok
+}
+
+#[post("/<id>/reactivate")]
+pub async fn reactivate_user(
+    id: UserID,
+    admin: Admin,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    log: &State<Log>,
+) -> Status {
+    if id.0 == DELETED_USER || id.0 == SYSTEM_USER {
+        return Status::BadRequest;
+    }
+    let uid = id.clone();
+    let password: String = match db
+        .run(move |d| {
+            let updated = d.execute(
+                "UPDATE users SET active = 1 WHERE user_id = ? AND active = 0",
+                params![uid.0],
+            )?;
+            if updated == 0 {
+                return Err(rusqlite::Error::QueryReturnedNoRows);
+            }
+            d.query_row(
+                "SELECT password FROM users WHERE user_id = ?",
+                params![uid.0],
+                |r| r.get(0),
+            )
+        })
+        .await
+    {
+        Ok(p) => p,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Status::NotFound,
+        Err(e) => {
+            log::error!("Failed to reactivate {id}: {e}");
+            return Status::InternalServerError;
+        }
+    };
+    user_db
+        .add_user(User {
+            name: id.clone(),
+            status: UserStatus::Inactive,
+            watching_board: false,
+            password,
+        })
+        .await;
+    let _ = log
+        .write(format!("{} reactivated {id}", admin.0.name))
+        .await;
+    Status::Ok
+}
+
+pub enum DeleteError {
+    /// Some of their time falls in a locked pay period, which payroll still needs
+    Locked,
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for DeleteError {
+    fn from(e: rusqlite::Error) -> Self {
+        DeleteError::Db(e)
+    }
+}
+
+/// Removes the account `id` with everything but its audit trail, returning whether it existed
+pub(crate) fn delete_account(
+    d: &mut rusqlite::Connection,
+    id: &UserID,
+    messages: MessageHandling,
+) -> Result<bool, DeleteError> {
+    let tx = d.transaction()?;
+    if has_locked_time(&tx, id)? {
+        return Err(DeleteError::Locked);
+    }
+    handle_messages(&tx, id, messages)?;
+    tx.execute(
+        "DELETE FROM chatroom_users WHERE user_id = ?",
+        params![id.0],
+    )?;
+    tx.execute(
+        "DELETE FROM time_breaks WHERE time_entry_id IN (SELECT te.time_entry_id \
+        FROM time_entries te INNER JOIN timesheets ts ON ts.timesheet_id = te.timesheet_id \
+        WHERE ts.user_id = ?)",
+        params![id.0],
+    )?;
+    tx.execute(
+        "DELETE FROM time_entries WHERE timesheet_id IN \
+        (SELECT timesheet_id FROM timesheets WHERE user_id = ?)",
+        params![id.0],
+    )?;
+    tx.execute("DELETE FROM timesheets WHERE user_id = ?", params![id.0])?;
+    // time_entry_audit and kiosk_punches are append-only, so their punches stay on record
+    for table in [
+        "user_profiles",
+        "dnd_windows",
+        "room_notifications",
+        "keyword_alerts",
+        "timesheet_approvals",
+        "time_corrections",
+        "leave_balances",
+        "leave_requests",
+        "shifts",
+        "calendar_feeds",
+        "kiosk_pins",
+        "oidc_identities",
+        // An account made again under the same name mustn't get these back
+        "api_tokens",
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE user_id = ?"),
+            params![id.0],
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM user_blocks WHERE blocker_id = ?1 OR blocked_id = ?1",
+        params![id.0],
+    )?;
+    tx.execute(
+        "DELETE FROM user_managers WHERE user_id = ?1 OR manager_id = ?1",
+        params![id.0],
+    )?;
+    let deleted = tx.execute("DELETE FROM users WHERE user_id = ?", params![id.0])?;
+    tx.commit()?;
+    Ok(deleted == 1)
+}
+
+/// Permanently removes an account along with its room memberships and time entries.
+/// Messages have to be either anonymized or deleted since they can't outlive their author.
+/// Accounts with time in a locked pay period can only be deactivated.
+#[delete("/<id>?<messages>")]
+pub async fn delete_user(
+    id: UserID,
+    messages: MessageHandling,
+    admin: Admin,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    log: &State<Log>,
+) -> Status {
+    if id == admin.0.name
+        || id.0 == DELETED_USER
+        || id.0 == SYSTEM_USER
+        || messages == MessageHandling::Keep
+    {
+        return Status::BadRequest;
+    }
+    let uid = id.clone();
+    match db.run(move |d| delete_account(d, &uid, messages)).await {
+        Ok(true) => {}
+        Ok(false) => return Status::NotFound,
+        Err(DeleteError::Locked) => return Status::Locked,
+        Err(DeleteError::Db(e)) => {
+            log::error!("Failed to delete {id}: {e}");
+            return Status::InternalServerError;
+        }
+    }
+    disconnect(user_db, &id, "Your account has been deleted").await;
+    let _ = log
+        .write(format!(
+            "{} deleted {id} (messages: {messages:?})",
+            admin.0.name
+        ))
+        .await;
+    Status::Ok
+}
+
+#[derive(Deserialize)]
+pub struct NewServiceAccount {
+    name: UserID,
+}
+
+/// Creates a user for bots and integrations, which can't log in and only act through API tokens
+#[post("/service", data = "<account>")]
+pub async fn create_service_account(
+    account: Json<NewServiceAccount>,
+    admin: Admin,
+    db: SqliteDB,
+    user_db: &State<UserDB>,
+    log: &State<Log>,
+) -> Status {
+    let id = account.into_inner().name;
+    let uid = id.clone();
+    // Not a valid bcrypt hash, so password logins always fail
+    let password = String::from("!");
+    let pass = password.clone();
+    match db
+        .run(move |d| {
+            d.execute(
+                "INSERT INTO users (user_id, password, service_account) VALUES (?, ?, 1)",
+                params![uid.0, pass],
+            )
+        })
+        .await
+    {
+        Ok(_) => {}
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            return Status::Conflict
+        }
+        Err(e) => {
+            log::error!("Failed to create service account {id}: {e}");
+            return Status::InternalServerError;
+        }
+    }
+    user_db
+        .add_user(User {
+            name: id.clone(),
+            status: UserStatus::Inactive,
+            watching_board: false,
+            password,
+        })
+        .await;
+    let _ = log
+        .write(format!("{} created service account {id}", admin.0.name))
+        .await;
+    Status::Ok
+}
+
+/// The department a user is in, which decides things like how their time gets rounded
+pub fn department_of(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<Option<String>> {
+    Ok(d.query_row(
+        "SELECT department FROM users WHERE user_id = ?",
+        params![id.0],
+        |r| r.get(0),
+    )
+    .optional()?
+    .flatten())
+}
+
+#[derive(Deserialize)]
+pub struct Department {
+    department: Option<String>,
+}
+
+/// Moves a user to a department, or out of theirs with `null`
+#[put("/<id>/department", data = "<department>")]
+pub async fn set_department(
+    id: UserID,
+    department: Json<Department>,
+    admin: Admin,
+    db: SqliteDB,
+    log: &State<Log>,
+) -> Status {
+    let department = department
+        .into_inner()
+        .department
+        .map(|department| department.trim().to_owned())
+        .filter(|department| !department.is_empty());
+    let (uid, dept) = (id.clone(), department.clone());
+    match db
+        .run(move |d| {
+            d.execute(
+                "UPDATE users SET department = ? WHERE user_id = ?",
+                params![dept, uid.0],
+            )
+        })
+        .await
+    {
+        Ok(0) => return Status::NotFound,
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("Failed to set the department of {id}: {e}");
+            return Status::InternalServerError;
+        }
+    }
+    let department = department.as_deref().unwrap_or("no department");
+    let _ = log
+        .write(format!("{} moved {id} to {department}", admin.0.name))
+        .await;
+    Status::Ok
+}
+
+#[derive(Deserialize)]
+pub struct AdminFlag {
+    admin: bool,
+}
+
+/// Makes a user an admin, or takes it away again. Admins can't demote themselves, so there's
+/// always one left.
+#[put("/<id>/admin", data = "<flag>")]
+pub async fn set_admin(
+    id: UserID,
+    flag: Json<AdminFlag>,
+    admin: Admin,
+    db: SqliteDB,
+    log: &State<Log>,
+) -> Status {
+    let promote = flag.into_inner().admin;
+    if !promote && id == admin.0.name {
+        return Status::BadRequest;
+    }
+    let uid = id.clone();
+    match db
+        .run(move |d| {
+            d.execute(
+                "UPDATE users SET is_admin = ? WHERE user_id = ? AND NOT service_account",
+                params![promote, uid.0],
+            )
+        })
+        .await
+    {
+        Ok(0) => return Status::NotFound,
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("Failed to change whether {id} is an admin: {e}");
+            return Status::InternalServerError;
+        }
+    }
+    let change = if promote {
+        format!("made {id} an admin")
+    } else {
+        format!("took admin from {id}")
+    };
+    let _ = log.write(format!("{} {change}", admin.0.name)).await;
+    Status::Ok
+}
+
+/// Everything stored about a single user, for data export requests
+#[derive(Serialize)]
+pub(crate) struct UserExport {
+    user: UserID,
+    active: bool,
+    exported_at: DateTime<Utc>,
+    profile: Option<Profile>,
+    rooms: Vec<ChatRoomID>,
+    messages: Vec<ChatMessage>,
+    time_entries: Vec<TimeRange>,
+}
+
+/// Everything stored about `id`
+pub(crate) fn user_export(d: &rusqlite::Connection, id: &UserID) -> rusqlite::Result<UserExport> {
+    let active = d.query_row(
+        "SELECT active FROM users WHERE user_id = ?",
+        params![id.0],
+        |r| r.get(0),
+    )?;
+    let rooms = d
+        .prepare("SELECT chatroom_id FROM chatroom_users WHERE user_id = ?")?
+        .query_map(params![id.0], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let messages = d
+        .prepare(
+            "SELECT user_id, chatroom_id, message, created_at FROM messages \
+            WHERE user_id = ? ORDER BY created_at",
+        )?
+        .query_map(params![id.0], message_from_row)?
+        .collect::<Result<_, _>>()?;
+    Ok(UserExport {
+        active,
+        rooms,
+        messages,
+        time_entries: time_entries(d, id)?,
+        profile: get_profile(d, id)?,
+        exported_at: Utc::now(),
+        user: id.clone(),
+    })
+}
+
+#[derive(Responder)]
+#[response(content_type = "json")]
+pub struct Archive(String, Header<'static>);
+
+#[get("/<id>/export")]
+pub async fn export_user(id: UserID, user: Jwt, db: SqliteDB) -> Result<Archive, Status> {
+    user.require(Scope::ChatRead)?;
+    user.require(Scope::TimeRead)?;
+    if user.name != id && !is_admin(&db, &user.name).await {
+        return Err(Status::Forbidden);
+    }
+    let uid = id.clone();
+    let export = db
+        .run(move |d| user_export(d, &uid))
+        .await
+        .map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Status::NotFound,
+            e => {
+                log::error!("Failed to export {id}: {e}");
+                Status::InternalServerError
+            }
+        })?;
+    let json = serde_json::to_string_pretty(&export).map_err(|_| Status::InternalServerError)?;
+    let disposition = format!("attachment; filename=\"{id}-export.json\"");
+    Ok(Archive(
+        json,
+        Header::new("Content-Disposition", disposition),
+    ))
+}
@@ -58,9 +58,12 @@ use crate::events::*;
 impl_user_event!(
   Message:ChatMessage,
   Egress:RoomEgress,
-  TimingAction:TimingAction;
+  TimingAction:TimingAction,
+  AllowTime:AllowTime;
 
   CheckTime:CheckTime,
+  WatchBoard:WatchBoard,
+  UnwatchBoard:UnwatchBoard,
   ListUsers:ListUsers;
 
   (SqliteDB, UserDB)